@@ -0,0 +1,80 @@
+//! This example reads the same ELF header as `elf_reader` but uses `#[derive(NeonRead)]`
+//! instead of building the readers by hand.
+//! Run it with `cargo run --example elf_derive --features derive -- <elf-file>`.
+#![allow(dead_code)]
+
+use neoncore::const_fn::ascii_to_u32_le;
+use neoncore::streams::advanced_readers::NeonRead;
+use neoncore::streams::Endianness;
+
+const ELF_MAGIC: u32 = ascii_to_u32_le(b"\x7fELF");
+
+#[derive(Debug, NeonRead)]
+struct EIdent {
+    ei_mag: u32,
+    ei_class: u8,
+    ei_data: u8,
+    ei_version: u8,
+    ei_osabi: u8,
+    #[neon(pad_after = 7)]
+    ei_abiversion: u8,
+}
+
+#[derive(Debug, NeonRead)]
+struct EHeader {
+    e_ident: EIdent,
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    #[neon(cond = "e_ident.ei_class == 1")]
+    e_entry32: Option<u32>,
+    #[neon(cond = "e_ident.ei_class == 1")]
+    e_phoff32: Option<u32>,
+    #[neon(cond = "e_ident.ei_class == 1")]
+    e_shoff32: Option<u32>,
+    #[neon(cond = "e_ident.ei_class == 2")]
+    e_entry: Option<u64>,
+    #[neon(cond = "e_ident.ei_class == 2")]
+    e_phoff: Option<u64>,
+    #[neon(cond = "e_ident.ei_class == 2")]
+    e_shoff: Option<u64>,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        println!("Usage: {} <elf-file>", args[0]);
+        return;
+    }
+
+    let file = match std::fs::File::open(&args[1]) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Error opening file: {}", e);
+            return;
+        }
+    };
+
+    let reader = std::io::BufReader::new(file);
+    let header = match EHeader::read_from(reader, Endianness::LittleEndian) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("Error reading header: {}", e);
+            return;
+        }
+    };
+
+    if header.e_ident.ei_mag != ELF_MAGIC {
+        println!("Not an ELF file");
+        return;
+    }
+
+    println!("{:#?}", header);
+}
@@ -1,20 +1,34 @@
 //! This example shows how to read an ELF file and print some information about it.
 //! This code is based on the ELF specification, which can be found here:
 //! https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
-//! This exaple wont work on big endian systems, because it assumes little endian.
-//! but it will distinguish between 32 and 64 bit ELF files.
-//! even though the [`EHeader`] struct is defined as 64 bit the reader will read the correct size
-//! depending on the ELF file.
+//! The fields after `e_ident` are read with the endianness given by `ei_data`,
+//! and even though the [`EHeader`] struct is defined as 64 bit the reader will read the correct size
+//! depending on the ELF file, using a conditional field on `ei_class`.
 #![allow(dead_code)]
 
 use std::io::Read;
 
 use neoncore::const_fn::ascii_to_u32_le;
-use neoncore::streams::advanced_readers::StructReader;
-use neoncore::streams::AnyInt;
+use neoncore::streams::advanced_readers::{PatternReaderTokens, StructReader};
+use neoncore::streams::read::StreamResult;
+use neoncore::streams::{AnyInt, Endianness};
 
 const ELF_MAGIC: u32 = ascii_to_u32_le(b"\x7fELF");
 
+const E_TYPES: [(u16, &str); 5] = [
+    (0, "NONE"),
+    (1, "REL"),
+    (2, "EXEC"),
+    (3, "DYN"),
+    (4, "CORE"),
+];
+const E_MACHINES: [(u16, &str); 4] = [
+    (0x03, "x86"),
+    (0x28, "ARM"),
+    (0x3E, "x86_64"),
+    (0xB7, "AArch64"),
+];
+
 #[derive(Debug)]
 struct EIdent {
     ei_mag: u32,
@@ -44,71 +58,76 @@ struct EHeader {
 }
 
 impl EHeader {
-    fn read<R: Read>(mut s: R) -> Self {
-        let ident_reader = StructReader::new_le()
-            .add_u32_field("ei_mag")
-            .add_u8_field("ei_class")
+    fn read<R: Read>(mut s: R) -> StreamResult<Self> {
+        let header = StructReader::new_le()
+            .add_magic_field("ei_mag", PatternReaderTokens::U32, ELF_MAGIC)
+            .add_range_field("ei_class", PatternReaderTokens::U8, 1u8..=2)
             .add_u8_field("ei_data")
             .add_u8_field("ei_version")
             .add_u8_field("ei_osabi")
             .add_u8_field("ei_abiversion")
             .add_padding(7)
-            .read(&mut s)
-            .unwrap();
-
-        let header_reader = StructReader::new_le()
-            .add_u16_field("e_type")
-            .add_u16_field("e_machine")
-            .add_u32_field("e_version");
-
-        let header_reader = if ident_reader["ei_class"] == AnyInt::U8(2) {
-            header_reader
-                .add_u64_field("e_entry")
-                .add_u64_field("e_phoff")
-                .add_u64_field("e_shoff")
-        } else {
-            header_reader
-                .add_u32_field("e_entry")
-                .add_u32_field("e_phoff")
-                .add_u32_field("e_shoff")
-        };
-
-        let header_reader = header_reader
+            .add_endian_switch(
+                "ei_data",
+                &[
+                    (AnyInt::U8(1), Endianness::LittleEndian),
+                    (AnyInt::U8(2), Endianness::BigEndian),
+                ],
+            )
+            .add_u16_enum_field("e_type", &E_TYPES)
+            .add_u16_enum_field("e_machine", &E_MACHINES)
+            .add_u32_field("e_version")
+            .add_if(
+                "ei_class",
+                2u8,
+                StructReader::new_le()
+                    .add_u64_field("e_entry")
+                    .add_u64_field("e_phoff")
+                    .add_u64_field("e_shoff"),
+                Some(
+                    StructReader::new_le()
+                        .add_u32_field("e_entry")
+                        .add_u32_field("e_phoff")
+                        .add_u32_field("e_shoff"),
+                ),
+            )
             .add_u32_field("e_flags")
             .add_u16_field("e_ehsize")
             .add_u16_field("e_phentsize")
             .add_u16_field("e_phnum")
             .add_u16_field("e_shentsize")
             .add_u16_field("e_shnum")
-            .add_u16_field("e_shstrndx");
-
-        let header = header_reader.read(&mut s).unwrap();
+            .add_u16_field("e_shstrndx")
+            .read(&mut s)?;
         println!("Header: {:#?}", header);
 
+        // e_entry, e_phoff and e_shoff are 32 bit on 32 bit ELF files
+        let addr = |name: &str| header[name].as_int().unwrap().to_u64_checked().unwrap();
+
         let e_ident = EIdent {
-            ei_mag: ident_reader["ei_mag"].try_into().unwrap(),
-            ei_class: ident_reader["ei_class"].try_into().unwrap(),
-            ei_data: ident_reader["ei_data"].try_into().unwrap(),
-            ei_version: ident_reader["ei_version"].try_into().unwrap(),
-            ei_osabi: ident_reader["ei_osabi"].try_into().unwrap(),
-            ei_abiversion: ident_reader["ei_abiversion"].try_into().unwrap(),
+            ei_mag: (&header["ei_mag"]).try_into().unwrap(),
+            ei_class: (&header["ei_class"]).try_into().unwrap(),
+            ei_data: (&header["ei_data"]).try_into().unwrap(),
+            ei_version: (&header["ei_version"]).try_into().unwrap(),
+            ei_osabi: (&header["ei_osabi"]).try_into().unwrap(),
+            ei_abiversion: (&header["ei_abiversion"]).try_into().unwrap(),
         };
-        EHeader {
+        Ok(EHeader {
             e_ident,
-            e_type: header["e_type"].try_into().unwrap(),
-            e_machine: header["e_machine"].try_into().unwrap(),
-            e_version: header["e_version"].try_into().unwrap(),
-            e_entry: header["e_entry"].try_into().unwrap(),
-            e_phoff: header["e_phoff"].try_into().unwrap(),
-            e_shoff: header["e_shoff"].try_into().unwrap(),
-            e_flags: header["e_flags"].try_into().unwrap(),
-            e_ehsize: header["e_ehsize"].try_into().unwrap(),
-            e_phentsize: header["e_phentsize"].try_into().unwrap(),
-            e_phnum: header["e_phnum"].try_into().unwrap(),
-            e_shentsize: header["e_shentsize"].try_into().unwrap(),
-            e_shnum: header["e_shnum"].try_into().unwrap(),
-            e_shstrndx: header["e_shstrndx"].try_into().unwrap(),
-        }
+            e_type: (&header["e_type"]).try_into().unwrap(),
+            e_machine: (&header["e_machine"]).try_into().unwrap(),
+            e_version: (&header["e_version"]).try_into().unwrap(),
+            e_entry: addr("e_entry"),
+            e_phoff: addr("e_phoff"),
+            e_shoff: addr("e_shoff"),
+            e_flags: (&header["e_flags"]).try_into().unwrap(),
+            e_ehsize: (&header["e_ehsize"]).try_into().unwrap(),
+            e_phentsize: (&header["e_phentsize"]).try_into().unwrap(),
+            e_phnum: (&header["e_phnum"]).try_into().unwrap(),
+            e_shentsize: (&header["e_shentsize"]).try_into().unwrap(),
+            e_shnum: (&header["e_shnum"]).try_into().unwrap(),
+            e_shstrndx: (&header["e_shstrndx"]).try_into().unwrap(),
+        })
     }
 }
 
@@ -131,7 +150,13 @@ fn main() {
 
     let mut reader = std::io::BufReader::new(file);
 
-    let header = EHeader::read(&mut reader);
+    let header = match EHeader::read(&mut reader) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("Error reading header: {}", e);
+            return;
+        }
+    };
 
     println!("Magic: {:#x}", header.e_ident.ei_mag);
 
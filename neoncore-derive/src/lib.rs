@@ -0,0 +1,157 @@
+//! Derive macros for `neoncore`, these are re-exported by `neoncore`
+//! when the `derive` feature is enabled and shouldn't be used directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, LitInt, LitStr, Type};
+
+/// Derive `neoncore::streams::advanced_readers::NeonRead` for a struct with named fields.
+///
+/// See the documentation of the `NeonRead` trait for the supported attributes.
+#[proc_macro_derive(NeonRead, attributes(neon))]
+pub fn derive_neon_read(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_neon_read(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    pad_before: Option<LitInt>,
+    pad_after: Option<LitInt>,
+    endian: Option<TokenStream2>,
+    read_as: Option<Type>,
+    cond: Option<Expr>,
+}
+
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("neon")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("pad_before") {
+                attrs.pad_before = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("pad_after") {
+                attrs.pad_after = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("endian") {
+                let lit: LitStr = meta.value()?.parse()?;
+                attrs.endian = Some(match lit.value().as_str() {
+                    "little" | "le" => quote!(::neoncore::streams::Endianness::LittleEndian),
+                    "big" | "be" => quote!(::neoncore::streams::Endianness::BigEndian),
+                    _ => {
+                        return Err(syn::Error::new(
+                            lit.span(),
+                            "expected \"little\" or \"big\"",
+                        ))
+                    }
+                });
+            } else if meta.path.is_ident("read_as") {
+                let lit: LitStr = meta.value()?.parse()?;
+                attrs.read_as = Some(lit.parse()?);
+            } else if meta.path.is_ident("cond") {
+                let lit: LitStr = meta.value()?.parse()?;
+                attrs.cond = Some(lit.parse()?);
+            } else {
+                return Err(meta.error("unknown neon attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+fn padding(len: &Option<LitInt>) -> TokenStream2 {
+    match len {
+        Some(len) => quote! {
+            ::neoncore::streams::advanced_readers::read_primitive(
+                &mut __reader,
+                &::neoncore::streams::advanced_readers::PatternReaderTokens::Padding(#len),
+                __endianness,
+            )?;
+        },
+        None => quote!(),
+    }
+}
+
+fn expand_neon_read(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new(
+                    input.span(),
+                    "NeonRead can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                input.span(),
+                "NeonRead can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut reads = Vec::with_capacity(fields.len());
+    let mut idents = Vec::with_capacity(fields.len());
+    for field in fields {
+        let attrs = parse_field_attrs(field)?;
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let endian = attrs.endian.unwrap_or_else(|| quote!(__endianness));
+
+        let value = match &attrs.read_as {
+            Some(read_as) => quote_spanned! {read_as.span()=>
+                ::core::convert::From::from(
+                    <#read_as as ::neoncore::streams::advanced_readers::NeonRead>::read_from(
+                        &mut __reader,
+                        #endian,
+                    )?
+                )
+            },
+            None => quote_spanned! {ty.span()=>
+                ::neoncore::streams::advanced_readers::NeonRead::read_from(&mut __reader, #endian)?
+            },
+        };
+        let pad_before = padding(&attrs.pad_before);
+        let pad_after = padding(&attrs.pad_after);
+        reads.push(match &attrs.cond {
+            Some(cond) => quote! {
+                let #ident: #ty = if #cond {
+                    #pad_before
+                    let __value = #value;
+                    #pad_after
+                    ::core::option::Option::Some(__value)
+                } else {
+                    ::core::option::Option::None
+                };
+            },
+            None => quote! {
+                #pad_before
+                let #ident: #ty = #value;
+                #pad_after
+            },
+        });
+        idents.push(ident);
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::neoncore::streams::advanced_readers::NeonRead
+            for #name #ty_generics #where_clause
+        {
+            fn read_from<__R: ::std::io::Read>(
+                mut __reader: __R,
+                __endianness: ::neoncore::streams::Endianness,
+            ) -> ::neoncore::streams::read::StreamResult<Self> {
+                #(#reads)*
+                ::core::result::Result::Ok(Self { #(#idents),* })
+            }
+        }
+    })
+}
@@ -3,12 +3,17 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::io::Read;
-use std::marker::PhantomData;
+use std::sync::Arc;
 
 use byteorder::ReadBytesExt;
 
-use super::read::StreamResult;
-use super::{AnyInt, StreamError};
+use super::bits::{BitOrder, BitReader};
+use super::helpers::{f16_to_f32, read_len, CountingReader};
+use super::read::{read_cstr, read_lpbuf, read_lpstr, StreamResult};
+use super::{AnyInt, AnyValue, Endianness, LPWidth, SeekRead, StreamError};
+
+#[cfg(feature = "derive")]
+pub use neoncore_derive::NeonRead;
 
 /// Read a number of elements from a stream,
 ///
@@ -16,25 +21,502 @@ use super::{AnyInt, StreamError};
 /// and then call call the read method with the stream to read from,
 /// the read method will return a vector of the read elements consuming the pattern,
 /// and leaving the stream at the end of the last read element.
-#[derive(Debug)]
-pub struct PatternReader<Ord: byteorder::ByteOrder> {
+///
+/// The byte order is chosen when the pattern is created, [`PatternReader::default`]
+/// reads little endian.
+#[derive(Clone, Debug)]
+pub struct PatternReader {
     pattern: Vec<PatternReaderTokens>,
-    endianess: PhantomData<Ord>,
+    endianness: Endianness,
 }
 
+#[derive(Clone)]
 pub enum PatternReaderTokens {
     Padding(usize),
     Bool,
     U8,
     U16,
+    U24,
     U32,
+    U48,
     U64,
+    U128,
     I8,
     I16,
+    I24,
     I32,
+    I48,
     I64,
+    I128,
     USize,
-    Expr((u8, Box<dyn Fn(AnyInt) -> bool>)),
+    F16,
+    F32,
+    F64,
+    /// A UTF-8 string with a length prefix of the given width, read as an [`AnyValue::Str`].
+    LPStr(LPWidth),
+    /// A byte buffer with a length prefix of the given width, read as an [`AnyValue::Bytes`].
+    LPBuf(LPWidth),
+    /// A NUL terminated UTF-8 string of at most `.0` bytes including the terminator,
+    /// read as an [`AnyValue::Str`].
+    CStr(usize),
+    /// A UTF-8 string stored in exactly the given number of bytes, padded with NULs,
+    /// read as an [`AnyValue::Str`] that ends at the first NUL.
+    Str(ArrayLen),
+    /// A byte buffer of the given number of bytes, read as an [`AnyValue::Bytes`].
+    Bytes(ArrayLen),
+    /// Read the inner token and check its value, reading fails with [`StreamError::Validation`]
+    /// if the value doesn't satisfy the constraint.
+    ///
+    /// Writers write the inner token without checking the value.
+    Check(Box<PatternReaderTokens>, Constraint),
+    /// Read the inner integer token and look its value up in the table of names,
+    /// a known value is read as an [`AnyValue::Enum`] with the raw value and its name.
+    ///
+    /// Writers accept the raw value, an [`AnyValue::Enum`] or the name as an [`AnyValue::Str`].
+    Enum(
+        Box<PatternReaderTokens>,
+        Vec<(AnyInt, String)>,
+        UnknownValue,
+    ),
+    /// An unsigned integer of `.1` bits, at most 64, read as the smallest [`AnyInt`] that fits.
+    ///
+    /// Consecutive bits tokens with the same order are packed together into whole bytes,
+    /// the unused bits of the last byte are skipped.
+    Bits(BitOrder, u32),
+    Expr((u8, Arc<dyn Fn(AnyInt) -> bool + Send + Sync>)),
+    /// An array of elements of the inner token, read as an [`AnyValue::List`].
+    Array(ArrayLen, Box<PatternReaderTokens>),
+    /// A sub struct, the names are given to the values of the tokens in order
+    /// and it's read as an [`AnyValue::Struct`].
+    ///
+    /// A value with an empty name must be a struct, its fields are merged into this struct.
+    Struct(Vec<String>, Vec<PatternReaderTokens>),
+    /// Read the first [`PatternReaderTokens::Struct`] if the integer `.0` refers to
+    /// equals `.1`, the second one if not.
+    If(
+        ValueRef,
+        AnyInt,
+        Box<PatternReaderTokens>,
+        Box<PatternReaderTokens>,
+    ),
+    /// Read the [`PatternReaderTokens::Struct`] of the first case equal to the integer
+    /// `.0` refers to, or the default if no case matches.
+    Switch(
+        ValueRef,
+        Vec<(AnyInt, PatternReaderTokens)>,
+        Option<Box<PatternReaderTokens>>,
+    ),
+    /// Read the inner token with a fixed endianness.
+    Endian(Endianness, Box<PatternReaderTokens>),
+    /// Switch the endianness of the following tokens of the enclosing pattern to the
+    /// one of the first case equal to the integer `.0` refers to.
+    ///
+    /// Reading fails if no case matches, this token doesn't produce a value.
+    EndianSwitch(ValueRef, Vec<(AnyInt, Endianness)>),
+    /// Move to the offset `.0` counted from the position the read started at,
+    /// this token doesn't produce a value.
+    ///
+    /// Needs a seekable stream, see [`PatternReader::read_pattern_seek`].
+    SeekTo(u64),
+    /// Move `.0` bytes from the current offset, backwards if negative,
+    /// this token doesn't produce a value.
+    ///
+    /// Needs a seekable stream, see [`PatternReader::read_pattern_seek`].
+    Skip(i64),
+    /// Skip to the next offset that is a multiple of `.0` counted from the position
+    /// the read started at, this token doesn't produce a value.
+    ///
+    /// The skipped bytes are read, so this works on any stream,
+    /// writers write them as zeros.
+    Align(u64),
+    /// Read the inner token at the offset given by the integer `.0` refers to,
+    /// counted from the position the read started at, then come back to read
+    /// the next token, e.g. a table at an offset given by a header.
+    ///
+    /// Needs a seekable stream, see [`PatternReader::read_pattern_seek`].
+    At(ValueRef, Box<PatternReaderTokens>),
+}
+
+impl PatternReaderTokens {
+    /// The branch a conditional token selects given the values read so far,
+    /// `None` for any other token.
+    pub(crate) fn select_branch(&self, scope: &Scope) -> StreamResult<Option<&Self>> {
+        match self {
+            PatternReaderTokens::If(r, expected, then, otherwise) => {
+                if scope.int(r)?.num_eq(expected) {
+                    Ok(Some(then))
+                } else {
+                    Ok(Some(otherwise))
+                }
+            }
+            PatternReaderTokens::Switch(r, cases, default) => {
+                let v = scope.int(r)?;
+                match cases.iter().find(|(case, _)| case.num_eq(&v)) {
+                    Some((_, tkn)) => Ok(Some(tkn)),
+                    None => match default {
+                        Some(tkn) => Ok(Some(tkn)),
+                        None => Err(StreamError::InvalidPattern(format!("no case for {:?}", v))),
+                    },
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The endianness a [`PatternReaderTokens::EndianSwitch`] selects given the values
+    /// read so far.
+    pub(crate) fn select_endianness(&self, scope: &Scope) -> StreamResult<Endianness> {
+        match self {
+            PatternReaderTokens::EndianSwitch(r, cases) => {
+                let v = scope.int(r)?;
+                match cases.iter().find(|(case, _)| case.num_eq(&v)) {
+                    Some((_, e)) => Ok(*e),
+                    None => Err(StreamError::InvalidPattern(format!(
+                        "no endianness for {:?}",
+                        v
+                    ))),
+                }
+            }
+            _ => Err(StreamError::InvalidPattern(format!(
+                "{:?} doesn't select an endianness",
+                self
+            ))),
+        }
+    }
+
+    /// Whether the token is read as a whole number of bytes into an [`AnyValue::Int`],
+    /// with a fixed endianness or not.
+    pub(crate) fn is_int(&self) -> bool {
+        match self {
+            PatternReaderTokens::U8
+            | PatternReaderTokens::U16
+            | PatternReaderTokens::U24
+            | PatternReaderTokens::U32
+            | PatternReaderTokens::U48
+            | PatternReaderTokens::U64
+            | PatternReaderTokens::U128
+            | PatternReaderTokens::I8
+            | PatternReaderTokens::I16
+            | PatternReaderTokens::I24
+            | PatternReaderTokens::I32
+            | PatternReaderTokens::I48
+            | PatternReaderTokens::I64
+            | PatternReaderTokens::I128
+            | PatternReaderTokens::USize => true,
+            PatternReaderTokens::Endian(_, tkn) => tkn.is_int(),
+            _ => false,
+        }
+    }
+
+    /// Whether the token produces a value when read.
+    pub(crate) fn has_value(&self) -> bool {
+        !matches!(
+            self,
+            PatternReaderTokens::Padding(_)
+                | PatternReaderTokens::EndianSwitch(..)
+                | PatternReaderTokens::SeekTo(_)
+                | PatternReaderTokens::Skip(_)
+                | PatternReaderTokens::Align(_)
+        )
+    }
+}
+
+/// A check of the value of a [`PatternReaderTokens::Check`] token.
+#[derive(Clone)]
+pub enum Constraint {
+    /// The value must equal this one, integers are compared by value regardless of their width.
+    Eq(AnyValue),
+    /// The value must be an integer between the two bounds, inclusive.
+    Range(AnyInt, AnyInt),
+    /// The value must satisfy the predicate.
+    Predicate(Arc<dyn Fn(&AnyValue) -> bool + Send + Sync>),
+}
+
+impl Constraint {
+    /// Whether `v` satisfies the constraint.
+    pub fn check(&self, v: &AnyValue) -> bool {
+        match (self, v.as_int()) {
+            (Constraint::Eq(AnyValue::Int(expected)), Some(v)) => expected.num_eq(&v),
+            (Constraint::Eq(expected), _) => expected == v,
+            (Constraint::Range(min, max), Some(v)) => {
+                v.num_cmp(min) != std::cmp::Ordering::Less
+                    && v.num_cmp(max) != std::cmp::Ordering::Greater
+            }
+            (Constraint::Range(..), None) => false,
+            (Constraint::Predicate(f), _) => f(v),
+        }
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Eq(v) => write!(f, "{:?}", v),
+            Constraint::Range(min, max) => write!(f, "{:?}..={:?}", min, max),
+            Constraint::Predicate(_) => write!(f, "a value satisfying the predicate"),
+        }
+    }
+}
+
+impl Debug for Constraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Eq(v) => write!(f, "Eq({:?})", v),
+            Constraint::Range(min, max) => write!(f, "Range({:?}, {:?})", min, max),
+            Constraint::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+/// What to do with a value of a [`PatternReaderTokens::Enum`] that isn't in its table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum UnknownValue {
+    /// Fail with [`StreamError::Validation`] naming the field and its offset.
+    Error,
+    /// Keep the value as a plain [`AnyValue::Int`].
+    #[default]
+    KeepRaw,
+}
+
+/// Convert a table of values and names to the one of [`PatternReaderTokens::Enum`].
+pub(crate) fn enum_table<T: Into<AnyInt> + Copy>(values: &[(T, &str)]) -> Vec<(AnyInt, String)> {
+    values
+        .iter()
+        .map(|(v, name)| ((*v).into(), name.to_string()))
+        .collect()
+}
+
+/// Prefix the field of a [`StreamError::Validation`] with `name`,
+/// the path of a nested field is built as the error goes up the enclosing structs.
+fn in_field(e: StreamError, name: &str) -> StreamError {
+    match e {
+        StreamError::Validation {
+            field,
+            offset,
+            value,
+            expected,
+        } if !name.is_empty() => StreamError::Validation {
+            field: if field.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}.{}", name, field)
+            },
+            offset,
+            value,
+            expected,
+        },
+        e => e,
+    }
+}
+
+/// Bits tokens are only packed together when they aren't wrapped in another token,
+/// a wrapped one would be read as whole bytes of its own and shift the following fields.
+///
+/// # Errors
+/// [`StreamError::InvalidPattern`] if the inner token of a [`PatternReaderTokens::Check`]
+/// is a [`PatternReaderTokens::Bits`] token.
+pub(crate) fn check_not_bits(tkn: &PatternReaderTokens) -> StreamResult<()> {
+    match tkn {
+        PatternReaderTokens::Bits(..) => Err(StreamError::InvalidPattern(
+            "checks can't wrap bits fields".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The leading run of [`PatternReaderTokens::Bits`] tokens of `tkns` with the same bit order,
+/// these are packed together into whole bytes.
+pub(crate) fn bit_group(tkns: &[PatternReaderTokens]) -> &[PatternReaderTokens] {
+    let order = match tkns.first() {
+        Some(PatternReaderTokens::Bits(order, _)) => *order,
+        _ => return &[],
+    };
+    let len = tkns
+        .iter()
+        .take_while(|tkn| matches!(tkn, PatternReaderTokens::Bits(o, _) if *o == order))
+        .count();
+    &tkns[..len]
+}
+
+/// The number of bytes a group of [`PatternReaderTokens::Bits`] tokens is packed into.
+pub(crate) fn bit_group_bytes(group: &[PatternReaderTokens]) -> usize {
+    let bits: u64 = group
+        .iter()
+        .map(|tkn| match tkn {
+            PatternReaderTokens::Bits(_, n) => *n as u64,
+            _ => 0,
+        })
+        .sum();
+    bits.div_ceil(8) as usize
+}
+
+/// The size of a sequence of tokens given the size of a single token,
+/// bit groups are counted as the bytes they are packed into.
+pub(crate) fn grouped_size(
+    mut tkns: &[PatternReaderTokens],
+    size: fn(&PatternReaderTokens) -> usize,
+) -> usize {
+    let mut total = 0;
+    while let Some(tkn) = tkns.first() {
+        let group = bit_group(tkns);
+        if group.is_empty() {
+            total += size(tkn);
+            tkns = &tkns[1..];
+        } else {
+            total += bit_group_bytes(group);
+            tkns = &tkns[group.len()..];
+        }
+    }
+    total
+}
+
+/// Read a group of [`PatternReaderTokens::Bits`] tokens, see [`bit_group`].
+fn read_bit_group<S: Read>(
+    stream: &mut S,
+    group: &[PatternReaderTokens],
+) -> StreamResult<Vec<AnyValue>> {
+    let mut buf = vec![0; bit_group_bytes(group)];
+    stream.read_exact(&mut buf)?;
+
+    let mut values = Vec::with_capacity(group.len());
+    let mut reader = None;
+    for tkn in group.iter() {
+        if let PatternReaderTokens::Bits(order, n) = tkn {
+            let reader = reader.get_or_insert_with(|| BitReader::new(&buf[..], *order));
+            let v = reader.read_bits(*n)?;
+            values.push(AnyValue::Int(match n {
+                0..=8 => AnyInt::U8(v as u8),
+                9..=16 => AnyInt::U16(v as u16),
+                17..=32 => AnyInt::U32(v as u32),
+                _ => AnyInt::U64(v),
+            }));
+        }
+    }
+    Ok(values)
+}
+
+/// A reference to an integer read before the token that uses it,
+/// e.g. the number of elements of an array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueRef {
+    /// The value at this index of the values already read by the enclosing pattern.
+    Index(usize),
+    /// The field at this path, see [`AnyValue::get`]. The first name is looked up in
+    /// the enclosing struct, including the fields of its conditional fields, then in
+    /// the structs around it, so `"e_ident.ei_class"` is a field of a sub struct.
+    Field(String),
+}
+
+impl std::fmt::Display for ValueRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueRef::Index(i) => write!(f, "value {}", i),
+            ValueRef::Field(path) => write!(f, "field {}", path),
+        }
+    }
+}
+
+/// The values a [`ValueRef`] is resolved against, the ones already read by a pattern,
+/// named if it's a struct, and the ones of the patterns around it.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Scope<'a> {
+    names: &'a [String],
+    values: &'a [AnyValue],
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    pub(crate) fn new(
+        names: &'a [String],
+        values: &'a [AnyValue],
+        parent: Option<&'a Scope<'a>>,
+    ) -> Self {
+        Self {
+            names,
+            values,
+            parent,
+        }
+    }
+
+    /// A scope without values of its own in this one, for the elements of an array
+    /// and the branches of conditional tokens.
+    pub(crate) fn inner(&'a self) -> Self {
+        Self::new(&[], &[], Some(self))
+    }
+
+    /// The latest field called `name`, in this scope or the ones around it.
+    fn field(&self, name: &str) -> Option<&'a AnyValue> {
+        for (n, v) in self.names.iter().zip(self.values).rev() {
+            match v {
+                AnyValue::Struct(fields) if n.is_empty() => {
+                    if let Some((_, v)) = fields.iter().find(|(n, _)| n == name) {
+                        return Some(v);
+                    }
+                }
+                v if n == name => return Some(v),
+                _ => {}
+            }
+        }
+        self.parent?.field(name)
+    }
+
+    /// The integer `r` refers to.
+    pub(crate) fn int(&self, r: &ValueRef) -> StreamResult<AnyInt> {
+        let v = match r {
+            ValueRef::Index(i) => self.values.get(*i),
+            ValueRef::Field(path) => {
+                let (head, rest) = path.split_once('.').unwrap_or((path, ""));
+                self.field(head).and_then(|v| v.get(rest))
+            }
+        };
+        match v {
+            Some(AnyValue::Int(v) | AnyValue::Enum(v, _)) => Ok(*v),
+            Some(v) => Err(StreamError::InvalidPattern(format!(
+                "{:?} is not an integer",
+                v
+            ))),
+            None => Err(StreamError::InvalidPattern(format!("{} wasn't read", r))),
+        }
+    }
+}
+
+/// Name the values of a struct, merging the fields of values with an empty name.
+fn named_values(names: &[String], values: Vec<AnyValue>) -> Vec<(String, AnyValue)> {
+    let mut fields = Vec::with_capacity(values.len());
+    for (name, value) in names.iter().zip(values) {
+        match value {
+            AnyValue::Struct(inner) if name.is_empty() => fields.extend(inner),
+            value => fields.push((name.clone(), value)),
+        }
+    }
+    fields
+}
+
+/// The largest number of elements [`ArrayLen::Counted`] accepts when the elements
+/// don't read any bytes, e.g. structs whose fields are all conditional,
+/// otherwise a count read from the input could build any number of values.
+pub const MAX_EMPTY_COUNTED_ELEMENTS: usize = 4096;
+
+/// The number of elements of a [`PatternReaderTokens::Array`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArrayLen {
+    /// A fixed number of elements.
+    Fixed(usize),
+    /// The number of elements is the integer this refers to.
+    ///
+    /// Reading fails with [`StreamError::InvalidPattern`] if the number is greater than
+    /// [`MAX_EMPTY_COUNTED_ELEMENTS`] and an element doesn't read any bytes.
+    Counted(ValueRef),
+}
+
+impl ArrayLen {
+    /// Resolve the number of elements given the values read so far.
+    pub(crate) fn resolve(&self, scope: &Scope) -> StreamResult<usize> {
+        match self {
+            ArrayLen::Fixed(n) => Ok(*n),
+            ArrayLen::Counted(r) => scope.int(r)?.to_usize_checked(),
+        }
+    }
 }
 
 impl Debug for PatternReaderTokens {
@@ -44,37 +526,93 @@ impl Debug for PatternReaderTokens {
             PatternReaderTokens::Bool => write!(f, "Bool"),
             PatternReaderTokens::U8 => write!(f, "U8"),
             PatternReaderTokens::U16 => write!(f, "U16"),
+            PatternReaderTokens::U24 => write!(f, "U24"),
             PatternReaderTokens::U32 => write!(f, "U32"),
+            PatternReaderTokens::U48 => write!(f, "U48"),
             PatternReaderTokens::U64 => write!(f, "U64"),
+            PatternReaderTokens::U128 => write!(f, "U128"),
             PatternReaderTokens::I8 => write!(f, "I8"),
             PatternReaderTokens::I16 => write!(f, "I16"),
+            PatternReaderTokens::I24 => write!(f, "I24"),
             PatternReaderTokens::I32 => write!(f, "I32"),
+            PatternReaderTokens::I48 => write!(f, "I48"),
             PatternReaderTokens::I64 => write!(f, "I64"),
+            PatternReaderTokens::I128 => write!(f, "I128"),
             PatternReaderTokens::USize => write!(f, "USize"),
+            PatternReaderTokens::F16 => write!(f, "F16"),
+            PatternReaderTokens::F32 => write!(f, "F32"),
+            PatternReaderTokens::F64 => write!(f, "F64"),
+            PatternReaderTokens::LPStr(w) => write!(f, "LPStr({:?})", w),
+            PatternReaderTokens::LPBuf(w) => write!(f, "LPBuf({:?})", w),
+            PatternReaderTokens::CStr(max) => write!(f, "CStr({})", max),
+            PatternReaderTokens::Str(len) => write!(f, "Str({:?})", len),
+            PatternReaderTokens::Bytes(len) => write!(f, "Bytes({:?})", len),
+            PatternReaderTokens::Bits(order, n) => write!(f, "Bits({:?}, {})", order, n),
+            PatternReaderTokens::Check(tkn, c) => write!(f, "Check({:?}, {})", tkn, c),
+            PatternReaderTokens::Enum(tkn, names, unknown) => {
+                write!(f, "Enum({:?}, {:?}, {:?})", tkn, names, unknown)
+            }
             PatternReaderTokens::Expr((w, _)) => write!(f, "Expr(par_width: {})", w),
+            PatternReaderTokens::Array(len, tkn) => write!(f, "Array({:?}, {:?})", len, tkn),
+            PatternReaderTokens::Struct(names, tkns) => f
+                .debug_map()
+                .entries(names.iter().zip(tkns.iter()))
+                .finish(),
+            PatternReaderTokens::If(r, v, then, otherwise) => {
+                write!(f, "If({} == {:?}, {:?}, {:?})", r, v, then, otherwise)
+            }
+            PatternReaderTokens::Switch(r, cases, default) => {
+                write!(f, "Switch({}, {:?}, {:?})", r, cases, default)
+            }
+            PatternReaderTokens::Endian(e, tkn) => write!(f, "Endian({:?}, {:?})", e, tkn),
+            PatternReaderTokens::EndianSwitch(r, cases) => {
+                write!(f, "EndianSwitch({}, {:?})", r, cases)
+            }
+            PatternReaderTokens::SeekTo(offset) => write!(f, "SeekTo({})", offset),
+            PatternReaderTokens::Skip(delta) => write!(f, "Skip({})", delta),
+            PatternReaderTokens::Align(n) => write!(f, "Align({})", n),
+            PatternReaderTokens::At(r, tkn) => write!(f, "At({}, {:?})", r, tkn),
         }
     }
 }
 
-impl PatternReader<byteorder::BigEndian> {
+impl PatternReader {
+    /// Create an empty pattern, values are read with `endianness`
+    /// unless a token overrides it.
+    pub fn new(endianness: Endianness) -> Self {
+        let pattern = Vec::new();
+        Self {
+            pattern,
+            endianness,
+        }
+    }
+
     pub fn new_be() -> Self {
-        Self::new()
+        Self::new(Endianness::BigEndian)
     }
-}
 
-impl PatternReader<byteorder::LittleEndian> {
     pub fn new_le() -> Self {
-        Self::new()
+        Self::new(Endianness::LittleEndian)
     }
-}
 
-impl<Ord: byteorder::ByteOrder> PatternReader<Ord> {
-    pub fn new() -> Self {
-        let pattern = Vec::new();
-        Self {
+    /// The endianness values are read with, unless a token overrides it.
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// Build a pattern from a Python `struct` style format string, e.g. `"<HHI7xQ"`.
+    ///
+    /// See [`parse_format`] for the supported syntax, the endianness is taken from
+    /// the byte order prefix, native if there is none.
+    ///
+    /// # Errors
+    /// [`StreamError::InvalidChar`] with the position of the first invalid character.
+    pub fn from_format(fmt: &str) -> StreamResult<Self> {
+        let (endianness, pattern) = parse_format(fmt)?;
+        Ok(Self {
             pattern,
-            endianess: PhantomData::<Ord>::default(),
-        }
+            endianness: endianness.unwrap_or_else(native_endianness),
+        })
     }
 
     pub fn add_u8(&mut self) -> &mut Self {
@@ -87,16 +625,32 @@ impl<Ord: byteorder::ByteOrder> PatternReader<Ord> {
         self
     }
 
+    /// Add a 24 bit unsigned integer, it's read as an [`AnyInt::U32`].
+    pub fn add_u24(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U24);
+        self
+    }
+
     pub fn add_u32(&mut self) -> &mut Self {
         self.pattern.push(PatternReaderTokens::U32);
         self
     }
 
+    pub fn add_u48(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U48);
+        self
+    }
+
     pub fn add_u64(&mut self) -> &mut Self {
         self.pattern.push(PatternReaderTokens::U64);
         self
     }
 
+    pub fn add_u128(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U128);
+        self
+    }
+
     pub fn add_i8(&mut self) -> &mut Self {
         self.pattern.push(PatternReaderTokens::I8);
         self
@@ -107,371 +661,2847 @@ impl<Ord: byteorder::ByteOrder> PatternReader<Ord> {
         self
     }
 
+    /// Add a 24 bit signed integer, it's sign extended and read as an [`AnyInt::I32`].
+    pub fn add_i24(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I24);
+        self
+    }
+
     pub fn add_i32(&mut self) -> &mut Self {
         self.pattern.push(PatternReaderTokens::I32);
         self
     }
 
+    pub fn add_i48(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I48);
+        self
+    }
+
     pub fn add_i64(&mut self) -> &mut Self {
         self.pattern.push(PatternReaderTokens::I64);
         self
     }
 
-    pub fn add_usize(&mut self) -> &mut Self {
-        self.pattern.push(PatternReaderTokens::USize);
+    pub fn add_i128(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I128);
         self
     }
 
-    pub fn add_padding(&mut self, len: usize) -> &mut Self {
-        self.pattern.push(PatternReaderTokens::Padding(len));
+    pub fn add_usize(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::USize);
         self
     }
 
-    pub fn add_bool(&mut self) -> &mut Self {
-        self.pattern.push(PatternReaderTokens::Bool);
+    /// Add an IEEE 754 half precision float, it's widened to an `f32` when read.
+    pub fn add_f16(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F16);
         self
     }
 
-    pub fn add_expr(
-        &mut self,
-        par_width: u8,
-        expr: impl Fn(AnyInt) -> bool + 'static,
-    ) -> &mut Self {
-        self.pattern
-            .push(PatternReaderTokens::Expr((par_width, Box::new(expr))));
+    pub fn add_f32(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F32);
         self
     }
 
-    /// How many input bytes are required at least to statisfy this pattern.
-    ///
-    /// # Returns
-    /// The number of bytes required to read this pattern.
-    pub fn pattern_required_bytes(&self) -> u64 {
-        let mut bytes = 0;
-        for tkn in self.pattern.iter() {
-            match tkn {
-                // skip
-                PatternReaderTokens::Padding(sz) => bytes += sz,
-                PatternReaderTokens::U8 | PatternReaderTokens::I8 | PatternReaderTokens::Bool => {
-                    bytes += 1
-                }
-                PatternReaderTokens::U16 | PatternReaderTokens::I16 => bytes += 2,
-                PatternReaderTokens::U32 | PatternReaderTokens::I32 => bytes += 4,
-                PatternReaderTokens::U64 | PatternReaderTokens::I64 => bytes += 8,
-                PatternReaderTokens::USize => {
-                    bytes += std::mem::size_of::<usize>();
-                }
-                PatternReaderTokens::Expr(_) => bytes += 0,
-            }
-        }
-        bytes as u64
+    pub fn add_f64(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F64);
+        self
     }
 
-    /// Read the stream according to the given `format` and return the result.
-    ///
-    /// # Returns
-    /// a ```Vec<AnyInt>``` containing the read values.
-    pub fn read_pattern<S: Read>(&self, mut stream: S) -> StreamResult<Vec<AnyInt>> {
-        let mut values = Vec::new();
-
-        for tkn in self.pattern.iter() {
-            if let PatternReaderTokens::Padding(size) = tkn {
-                for _ in 0..*size {
-                    stream.read_u8()?;
-                }
-                continue;
-            }
-
-            let v = match tkn {
-                PatternReaderTokens::U8 => Some(AnyInt::U8(stream.read_u8()?)),
-                PatternReaderTokens::I8 => Some(AnyInt::I8(stream.read_i8()?)),
-                _ => None,
-            };
-
-            if let Some(v) = v {
-                values.push(v);
-                continue;
-            }
-
-            // the rest of the format characters require at least 2 bytes
-            let v = match tkn {
-                PatternReaderTokens::U16 => AnyInt::U16(stream.read_u16::<Ord>()?),
-                PatternReaderTokens::U32 => AnyInt::U32(stream.read_u32::<Ord>()?),
-                PatternReaderTokens::U64 => AnyInt::U64(stream.read_u64::<Ord>()?),
-                PatternReaderTokens::I16 => AnyInt::I16(stream.read_i16::<Ord>()?),
-                PatternReaderTokens::I32 => AnyInt::I32(stream.read_i32::<Ord>()?),
-                PatternReaderTokens::I64 => AnyInt::I64(stream.read_i64::<Ord>()?),
-                PatternReaderTokens::USize => {
-                    if std::mem::size_of::<usize>() == 4 {
-                        AnyInt::U32(stream.read_u32::<Ord>()?)
-                    } else {
-                        AnyInt::U64(stream.read_u64::<Ord>()?)
-                    }
-                }
-                PatternReaderTokens::Bool => {
-                    let v = stream.read_u8()?;
-                    if v == 0 {
-                        AnyInt::Bool(false)
-                    } else {
-                        AnyInt::Bool(true)
-                    }
-                }
-                PatternReaderTokens::Expr((par_width, expr)) => {
-                    let v = match par_width {
-                        1 => AnyInt::U8(stream.read_u8()?),
-                        2 => AnyInt::U16(stream.read_u16::<Ord>()?),
-                        4 => AnyInt::U32(stream.read_u32::<Ord>()?),
-                        8 => AnyInt::U64(stream.read_u64::<Ord>()?),
-                        _ => {
-                            return Err(StreamError::InvalidPattern(
-                                "invalid parameter width".into(),
-                            ))
-                        }
-                    };
-                    if expr(v) {
-                        AnyInt::Bool(true)
-                    } else {
-                        AnyInt::Bool(false)
-                    }
-                }
-                PatternReaderTokens::Padding(_)
-                | PatternReaderTokens::U8
-                | PatternReaderTokens::I8 => unreachable!(),
-            };
-            values.push(v);
-        }
-        Ok(values)
+    pub fn add_padding(&mut self, len: usize) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Padding(len));
+        self
     }
-}
-
-#[derive(Debug)]
-pub struct StructReader<Ord: byteorder::ByteOrder> {
-    fields: PatternReader<Ord>,
-    field_names: Vec<String>,
-    results: HashMap<String, AnyInt>,
-}
 
-impl StructReader<byteorder::BigEndian> {
-    pub fn new_be() -> Self {
-        Self::new()
+    pub fn add_bool(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Bool);
+        self
     }
-}
 
-impl StructReader<byteorder::LittleEndian> {
-    pub fn new_le() -> Self {
-        Self::new()
+    /// Add a string with a length prefix of `width`, the prefix is read
+    /// with the endianness of the pattern.
+    pub fn add_lpstr(&mut self, width: LPWidth) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::LPStr(width));
+        self
     }
-}
 
-impl<Ord: byteorder::ByteOrder> StructReader<Ord> {
-    pub fn new() -> Self {
-        Self {
-            fields: PatternReader::<Ord>::new(),
-            field_names: Vec::new(),
-            results: HashMap::new(),
-        }
+    /// Add a byte buffer with a length prefix of `width`, the prefix is read
+    /// with the endianness of the pattern.
+    pub fn add_lpbuf(&mut self, width: LPWidth) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::LPBuf(width));
+        self
     }
 
-    pub fn add_u8_field(mut self, name: &str) -> Self {
-        self.fields.add_u8();
-        self.field_names.push(name.to_string());
+    /// Add a NUL terminated string of at most `maxlen` bytes including the terminator.
+    pub fn add_cstr(&mut self, maxlen: usize) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::CStr(maxlen));
         self
     }
 
-    pub fn add_u16_field(mut self, name: &str) -> Self {
-        self.fields.add_u16();
-        self.field_names.push(name.to_string());
+    /// Add a string stored in exactly `len` bytes padded with NULs.
+    pub fn add_str(&mut self, len: usize) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Str(ArrayLen::Fixed(len)));
         self
     }
 
-    pub fn add_u32_field(mut self, name: &str) -> Self {
-        self.fields.add_u32();
-        self.field_names.push(name.to_string());
+    /// Add a byte buffer of exactly `len` bytes.
+    pub fn add_bytes(&mut self, len: usize) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Bytes(ArrayLen::Fixed(len)));
         self
     }
 
-    pub fn add_u64_field(mut self, name: &str) -> Self {
-        self.fields.add_u64();
-        self.field_names.push(name.to_string());
+    /// Add an unsigned integer of `nbits` bits, consecutive bits with the same
+    /// `order` are packed together, see [`PatternReaderTokens::Bits`].
+    ///
+    /// # Panics
+    /// If `nbits` is 0 or greater than 64.
+    pub fn add_bits(&mut self, order: BitOrder, nbits: u32) -> &mut Self {
+        assert!(
+            (1..=64).contains(&nbits),
+            "bits fields must be 1 to 64 bits wide"
+        );
+        self.pattern.push(PatternReaderTokens::Bits(order, nbits));
         self
     }
 
-    pub fn add_usize_field(mut self, name: &str) -> Self {
-        self.fields.add_usize();
-        self.field_names.push(name.to_string());
+    /// Add `tkn` read with a fixed `endianness`, regardless of the endianness of the pattern.
+    pub fn add_endian(&mut self, endianness: Endianness, tkn: PatternReaderTokens) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Endian(endianness, Box::new(tkn)));
         self
     }
 
-    pub fn add_i8_field(mut self, name: &str) -> Self {
-        self.fields.add_i8();
-        self.field_names.push(name.to_string());
+    /// Move to `offset` counted from the position the read started at,
+    /// see [`PatternReaderTokens::SeekTo`].
+    pub fn add_seek_to(&mut self, offset: u64) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::SeekTo(offset));
         self
     }
 
-    pub fn add_i16_field(mut self, name: &str) -> Self {
-        self.fields.add_i16();
-        self.field_names.push(name.to_string());
+    /// Move `delta` bytes from the current offset, see [`PatternReaderTokens::Skip`].
+    pub fn add_skip(&mut self, delta: i64) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Skip(delta));
         self
     }
 
-    pub fn add_i32_field(mut self, name: &str) -> Self {
-        self.fields.add_i32();
-        self.field_names.push(name.to_string());
+    /// Skip to the next multiple of `n` bytes, see [`PatternReaderTokens::Align`].
+    ///
+    /// Reading fails with [`StreamError::InvalidPattern`] if `n` is 0.
+    pub fn add_align(&mut self, n: u64) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Align(n));
         self
     }
 
-    pub fn add_i64_field(mut self, name: &str) -> Self {
-        self.fields.add_i64();
-        self.field_names.push(name.to_string());
+    /// Add `tkn` read at the offset given by the integer value at `index`,
+    /// see [`PatternReaderTokens::At`].
+    pub fn add_at(&mut self, index: usize, tkn: PatternReaderTokens) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::At(
+            ValueRef::Index(index),
+            Box::new(tkn),
+        ));
         self
     }
 
-    pub fn add_padding(mut self, size: usize) -> Self {
-        self.fields.add_padding(size);
+    /// Add an array of `len` elements of `tkn`, the elements are read as an [`AnyValue::List`].
+    pub fn add_array(&mut self, len: usize, tkn: PatternReaderTokens) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Array(
+            ArrayLen::Fixed(len),
+            Box::new(tkn),
+        ));
         self
     }
 
-    pub fn add_bool_field(mut self, name: &str) -> Self {
-        self.fields.add_bool();
-        self.field_names.push(name.to_string());
+    /// Add a token whose value is replaced by the result of `expr`, the value is read
+    /// as an unsigned integer of `par_width` bytes, which must be 1, 2, 4 or 8.
+    ///
+    /// Use [`PatternReader::add_check`] to keep the value.
+    pub fn add_expr(
+        &mut self,
+        par_width: u8,
+        expr: impl Fn(AnyInt) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Expr((par_width, Arc::new(expr))));
         self
     }
 
-    pub fn add_expr_field(mut self, name: &str, par_width: u8, expr: fn(AnyInt) -> bool) -> Self {
-        self.fields.add_expr(par_width, expr);
-        self.field_names.push(name.to_string());
+    /// Add `tkn` checked against `constraint`, see [`PatternReaderTokens::Check`].
+    ///
+    /// Reading fails with [`StreamError::InvalidPattern`] if `tkn` is a
+    /// [`PatternReaderTokens::Bits`] token.
+    pub fn add_check(&mut self, tkn: PatternReaderTokens, constraint: Constraint) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Check(Box::new(tkn), constraint));
         self
     }
 
-    pub fn required_bytes(&self) -> u64 {
-        self.fields.pattern_required_bytes()
+    /// Add the integer token `tkn` decoded with a table of names,
+    /// see [`PatternReaderTokens::Enum`].
+    ///
+    /// Reading fails with [`StreamError::InvalidPattern`] if `tkn` isn't an integer
+    /// token, e.g. a string, a float or a [`PatternReaderTokens::Bits`] token.
+    pub fn add_enum<T: Into<AnyInt> + Copy>(
+        &mut self,
+        tkn: PatternReaderTokens,
+        values: &[(T, &str)],
+        unknown: UnknownValue,
+    ) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Enum(
+            Box::new(tkn),
+            enum_table(values),
+            unknown,
+        ));
+        self
     }
 
-    pub fn read<S: Read>(mut self, mut stream: S) -> StreamResult<Self> {
-        let values = self.fields.read_pattern(&mut stream)?;
-        for (name, value) in self.field_names.iter().zip(values.iter()) {
-            self.results.insert(name.clone(), *value);
-        }
+    /// How many input bytes are required at least to statisfy this pattern,
+    /// strings and buffers of variable length are counted as empty,
+    /// as are the tokens that move around the stream.
+    ///
+    /// # Returns
+    /// The number of bytes required to read this pattern.
+    pub fn pattern_required_bytes(&self) -> u64 {
+        grouped_size(&self.pattern, token_required_bytes) as u64
+    }
+
+    /// Read the stream according to the given `format` and return the result.
+    ///
+    /// # Returns
+    /// a ```Vec<AnyValue>``` containing the read values.
+    ///
+    /// # Errors
+    /// [`StreamError::Validation`] if a checked value is invalid, the field is the index
+    /// of the value and the offset is counted from the start of the pattern.
+    pub fn read_pattern<S: Read>(&self, stream: S) -> StreamResult<Vec<AnyValue>> {
+        read_tokens(
+            &mut CountingReader::new(stream),
+            &self.pattern,
+            &[],
+            self.endianness,
+            None,
+        )
+    }
+
+    /// Same as [`PatternReader::read_pattern`] but the pattern can move around the stream,
+    /// offsets are counted from the current position of `stream`.
+    ///
+    /// The stream is left after the last token read in order,
+    /// tokens read with [`PatternReaderTokens::At`] don't move it.
+    pub fn read_pattern_seek<S: SeekRead>(&self, stream: S) -> StreamResult<Vec<AnyValue>> {
+        read_tokens(
+            &mut CountingReader::seekable(stream)?,
+            &self.pattern,
+            &[],
+            self.endianness,
+            None,
+        )
+    }
+}
+
+/// How many input bytes are required at least to read `tkn`,
+/// arrays with a count read from the stream are counted as empty.
+fn token_required_bytes(tkn: &PatternReaderTokens) -> usize {
+    match tkn {
+        PatternReaderTokens::Padding(sz) => *sz,
+        PatternReaderTokens::U8 | PatternReaderTokens::I8 | PatternReaderTokens::Bool => 1,
+        PatternReaderTokens::U16 | PatternReaderTokens::I16 | PatternReaderTokens::F16 => 2,
+        PatternReaderTokens::U24 | PatternReaderTokens::I24 => 3,
+        PatternReaderTokens::U32 | PatternReaderTokens::I32 | PatternReaderTokens::F32 => 4,
+        PatternReaderTokens::U48 | PatternReaderTokens::I48 => 6,
+        PatternReaderTokens::U64 | PatternReaderTokens::I64 | PatternReaderTokens::F64 => 8,
+        PatternReaderTokens::U128 | PatternReaderTokens::I128 => 16,
+        PatternReaderTokens::USize => std::mem::size_of::<usize>(),
+        PatternReaderTokens::LPStr(w) | PatternReaderTokens::LPBuf(w) => w.size(),
+        PatternReaderTokens::CStr(_) => 1,
+        PatternReaderTokens::Str(ArrayLen::Fixed(n))
+        | PatternReaderTokens::Bytes(ArrayLen::Fixed(n)) => *n,
+        PatternReaderTokens::Str(ArrayLen::Counted(_))
+        | PatternReaderTokens::Bytes(ArrayLen::Counted(_)) => 0,
+        PatternReaderTokens::Bits(..) => bit_group_bytes(std::slice::from_ref(tkn)),
+        PatternReaderTokens::Check(tkn, _) | PatternReaderTokens::Enum(tkn, ..) => {
+            token_required_bytes(tkn)
+        }
+        PatternReaderTokens::Expr((w, _)) => *w as usize,
+        PatternReaderTokens::Array(ArrayLen::Fixed(n), tkn) => n * token_required_bytes(tkn),
+        PatternReaderTokens::Array(ArrayLen::Counted(_), _) => 0,
+        PatternReaderTokens::Struct(_, tkns) => grouped_size(tkns, token_required_bytes),
+        PatternReaderTokens::If(_, _, then, otherwise) => {
+            token_required_bytes(then).min(token_required_bytes(otherwise))
+        }
+        PatternReaderTokens::Switch(_, cases, default) => cases
+            .iter()
+            .map(|(_, tkn)| tkn)
+            .chain(default.as_deref())
+            .map(token_required_bytes)
+            .min()
+            .unwrap_or(0),
+        PatternReaderTokens::Endian(_, tkn) => token_required_bytes(tkn),
+        PatternReaderTokens::EndianSwitch(..)
+        | PatternReaderTokens::SeekTo(_)
+        | PatternReaderTokens::Skip(_)
+        | PatternReaderTokens::Align(_)
+        | PatternReaderTokens::At(..) => 0,
+    }
+}
+
+/// The largest repeat count [`parse_format`] accepts on a character that is repeated
+/// as separate tokens, i.e. anything but `x` and `s`.
+pub const MAX_FORMAT_REPEAT: usize = 4096;
+
+/// Parse a Python `struct` style format string into pattern tokens.
+///
+/// The format is an optional byte order prefix followed by format characters,
+/// each optionally preceded by a decimal repeat count, whitespace between
+/// format characters is ignored. No alignment is ever applied.
+///
+/// | Prefix       | Byte order |
+/// |--------------|------------|
+/// | `<`          | little     |
+/// | `>` or `!`   | big        |
+/// | `@` or `=`   | native     |
+///
+/// | Char | Token    | Char | Token    | Char | Token  |
+/// |------|----------|------|----------|------|--------|
+/// | `x`  | padding  | `h`  | `I16`    | `q`  | `I64`  |
+/// | `c`  | `U8`     | `H`  | `U16`    | `Q`  | `U64`  |
+/// | `b`  | `I8`     | `i`  | `I32`    | `N`  | `USize`|
+/// | `B`  | `U8`     | `I`  | `U32`    | `e`  | `F16`  |
+/// | `?`  | `Bool`   | `l`  | `I32`    | `f`  | `F32`  |
+/// | `s`  | `Bytes`  | `L`  | `U32`    | `d`  | `F64`  |
+///
+/// A repeat count on `x` is the number of padding bytes and on `s` the length
+/// of the buffer, on any other character it repeats the token, so `"3H"` is the
+/// same as `"HHH"` but `"3s"` is a single 3 byte buffer. Repeating a token more than
+/// [`MAX_FORMAT_REPEAT`] times is rejected, use [`PatternReader::add_array`] for longer runs.
+///
+/// # Returns
+/// The byte order given by the prefix, if any, and the tokens.
+///
+/// # Errors
+/// * [`StreamError::InvalidChar`] with the character position for unknown characters
+///   or a byte order prefix that isn't the first character.
+/// * [`StreamError::InvalidPattern`] for a repeat count that doesn't fit in a `usize`,
+///   isn't followed by a format character or repeats a token more than
+///   [`MAX_FORMAT_REPEAT`] times.
+pub fn parse_format(fmt: &str) -> StreamResult<(Option<Endianness>, Vec<PatternReaderTokens>)> {
+    let mut endianness = None;
+    let mut tokens = Vec::new();
+    let mut count: Option<usize> = None;
+
+    for (pos, c) in fmt.chars().enumerate() {
+        if let Some(digit) = c.to_digit(10) {
+            count = count
+                .unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(digit as usize));
+            if count.is_none() {
+                return Err(StreamError::InvalidPattern(format!(
+                    "repeat count at position {} is too large",
+                    pos
+                )));
+            }
+            continue;
+        }
+
+        let tkn: fn() -> PatternReaderTokens = match c {
+            '<' | '>' | '!' | '@' | '=' if pos == 0 => {
+                endianness = Some(match c {
+                    '<' => Endianness::LittleEndian,
+                    '>' | '!' => Endianness::BigEndian,
+                    _ => native_endianness(),
+                });
+                continue;
+            }
+            c if c.is_whitespace() && count.is_none() => continue,
+            'x' => {
+                let n = count.take().unwrap_or(1);
+                if n > 0 {
+                    tokens.push(PatternReaderTokens::Padding(n));
+                }
+                continue;
+            }
+            's' => {
+                let n = count.take().unwrap_or(1);
+                tokens.push(PatternReaderTokens::Bytes(ArrayLen::Fixed(n)));
+                continue;
+            }
+            'c' | 'B' => || PatternReaderTokens::U8,
+            'b' => || PatternReaderTokens::I8,
+            '?' => || PatternReaderTokens::Bool,
+            'h' => || PatternReaderTokens::I16,
+            'H' => || PatternReaderTokens::U16,
+            'i' | 'l' => || PatternReaderTokens::I32,
+            'I' | 'L' => || PatternReaderTokens::U32,
+            'q' => || PatternReaderTokens::I64,
+            'Q' => || PatternReaderTokens::U64,
+            'N' => || PatternReaderTokens::USize,
+            'e' => || PatternReaderTokens::F16,
+            'f' => || PatternReaderTokens::F32,
+            'd' => || PatternReaderTokens::F64,
+            c => return Err(StreamError::InvalidChar(c, pos)),
+        };
+        let n = count.take().unwrap_or(1);
+        if n > MAX_FORMAT_REPEAT {
+            return Err(StreamError::InvalidPattern(format!(
+                "repeat count {} exceeds {}",
+                n, MAX_FORMAT_REPEAT
+            )));
+        }
+        for _ in 0..n {
+            tokens.push(tkn());
+        }
+    }
+
+    if count.is_some() {
+        return Err(StreamError::InvalidPattern(
+            "repeat count without a format character".into(),
+        ));
+    }
+    Ok((endianness, tokens))
+}
+
+/// Read values from `stream` as described by a Python `struct` style format string,
+/// with the byte order taken from the format prefix, native if there is none.
+///
+/// See [`parse_format`] for the supported syntax.
+pub fn read_format<S: Read>(stream: S, fmt: &str) -> StreamResult<Vec<AnyValue>> {
+    PatternReader::from_format(fmt)?.read_pattern(stream)
+}
+
+pub(crate) fn native_endianness() -> Endianness {
+    if cfg!(target_endian = "little") {
+        Endianness::LittleEndian
+    } else {
+        Endianness::BigEndian
+    }
+}
+
+/// Read a sequence of tokens from `stream` starting with `endianness`,
+/// `names` are the names of the values used in validation errors, the index if missing,
+/// and `parent` the scope of the enclosing pattern, if any.
+fn read_tokens<S: Read>(
+    stream: &mut CountingReader<S>,
+    tkns: &[PatternReaderTokens],
+    names: &[String],
+    mut endianness: Endianness,
+    parent: Option<&Scope>,
+) -> StreamResult<Vec<AnyValue>> {
+    let mut values = Vec::new();
+    let mut i = 0;
+    while i < tkns.len() {
+        let group = bit_group(&tkns[i..]);
+        if !group.is_empty() {
+            values.extend(read_bit_group(stream, group)?);
+            i += group.len();
+            continue;
+        }
+        let scope = Scope::new(names, &values, parent);
+        let v = read_token(stream, &tkns[i], &scope, &mut endianness).map_err(|e| {
+            match names.get(values.len()).filter(|n| !n.is_empty()) {
+                Some(name) => in_field(e, name),
+                None => in_field(e, &values.len().to_string()),
+            }
+        })?;
+        if let Some(v) = v {
+            values.push(v);
+        }
+        i += 1;
+    }
+    Ok(values)
+}
+
+/// Read a single token from `stream`, `scope` has the values already read by the
+/// enclosing pattern and `endianness` is the current endianness of the pattern,
+/// which is updated by [`PatternReaderTokens::EndianSwitch`].
+///
+/// Returns `None` for tokens that don't produce a value, like padding.
+fn read_token<S: Read>(
+    stream: &mut CountingReader<S>,
+    tkn: &PatternReaderTokens,
+    scope: &Scope,
+    endianness: &mut Endianness,
+) -> StreamResult<Option<AnyValue>> {
+    match tkn {
+        PatternReaderTokens::Padding(size) => {
+            for _ in 0..*size {
+                stream.read_u8()?;
+            }
+            Ok(None)
+        }
+        PatternReaderTokens::Array(array_len, tkn) => {
+            let len = array_len.resolve(scope)?;
+            let bounded =
+                matches!(array_len, ArrayLen::Fixed(_)) || len <= MAX_EMPTY_COUNTED_ELEMENTS;
+            let mut list = Vec::new();
+            for i in 0..len {
+                let start = stream.count();
+                let v = read_token(stream, tkn, &scope.inner(), &mut endianness.clone())
+                    .map_err(|e| in_field(e, &i.to_string()))?;
+                if !bounded && stream.count() == start {
+                    return Err(StreamError::InvalidPattern(format!(
+                        "{} elements that don't read any bytes exceed {}",
+                        len, MAX_EMPTY_COUNTED_ELEMENTS
+                    )));
+                }
+                if let Some(v) = v {
+                    list.push(v);
+                }
+            }
+            Ok(Some(AnyValue::List(list)))
+        }
+        PatternReaderTokens::Struct(names, tkns) => {
+            let values = read_tokens(stream, tkns, names, *endianness, Some(scope))?;
+            Ok(Some(AnyValue::Struct(named_values(names, values))))
+        }
+        PatternReaderTokens::If(..) | PatternReaderTokens::Switch(..) => {
+            let branch = tkn.select_branch(scope)?.unwrap();
+            read_token(stream, branch, &scope.inner(), &mut endianness.clone())
+        }
+        PatternReaderTokens::LPStr(w) => Ok(Some(AnyValue::Str(read_lpstr(
+            &mut *stream,
+            *w,
+            *endianness,
+        )?))),
+        PatternReaderTokens::LPBuf(w) => Ok(Some(AnyValue::Bytes(read_lpbuf(
+            &mut *stream,
+            *w,
+            *endianness,
+        )?))),
+        PatternReaderTokens::CStr(maxlen) => {
+            Ok(Some(AnyValue::Str(read_cstr(&mut *stream, *maxlen)?)))
+        }
+        PatternReaderTokens::Str(len) | PatternReaderTokens::Bytes(len) => {
+            let mut buf = read_len(&mut *stream, len.resolve(scope)?)?;
+            if let PatternReaderTokens::Bytes(_) = tkn {
+                return Ok(Some(AnyValue::Bytes(buf)));
+            }
+            if let Some(end) = buf.iter().position(|b| *b == 0) {
+                buf.truncate(end);
+            }
+            match String::from_utf8(buf) {
+                Ok(s) => Ok(Some(AnyValue::Str(s))),
+                Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()),
+            }
+        }
+        PatternReaderTokens::Bits(..) => {
+            Ok(read_bit_group(stream, std::slice::from_ref(tkn))?.pop())
+        }
+        PatternReaderTokens::Check(tkn, constraint) => {
+            check_not_bits(tkn)?;
+            let offset = stream.count();
+            let v = read_token(stream, tkn, scope, endianness)?;
+            match v {
+                Some(v) if !constraint.check(&v) => Err(StreamError::Validation {
+                    field: String::new(),
+                    offset,
+                    value: Box::new(v),
+                    expected: constraint.to_string(),
+                }),
+                v => Ok(v),
+            }
+        }
+        PatternReaderTokens::Enum(tkn, names, unknown) => {
+            if !tkn.is_int() {
+                return Err(StreamError::InvalidPattern(format!(
+                    "enums must wrap an integer token, not {:?}",
+                    tkn
+                )));
+            }
+            let offset = stream.count();
+            let v = match read_token(stream, tkn, scope, endianness)? {
+                Some(AnyValue::Int(v)) => v,
+                v => {
+                    return Err(StreamError::InvalidPattern(format!(
+                        "enums must wrap an integer token, got {:?}",
+                        v
+                    )))
+                }
+            };
+            match names.iter().find(|(n, _)| n.num_eq(&v)) {
+                Some((_, name)) => Ok(Some(AnyValue::Enum(v, name.clone()))),
+                None if *unknown == UnknownValue::KeepRaw => Ok(Some(AnyValue::Int(v))),
+                None => Err(StreamError::Validation {
+                    field: String::new(),
+                    offset,
+                    value: Box::new(AnyValue::Int(v)),
+                    expected: format!(
+                        "one of {:?}",
+                        names.iter().map(|(_, n)| n.as_str()).collect::<Vec<_>>()
+                    ),
+                }),
+            }
+        }
+        PatternReaderTokens::Endian(fixed, tkn) => {
+            read_token(stream, tkn, scope, &mut fixed.clone())
+        }
+        PatternReaderTokens::EndianSwitch(..) => {
+            *endianness = tkn.select_endianness(scope)?;
+            Ok(None)
+        }
+        PatternReaderTokens::SeekTo(offset) => {
+            stream.seek_to(*offset)?;
+            Ok(None)
+        }
+        PatternReaderTokens::Skip(delta) => {
+            stream.seek_by(*delta)?;
+            Ok(None)
+        }
+        PatternReaderTokens::Align(n) => {
+            let rem = stream
+                .count()
+                .checked_rem(*n)
+                .ok_or_else(|| StreamError::InvalidPattern("can't align to 0 bytes".into()))?;
+            if rem != 0 {
+                let padding = PatternReaderTokens::Padding((n - rem) as usize);
+                read_token(stream, &padding, scope, endianness)?;
+            }
+            Ok(None)
+        }
+        PatternReaderTokens::At(r, tkn) => {
+            let back = stream.count();
+            stream.seek_to(scope.int(r)?.to_u64_checked()?)?;
+            let v = read_token(stream, tkn, scope, &mut endianness.clone())?;
+            stream.seek_to(back)?;
+            Ok(v)
+        }
+        _ => match endianness {
+            Endianness::LittleEndian => read_scalar::<_, byteorder::LittleEndian>(stream, tkn),
+            Endianness::BigEndian => read_scalar::<_, byteorder::BigEndian>(stream, tkn),
+        },
+    }
+}
+
+/// Read a token that doesn't contain other tokens using the byte order `Ord`.
+fn read_scalar<S: Read, Ord: byteorder::ByteOrder>(
+    stream: &mut S,
+    tkn: &PatternReaderTokens,
+) -> StreamResult<Option<AnyValue>> {
+    let v = match tkn {
+        PatternReaderTokens::F16 => Some(AnyValue::F16(f16_to_f32(stream.read_u16::<Ord>()?))),
+        PatternReaderTokens::F32 => Some(AnyValue::F32(stream.read_f32::<Ord>()?)),
+        PatternReaderTokens::F64 => Some(AnyValue::F64(stream.read_f64::<Ord>()?)),
+        _ => None,
+    };
+
+    if v.is_some() {
+        return Ok(v);
+    }
+
+    let v = match tkn {
+        PatternReaderTokens::U8 => Some(AnyInt::U8(stream.read_u8()?)),
+        PatternReaderTokens::I8 => Some(AnyInt::I8(stream.read_i8()?)),
+        _ => None,
+    };
+
+    if let Some(v) = v {
+        return Ok(Some(v.into()));
+    }
+
+    // the rest of the format characters require at least 2 bytes
+    let v = match tkn {
+        PatternReaderTokens::U16 => AnyInt::U16(stream.read_u16::<Ord>()?),
+        PatternReaderTokens::U32 => AnyInt::U32(stream.read_u32::<Ord>()?),
+        PatternReaderTokens::U64 => AnyInt::U64(stream.read_u64::<Ord>()?),
+        PatternReaderTokens::U24 => AnyInt::U32(stream.read_u24::<Ord>()?),
+        PatternReaderTokens::U48 => AnyInt::U48(stream.read_u48::<Ord>()?),
+        PatternReaderTokens::U128 => AnyInt::U128(stream.read_u128::<Ord>()?),
+        PatternReaderTokens::I16 => AnyInt::I16(stream.read_i16::<Ord>()?),
+        PatternReaderTokens::I32 => AnyInt::I32(stream.read_i32::<Ord>()?),
+        PatternReaderTokens::I64 => AnyInt::I64(stream.read_i64::<Ord>()?),
+        PatternReaderTokens::I24 => AnyInt::I32(stream.read_i24::<Ord>()?),
+        PatternReaderTokens::I48 => AnyInt::I48(stream.read_i48::<Ord>()?),
+        PatternReaderTokens::I128 => AnyInt::I128(stream.read_i128::<Ord>()?),
+        PatternReaderTokens::USize => {
+            if std::mem::size_of::<usize>() == 4 {
+                AnyInt::U32(stream.read_u32::<Ord>()?)
+            } else {
+                AnyInt::U64(stream.read_u64::<Ord>()?)
+            }
+        }
+        PatternReaderTokens::Bool => {
+            let v = stream.read_u8()?;
+            if v == 0 {
+                AnyInt::Bool(false)
+            } else {
+                AnyInt::Bool(true)
+            }
+        }
+        PatternReaderTokens::Expr((par_width, expr)) => {
+            let v = match par_width {
+                1 => AnyInt::U8(stream.read_u8()?),
+                2 => AnyInt::U16(stream.read_u16::<Ord>()?),
+                4 => AnyInt::U32(stream.read_u32::<Ord>()?),
+                8 => AnyInt::U64(stream.read_u64::<Ord>()?),
+                _ => {
+                    return Err(StreamError::InvalidPattern(
+                        "invalid parameter width".into(),
+                    ))
+                }
+            };
+            if expr(v) {
+                AnyInt::Bool(true)
+            } else {
+                AnyInt::Bool(false)
+            }
+        }
+        PatternReaderTokens::Padding(_)
+        | PatternReaderTokens::U8
+        | PatternReaderTokens::I8
+        | PatternReaderTokens::F16
+        | PatternReaderTokens::F32
+        | PatternReaderTokens::F64
+        | PatternReaderTokens::Array(..)
+        | PatternReaderTokens::Struct(..)
+        | PatternReaderTokens::If(..)
+        | PatternReaderTokens::Switch(..)
+        | PatternReaderTokens::Endian(..)
+        | PatternReaderTokens::EndianSwitch(..)
+        | PatternReaderTokens::LPStr(_)
+        | PatternReaderTokens::LPBuf(_)
+        | PatternReaderTokens::CStr(_)
+        | PatternReaderTokens::Str(_)
+        | PatternReaderTokens::Bytes(_)
+        | PatternReaderTokens::Bits(..)
+        | PatternReaderTokens::Check(..)
+        | PatternReaderTokens::Enum(..)
+        | PatternReaderTokens::SeekTo(_)
+        | PatternReaderTokens::Skip(_)
+        | PatternReaderTokens::Align(_)
+        | PatternReaderTokens::At(..) => {
+            unreachable!()
+        }
+    };
+    Ok(Some(v.into()))
+}
+
+/// Read a single token from `stream` with a runtime `endianness`.
+///
+/// This is the same primitive reader used by [`PatternReader::read_pattern`],
+/// exposed so that code generated by `#[derive(NeonRead)]` and hand built
+/// patterns decode values the same way.
+///
+/// # Returns
+/// `None` for tokens that don't produce a value, like padding.
+pub fn read_primitive<S: Read>(
+    stream: S,
+    tkn: &PatternReaderTokens,
+    mut endianness: Endianness,
+) -> StreamResult<Option<AnyValue>> {
+    read_token(
+        &mut CountingReader::new(stream),
+        tkn,
+        &Scope::default(),
+        &mut endianness,
+    )
+}
+
+/// Types that can be read from a stream with a runtime [`Endianness`].
+///
+/// This is implemented for the primitive types [`PatternReader`] knows about,
+/// and can be derived for structs with `#[derive(NeonRead)]` when the `derive`
+/// feature is enabled.
+///
+/// # Derive attributes
+/// Fields are read in declaration order, the following `#[neon(...)]`
+/// attributes are supported on fields:
+/// * `pad_before = N` / `pad_after = N`: skip `N` bytes before or after the field,
+///   the padding of a `cond` field is only skipped when the field is read.
+/// * `endian = "little" | "big"`: read the field with a fixed endianness.
+/// * `read_as = "u32"`: read the field as another type and convert it with [`From`].
+/// * `cond = "expr"`: only read the field if `expr` is true, the field must be an [`Option`],
+///   previously read fields are in scope by name.
+pub trait NeonRead: Sized {
+    fn read_from<R: Read>(reader: R, endianness: Endianness) -> StreamResult<Self>;
+}
+
+macro_rules! impl_neon_read {
+    ($t:ty, $tkn:ident) => {
+        impl NeonRead for $t {
+            fn read_from<R: Read>(reader: R, endianness: Endianness) -> StreamResult<Self> {
+                match read_primitive(reader, &PatternReaderTokens::$tkn, endianness)? {
+                    Some(v) => Ok(v.try_into()?),
+                    None => unreachable!(),
+                }
+            }
+        }
+    };
+}
+
+impl_neon_read!(u8, U8);
+impl_neon_read!(u16, U16);
+impl_neon_read!(u32, U32);
+impl_neon_read!(u64, U64);
+impl_neon_read!(i8, I8);
+impl_neon_read!(i16, I16);
+impl_neon_read!(i32, I32);
+impl_neon_read!(i64, I64);
+impl_neon_read!(u128, U128);
+impl_neon_read!(i128, I128);
+impl_neon_read!(bool, Bool);
+impl_neon_read!(f32, F32);
+impl_neon_read!(f64, F64);
+
+/// An immutable description of the fields of a struct, built with a [`StructReader`]
+/// and shared to parse any number of structs with [`StructSchema::parse`].
+///
+/// Schemas are `Clone`, `Send` and `Sync`, so they can be built once and
+/// parsed from multiple threads.
+#[derive(Clone, Debug)]
+pub struct StructSchema {
+    fields: PatternReader,
+    /// The name of each value of `fields`, conditional fields are unnamed.
+    field_names: Vec<String>,
+    /// Names declared inside the branches of conditional fields.
+    branch_names: Vec<String>,
+    /// The first error found while declaring the fields, returned by every parse.
+    error: Option<String>,
+}
+
+impl StructSchema {
+    fn new(endianness: Endianness) -> Self {
+        Self {
+            fields: PatternReader::new(endianness),
+            field_names: Vec::new(),
+            branch_names: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Keep `error` unless an earlier one was found.
+    fn set_error(&mut self, error: String) {
+        self.error.get_or_insert(error);
+    }
+
+    /// Declare a field name, an empty name, a name containing a `.` or a name
+    /// that was already declared is an error returned when parsing.
+    fn push_name(&mut self, name: &str) {
+        if name.is_empty() || name.contains('.') {
+            self.set_error(format!("invalid field name {:?}", name));
+        } else if self.declared_names().any(|n| n == name) {
+            self.set_error(format!("duplicate field name {}", name));
+        }
+        self.field_names.push(name.to_string());
+    }
+
+    /// All the names declared in this struct, including the ones inside conditional fields.
+    fn declared_names(&self) -> impl Iterator<Item = &str> {
+        self.field_names
+            .iter()
+            .chain(self.branch_names.iter())
+            .map(String::as_str)
+            .filter(|n| !n.is_empty())
+    }
+
+    /// Add the tokens of `fields` as an unnamed field, with the names of `branches`.
+    fn push_branches(&mut self, tkn: PatternReaderTokens, branches: &[&Self]) {
+        let mut names: Vec<String> = Vec::new();
+        for branch in branches {
+            if let Some(e) = &branch.error {
+                self.set_error(e.clone());
+            }
+        }
+        for name in branches.iter().flat_map(|b| b.declared_names()) {
+            if self.declared_names().any(|n| n == name) {
+                self.set_error(format!("duplicate field name {}", name));
+            }
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        self.branch_names.extend(names);
+        self.fields.pattern.push(tkn);
+        self.field_names.push(String::new());
+    }
+
+    /// Turn the schema into a [`PatternReaderTokens::Struct`], dropping its error.
+    fn into_token(self) -> PatternReaderTokens {
+        PatternReaderTokens::Struct(self.field_names, self.fields.pattern)
+    }
+
+    /// Turn `fields` into a [`PatternReaderTokens::Struct`] nested in this schema,
+    /// an error of `fields` becomes an error of this schema.
+    fn nest(&mut self, mut fields: Self) -> PatternReaderTokens {
+        if let Some(e) = fields.error.take() {
+            self.set_error(e);
+        }
+        fields.into_token()
+    }
+
+    /// The names of the fields in declaration order,
+    /// fields declared inside conditional fields aren't included.
+    pub fn field_names(&self) -> impl Iterator<Item = &str> {
+        self.field_names
+            .iter()
+            .map(String::as_str)
+            .filter(|n| !n.is_empty())
+    }
+
+    pub fn required_bytes(&self) -> u64 {
+        self.fields.pattern_required_bytes()
+    }
+
+    /// Parse a struct from `stream` into a new [`Record`].
+    ///
+    /// # Errors
+    /// [`StreamError::InvalidPattern`] if a field name is invalid or declared twice,
+    /// in this struct or in its sub structs, or an error of reading the fields.
+    pub fn parse<S: Read>(&self, stream: S) -> StreamResult<Record> {
+        self.parse_counted(&mut CountingReader::new(stream))
+    }
+
+    /// Same as [`StructSchema::parse`] but the fields can move around the stream,
+    /// e.g. fields added with [`StructReader::add_struct_at`], offsets are counted
+    /// from the current position of `stream`.
+    pub fn parse_seek<S: SeekRead>(&self, stream: S) -> StreamResult<Record> {
+        self.parse_counted(&mut CountingReader::seekable(stream)?)
+    }
+
+    fn parse_counted<S: Read>(&self, stream: &mut CountingReader<S>) -> StreamResult<Record> {
+        if let Some(e) = &self.error {
+            return Err(StreamError::InvalidPattern(e.clone()));
+        }
+        let mut record = Record::default();
+        parse_fields(
+            stream,
+            &self.field_names,
+            &self.fields.pattern,
+            &mut record,
+            self.fields.endianness,
+            None,
+        )?;
+        Ok(record)
+    }
+}
+
+/// Read the named `tkns` into `record`, the fields of the selected branch of conditional
+/// tokens are added to `record` as well, `parent` is the scope of the enclosing struct.
+///
+/// # Returns
+/// The values of the tokens, for conditional tokens this is a struct of the branch fields.
+fn parse_fields<S: Read>(
+    stream: &mut CountingReader<S>,
+    names: &[String],
+    tkns: &[PatternReaderTokens],
+    record: &mut Record,
+    mut endianness: Endianness,
+    parent: Option<&Scope>,
+) -> StreamResult<Vec<AnyValue>> {
+    let mut values = Vec::new();
+    let mut unread = names.iter();
+    let mut i = 0;
+    while i < tkns.len() {
+        let tkn = &tkns[i];
+        let scope = Scope::new(names, &values, parent);
+        let mut start = stream.count();
+        // the end of a field read somewhere else, see `PatternReaderTokens::At`
+        let mut end = None;
+        let field_err = |e| match unread.as_slice().first() {
+            Some(name) => in_field(e, name),
+            None => e,
+        };
+        let group = bit_group(&tkns[i..]);
+        let read = if !group.is_empty() {
+            i += group.len();
+            read_bit_group(stream, group)?
+        } else {
+            i += 1;
+            match tkn {
+                PatternReaderTokens::If(..) => match tkn.select_branch(&scope)? {
+                    Some(PatternReaderTokens::Struct(names, tkns)) => {
+                        let branch =
+                            parse_fields(stream, names, tkns, record, endianness, Some(&scope))?;
+                        vec![AnyValue::Struct(named_values(names, branch))]
+                    }
+                    _ => {
+                        return Err(StreamError::InvalidPattern(
+                            "branches must be structs".into(),
+                        ))
+                    }
+                },
+                PatternReaderTokens::At(r, inner) => {
+                    let back = stream.count();
+                    start = scope
+                        .int(r)
+                        .and_then(|offset| offset.to_u64_checked())
+                        .map_err(field_err)?;
+                    stream.seek_to(start).map_err(field_err)?;
+                    let v = read_token(stream, inner, &scope, &mut endianness.clone())
+                        .map_err(field_err)?;
+                    end = Some(stream.count());
+                    stream.seek_to(back)?;
+                    v.into_iter().collect()
+                }
+                _ => read_token(stream, tkn, &scope, &mut endianness)
+                    .map_err(field_err)?
+                    .into_iter()
+                    .collect(),
+            }
+        };
+
+        for v in read {
+            match unread.next() {
+                Some(name) if !name.is_empty() => {
+                    let end = end.unwrap_or(stream.count());
+                    record.layout.push((name.clone(), start, end - start));
+                    record.values.insert(name.clone(), v.clone());
+                }
+                _ => {}
+            }
+            values.push(v);
+        }
+    }
+    Ok(values)
+}
+
+/// The fields of a struct parsed by a [`StructSchema`], in declaration order.
+#[derive(Clone, Default, PartialEq)]
+pub struct Record {
+    values: HashMap<String, AnyValue>,
+    /// `(name, offset, size)` of each field, in declaration order.
+    layout: Vec<(String, u64, u64)>,
+}
+
+impl Record {
+    /// Iterate over the fields in declaration order.
+    ///
+    /// # Returns
+    /// An iterator of `(name, offset, size, value)`, the offset is in bytes
+    /// from the start of the struct.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64, u64, &AnyValue)> {
+        self.layout.iter().filter_map(|(name, offset, size)| {
+            Some((name.as_str(), *offset, *size, self.values.get(name)?))
+        })
+    }
+
+    /// Get the value of a field, nested fields of sub structs and elements of arrays
+    /// can be addressed with a dot separated path, e.g. `"e_ident.ei_class"`,
+    /// see [`AnyValue::get`].
+    pub fn get(&self, path: &str) -> Option<&AnyValue> {
+        let (head, rest) = path.split_once('.').unwrap_or((path, ""));
+        self.values.get(head)?.get(rest)
+    }
+
+    /// The fields as a tree of values, an [`AnyValue::Struct`] with the fields
+    /// in declaration order.
+    pub fn to_value(&self) -> AnyValue {
+        AnyValue::Struct(
+            self.iter()
+                .map(|(name, _, _, v)| (name.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    /// The fields as a HashMap, which doesn't keep the declaration order.
+    pub fn values(&self) -> &HashMap<String, AnyValue> {
+        &self.values
+    }
+
+    /// The fields as a HashMap, which doesn't keep the declaration order.
+    pub fn into_map(self) -> HashMap<String, AnyValue> {
+        self.values
+    }
+
+    /// The fields in declaration order.
+    pub fn into_vec(mut self) -> Vec<(String, AnyValue)> {
+        self.layout
+            .into_iter()
+            .filter_map(|(name, _, _)| {
+                let v = self.values.remove(&name)?;
+                Some((name, v))
+            })
+            .collect()
+    }
+}
+
+impl Debug for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(name, _, _, v)| (name, v)))
+            .finish()
+    }
+}
+
+impl std::ops::Index<&str> for Record {
+    type Output = AnyValue;
+    /// Warning: panics if the field is not found
+    fn index(&self, path: &str) -> &Self::Output {
+        self.get(path).unwrap()
+    }
+}
+
+/// Read a struct from a stream into named fields.
+///
+/// Fields are declared with the `add_*` methods and read in declaration order,
+/// the results keep that order in [`StructReader::iter`], [`StructReader::into_vec`]
+/// and the `Debug` output.
+///
+/// To parse many structs with the same layout build the reader once and
+/// use the [`StructSchema`] from [`StructReader::into_schema`].
+///
+/// A field name that is empty, contains a `.` or is declared twice doesn't stop
+/// the declarations, reading fails with [`StreamError::InvalidPattern`] instead.
+///
+/// Fields that depend on another field, e.g. the number of elements of an array,
+/// refer to it by its path, see [`ValueRef::Field`]: a field of this struct or of a
+/// struct around it, or a field of a sub struct like `"e_ident.ei_class"`.
+/// Reading fails with [`StreamError::InvalidPattern`] if that field wasn't read before.
+///
+/// The byte order is chosen when the reader is created, [`StructReader::default`]
+/// reads little endian.
+pub struct StructReader {
+    schema: StructSchema,
+    record: Record,
+    bit_order: BitOrder,
+}
+
+impl StructReader {
+    /// Create an empty struct, fields are read with `endianness`
+    /// unless it's overridden or switched.
+    pub fn new(endianness: Endianness) -> Self {
+        Self {
+            schema: StructSchema::new(endianness),
+            record: Record::default(),
+            bit_order: BitOrder::MsbFirst,
+        }
+    }
+
+    pub fn new_be() -> Self {
+        Self::new(Endianness::BigEndian)
+    }
+
+    pub fn new_le() -> Self {
+        Self::new(Endianness::LittleEndian)
+    }
+
+    pub fn add_u8_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u8();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_u16_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u16();
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a 24 bit unsigned integer field, it's read as an [`AnyInt::U32`].
+    pub fn add_u24_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u24();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_u32_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u32();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_u48_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u48();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_u64_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u64();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_u128_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_u128();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_usize_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_usize();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i8_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i8();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i16_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i16();
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a 24 bit signed integer field, it's sign extended and read as an [`AnyInt::I32`].
+    pub fn add_i24_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i24();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i32_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i32();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i48_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i48();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i64_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i64();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_i128_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_i128();
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add an IEEE 754 half precision float field, it's widened to an `f32` when read.
+    pub fn add_f16_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_f16();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_f32_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_f32();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_f64_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_f64();
+        self.schema.push_name(name);
+        self
+    }
+
+    pub fn add_padding(mut self, size: usize) -> Self {
+        self.schema.fields.add_padding(size);
+        self
+    }
+
+    /// Move to `offset` counted from the start of the read before reading the next field.
+    ///
+    /// The struct must be read with [`StructReader::read_seek`].
+    pub fn add_seek_to(mut self, offset: u64) -> Self {
+        self.schema.fields.add_seek_to(offset);
+        self
+    }
+
+    /// Move `delta` bytes from the current offset before reading the next field,
+    /// backwards if negative.
+    ///
+    /// The struct must be read with [`StructReader::read_seek`].
+    pub fn add_skip(mut self, delta: i64) -> Self {
+        self.schema.fields.add_skip(delta);
+        self
+    }
+
+    /// Skip to the next offset that is a multiple of `n` bytes counted from the start
+    /// of the read, this works on any stream.
+    ///
+    /// Reading fails with [`StreamError::InvalidPattern`] if `n` is 0.
+    pub fn add_align(mut self, n: u64) -> Self {
+        self.schema.fields.add_align(n);
+        self
+    }
+
+    pub fn add_bool_field(mut self, name: &str) -> Self {
+        self.schema.fields.add_bool();
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Set the bit order of the bits fields added after this, the default is
+    /// [`BitOrder::MsbFirst`].
+    pub fn set_bit_order(mut self, order: BitOrder) -> Self {
+        self.bit_order = order;
+        self
+    }
+
+    /// Add an unsigned integer field of `nbits` bits, read as the smallest [`AnyInt`] that fits.
+    ///
+    /// Consecutive bits fields are packed together into whole bytes, e.g. the data offset,
+    /// reserved bits and flags of a TCP header are 3 fields packed into 2 bytes.
+    /// In the layout of [`Record::iter`] every field of a group spans all of its bytes.
+    ///
+    /// # Panics
+    /// If `nbits` is 0 or greater than 64.
+    pub fn add_bits_field(mut self, name: &str, nbits: u32) -> Self {
+        self.schema.fields.add_bits(self.bit_order, nbits);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a string field with a length prefix of `width`, read as an [`AnyValue::Str`].
+    pub fn add_lpstr_field(mut self, name: &str, width: LPWidth) -> Self {
+        self.schema.fields.add_lpstr(width);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a byte buffer field with a length prefix of `width`, read as an [`AnyValue::Bytes`].
+    pub fn add_lpbuf_field(mut self, name: &str, width: LPWidth) -> Self {
+        self.schema.fields.add_lpbuf(width);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a NUL terminated string field of at most `maxlen` bytes including the terminator.
+    pub fn add_cstr_field(mut self, name: &str, maxlen: usize) -> Self {
+        self.schema.fields.add_cstr(maxlen);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a string field stored in exactly `len` bytes padded with NULs,
+    /// e.g. a section name.
+    pub fn add_str_field(mut self, name: &str, len: usize) -> Self {
+        self.schema.fields.add_str(len);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a byte buffer field of exactly `len` bytes.
+    pub fn add_bytes_field(mut self, name: &str, len: usize) -> Self {
+        self.schema.fields.add_bytes(len);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a NUL padded string field whose length in bytes is the value of the
+    /// previously read integer field `len_field`.
+    pub fn add_counted_str_field(mut self, name: &str, len_field: &str) -> Self {
+        let len = ValueRef::Field(len_field.to_string());
+        self.schema
+            .fields
+            .pattern
+            .push(PatternReaderTokens::Str(ArrayLen::Counted(len)));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a byte buffer field whose length is the value of the previously
+    /// read integer field `len_field`.
+    pub fn add_counted_bytes_field(mut self, name: &str, len_field: &str) -> Self {
+        let len = ValueRef::Field(len_field.to_string());
+        self.schema
+            .fields
+            .pattern
+            .push(PatternReaderTokens::Bytes(ArrayLen::Counted(len)));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a field whose value is replaced by the result of `expr`, see [`PatternReader::add_expr`].
+    ///
+    /// Use [`StructReader::add_predicate_field`] to keep the value.
+    pub fn add_expr_field(mut self, name: &str, par_width: u8, expr: fn(AnyInt) -> bool) -> Self {
+        self.schema.fields.add_expr(par_width, expr);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a field of `tkn` that must equal `magic`, e.g. the signature of a file format.
+    ///
+    /// Reading fails with [`StreamError::Validation`] naming the field and its offset
+    /// if the value differs, integers are compared by value regardless of their width.
+    pub fn add_magic_field(
+        mut self,
+        name: &str,
+        tkn: PatternReaderTokens,
+        magic: impl Into<AnyValue>,
+    ) -> Self {
+        self.schema
+            .fields
+            .add_check(tkn, Constraint::Eq(magic.into()));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add an integer field of `tkn` that must be in `range`.
+    ///
+    /// Reading fails with [`StreamError::Validation`] naming the field and its offset
+    /// if the value is out of range.
+    pub fn add_range_field<T: Into<AnyInt>>(
+        mut self,
+        name: &str,
+        tkn: PatternReaderTokens,
+        range: std::ops::RangeInclusive<T>,
+    ) -> Self {
+        let (min, max) = range.into_inner();
+        self.schema
+            .fields
+            .add_check(tkn, Constraint::Range(min.into(), max.into()));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a field of `tkn` whose value must satisfy `predicate`.
+    ///
+    /// Reading fails with [`StreamError::Validation`] naming the field and its offset
+    /// if `predicate` returns `false`.
+    pub fn add_predicate_field(
+        mut self,
+        name: &str,
+        tkn: PatternReaderTokens,
+        predicate: impl Fn(&AnyValue) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.schema
+            .fields
+            .add_check(tkn, Constraint::Predicate(Arc::new(predicate)));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add an integer field of `tkn` decoded with a table of `values` and their names,
+    /// a known value is read as an [`AnyValue::Enum`] with the raw value and the name,
+    /// see [`AnyValue::as_int`] and [`AnyValue::enum_name`].
+    ///
+    /// `unknown` decides whether a value that isn't in the table fails the read
+    /// with [`StreamError::Validation`] or is kept as a plain [`AnyValue::Int`].
+    ///
+    /// `tkn` must be an integer token, see [`PatternReader::add_enum`].
+    pub fn add_enum_field<T: Into<AnyInt> + Copy>(
+        mut self,
+        name: &str,
+        tkn: PatternReaderTokens,
+        values: &[(T, &str)],
+        unknown: UnknownValue,
+    ) -> Self {
+        self.schema.fields.add_enum(tkn, values, unknown);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a `u8` enum field, unknown values are kept, see [`StructReader::add_enum_field`].
+    pub fn add_u8_enum_field(self, name: &str, values: &[(u8, &str)]) -> Self {
+        self.add_enum_field(name, PatternReaderTokens::U8, values, UnknownValue::KeepRaw)
+    }
+
+    /// Add a `u16` enum field, unknown values are kept, see [`StructReader::add_enum_field`].
+    pub fn add_u16_enum_field(self, name: &str, values: &[(u16, &str)]) -> Self {
+        self.add_enum_field(
+            name,
+            PatternReaderTokens::U16,
+            values,
+            UnknownValue::KeepRaw,
+        )
+    }
+
+    /// Add a `u32` enum field, unknown values are kept, see [`StructReader::add_enum_field`].
+    pub fn add_u32_enum_field(self, name: &str, values: &[(u32, &str)]) -> Self {
+        self.add_enum_field(
+            name,
+            PatternReaderTokens::U32,
+            values,
+            UnknownValue::KeepRaw,
+        )
+    }
+
+    /// Add a sub struct field, read as an [`AnyValue::Struct`] with the fields
+    /// of `fields` in declaration order.
+    ///
+    /// The sub struct is read with the endianness of this struct at that point,
+    /// the endianness `fields` was created with is ignored.
+    pub fn add_struct_field(mut self, name: &str, fields: Self) -> Self {
+        let tkn = self.schema.nest(fields.schema);
+        self.schema.fields.pattern.push(tkn);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add an array field of `len` elements of `tkn`, read as an [`AnyValue::List`].
+    pub fn add_array_field(mut self, name: &str, len: usize, tkn: PatternReaderTokens) -> Self {
+        self.schema.fields.add_array(len, tkn);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add an array field of `element` structs, the number of elements is the value
+    /// of the previously read integer field `count_field`.
+    ///
+    /// The field is read as an [`AnyValue::List`] of [`AnyValue::Struct`]s,
+    /// see [`MAX_EMPTY_COUNTED_ELEMENTS`] for elements that don't read any bytes.
+    pub fn add_counted_array(mut self, name: &str, count_field: &str, element: Self) -> Self {
+        let count = ValueRef::Field(count_field.to_string());
+        let element = self.schema.nest(element.schema);
+        self.schema.fields.pattern.push(PatternReaderTokens::Array(
+            ArrayLen::Counted(count),
+            Box::new(element),
+        ));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Read the fields of `then` if the integer field `field` equals `value`,
+    /// the fields of `otherwise`, if any, if not.
+    ///
+    /// The fields of the selected branch are part of this struct, both branches
+    /// can declare fields with the same names, e.g. fields with different widths,
+    /// but reading fails with [`StreamError::InvalidPattern`] if they declare names
+    /// already used in this struct.
+    ///
+    /// Each branch is otherwise read as a separate struct: an
+    /// [`StructReader::add_endian_switch`] inside a branch only applies to the rest of
+    /// that branch, and fields after this one are read with the endianness of this struct.
+    /// A branch can still refer to the fields of this struct read before it.
+    pub fn add_if(
+        mut self,
+        field: &str,
+        value: impl Into<AnyInt>,
+        then: Self,
+        otherwise: Option<Self>,
+    ) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        let otherwise = otherwise.unwrap_or_default().schema;
+        let then = then.schema;
+        let tkn_then = then.clone().into_token();
+        let tkn_otherwise = otherwise.clone().into_token();
+        self.schema.push_branches(
+            PatternReaderTokens::If(
+                index,
+                value.into(),
+                Box::new(tkn_then),
+                Box::new(tkn_otherwise),
+            ),
+            &[&then, &otherwise],
+        );
+        self
+    }
+
+    /// Add a sub struct field whose layout is selected by the value of the integer
+    /// field `field`, the struct of the first matching case is read, or `default`
+    /// if no case matches.
+    ///
+    /// Reading fails if no case matches and there's no `default`.
+    pub fn add_switch_field(
+        mut self,
+        name: &str,
+        field: &str,
+        cases: Vec<(AnyInt, Self)>,
+        default: Option<Self>,
+    ) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        let cases = cases
+            .into_iter()
+            .map(|(v, s)| (v, self.schema.nest(s.schema)))
+            .collect();
+        let default = default.map(|s| Box::new(self.schema.nest(s.schema)));
+        self.schema
+            .fields
+            .pattern
+            .push(PatternReaderTokens::Switch(index, cases, default));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a field read with a fixed `endianness`, regardless of the endianness of the struct.
+    pub fn add_endian_field(
+        mut self,
+        name: &str,
+        endianness: Endianness,
+        tkn: PatternReaderTokens,
+    ) -> Self {
+        self.schema.fields.add_endian(endianness, tkn);
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Read all the following fields of this struct with the endianness of the first case
+    /// equal to the value of the integer field `field`, e.g. the `EI_DATA` byte of an ELF header.
+    ///
+    /// Reading fails if no case matches.
+    pub fn add_endian_switch(mut self, field: &str, cases: &[(AnyInt, Endianness)]) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        self.schema
+            .fields
+            .pattern
+            .push(PatternReaderTokens::EndianSwitch(index, cases.to_vec()));
+        self
+    }
+
+    /// Add a field of `tkn` read at the offset given by the previously declared integer
+    /// field `offset_field`, counted from the start of the read. The next field is read
+    /// after the previous one, as if this field wasn't there.
+    ///
+    /// The struct must be read with [`StructReader::read_seek`], in the layout of
+    /// [`Record::iter`] the field is at the offset it was read at.
+    pub fn add_field_at(
+        mut self,
+        name: &str,
+        offset_field: &str,
+        tkn: PatternReaderTokens,
+    ) -> Self {
+        let offset = ValueRef::Field(offset_field.to_string());
+        self.schema
+            .fields
+            .pattern
+            .push(PatternReaderTokens::At(offset, Box::new(tkn)));
+        self.schema.push_name(name);
+        self
+    }
+
+    /// Add a sub struct field read at the offset given by the previously declared
+    /// integer field `offset_field`, see [`StructReader::add_field_at`].
+    pub fn add_struct_at(mut self, name: &str, offset_field: &str, fields: Self) -> Self {
+        let tkn = self.schema.nest(fields.schema);
+        self.add_field_at(name, offset_field, tkn)
+    }
+
+    /// Add an array field of `element` structs read at the offset given by the previously
+    /// declared integer field `offset_field`, the number of elements is the value of the
+    /// previously declared integer field `count_field`, e.g. the program headers of an ELF file.
+    ///
+    /// See [`StructReader::add_field_at`] and [`StructReader::add_counted_array`].
+    pub fn add_counted_array_at(
+        mut self,
+        name: &str,
+        offset_field: &str,
+        count_field: &str,
+        element: Self,
+    ) -> Self {
+        let count = ValueRef::Field(count_field.to_string());
+        let element = self.schema.nest(element.schema);
+        self.add_field_at(
+            name,
+            offset_field,
+            PatternReaderTokens::Array(ArrayLen::Counted(count), Box::new(element)),
+        )
+    }
+
+    pub fn required_bytes(&self) -> u64 {
+        self.schema.required_bytes()
+    }
+
+    pub fn read<S: Read>(mut self, stream: S) -> StreamResult<Self> {
+        self.record = self.schema.parse(stream)?;
         Ok(self)
     }
 
-    pub fn get(&self, name: &str) -> Option<AnyInt> {
-        self.results.get(name).cloned()
+    /// Read the struct from a seekable stream, see [`StructSchema::parse_seek`].
+    pub fn read_seek<S: SeekRead>(mut self, stream: S) -> StreamResult<Self> {
+        self.record = self.schema.parse_seek(stream)?;
+        Ok(self)
+    }
+
+    /// The schema of the declared fields, see [`StructSchema::parse`].
+    pub fn schema(&self) -> &StructSchema {
+        &self.schema
+    }
+
+    /// Consume the reader and return the schema of the declared fields.
+    pub fn into_schema(self) -> StructSchema {
+        self.schema
+    }
+
+    /// The result of the last [`StructReader::read`].
+    pub fn record(&self) -> &Record {
+        &self.record
+    }
+
+    /// Iterate over the read fields in declaration order, see [`Record::iter`].
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64, u64, &AnyValue)> {
+        self.record.iter()
+    }
+
+    /// Get the value of a field, nested fields of sub structs and elements of arrays
+    /// can be addressed with a dot separated path, e.g. `"e_ident.ei_class"`,
+    /// see [`AnyValue::get`].
+    pub fn get(&self, path: &str) -> Option<AnyValue> {
+        self.record.get(path).cloned()
+    }
+
+    /// The results as a tree of values, an [`AnyValue::Struct`] with the fields
+    /// in declaration order.
+    pub fn to_value(&self) -> AnyValue {
+        self.record.to_value()
+    }
+
+    /// reuturns the results as a HashMap, which doesn't keep the declaration order,
+    /// and consumes the StructReader
+    pub fn into_inner(self) -> HashMap<String, AnyValue> {
+        self.record.into_map()
+    }
+
+    pub fn get_inner_pattern(&self) -> &PatternReader {
+        &self.schema.fields
+    }
+
+    pub fn results(&self) -> &HashMap<String, AnyValue> {
+        self.record.values()
+    }
+
+    /// The results in declaration order, consuming the StructReader.
+    pub fn into_vec(self) -> Vec<(String, AnyValue)> {
+        self.record.into_vec()
+    }
+}
+
+impl Debug for StructReader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StructReader")
+            .field("fields", &self.schema.fields)
+            .field("field_names", &self.schema.field_names)
+            .field("results", &self.record)
+            .finish()
+    }
+}
+
+impl std::ops::Index<&str> for StructReader {
+    type Output = AnyValue;
+    /// Warning: panics if the field is not found
+    fn index(&self, path: &str) -> &Self::Output {
+        &self.record[path]
+    }
+}
+
+impl Default for StructReader {
+    fn default() -> Self {
+        Self::new_le()
+    }
+}
+
+impl Default for PatternReader {
+    fn default() -> Self {
+        Self::new_le()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::AnyInt;
+
+    const DATA: [u8; 168] = [
+        0x00, 0x2F, 0x6D, 0x61, 0x78, 0x5F, 0x73, 0x69, 0x7A, 0x65, 0x2E, 0x72, 0x73, 0x55, 0x54,
+        0x05, 0x00, 0x01, 0xA9, 0xBA, 0xEE, 0x63, 0x50, 0x4B, 0x01, 0x02, 0x00, 0x00, 0x0A, 0x00,
+        0x00, 0x00, 0x08, 0x00, 0xC8, 0x7A, 0x50, 0x56, 0xDB, 0x87, 0xEE, 0xBA, 0x1A, 0x02, 0x00,
+        0x00, 0x8C, 0x09, 0x00, 0x00, 0x1D, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0xF5, 0xEC, 0x00, 0x00, 0x70, 0x6F, 0x73, 0x74, 0x63, 0x61, 0x72,
+        0x64, 0x2D, 0x6D, 0x61, 0x69, 0x6E, 0x2F, 0x74, 0x65, 0x73, 0x74, 0x73, 0x2F, 0x73, 0x63,
+        0x68, 0x65, 0x6D, 0x61, 0x2E, 0x72, 0x73, 0x55, 0x54, 0x05, 0x00, 0x01, 0xA9, 0xBA, 0xEE,
+        0x63, 0x50, 0x4B, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x2C, 0x00, 0x82, 0x0E,
+        0x00, 0x00, 0x53, 0xEF, 0x00, 0x00, 0x28, 0x00, 0x61, 0x31, 0x63, 0x33, 0x61, 0x66, 0x34,
+        0x37, 0x61, 0x65, 0x63, 0x34, 0x33, 0x33, 0x61, 0x34, 0x30, 0x30, 0x62, 0x39, 0x38, 0x37,
+        0x31, 0x38, 0x64, 0x36, 0x37, 0x65, 0x32, 0x62, 0x38, 0x38, 0x33, 0x61, 0x36, 0x36, 0x38,
+        0x64, 0x37, 0x37,
+    ];
+
+    #[test]
+    fn test_pattern_req_bytes() {
+        let v = PatternReader::new_le()
+            .add_padding(2)
+            .add_u64()
+            .add_u64()
+            .add_i16()
+            .add_padding(6)
+            .pattern_required_bytes();
+        assert_eq!(v, 26);
+    }
+
+    #[test]
+    fn test_read_pattern() {
+        let stream = std::io::Cursor::new(DATA);
+        let v = PatternReader::new_le()
+            .add_u64()
+            .add_u64()
+            .add_u64()
+            .read_pattern(stream)
+            .unwrap();
+        assert_eq!(
+            v,
+            vec![
+                AnyInt::U64(0x69735f78616d2f00),
+                AnyInt::U64(0x5545573722e657a),
+                AnyInt::U64(0x4b5063eebaa90100)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_struct() {
+        let stream = std::io::Cursor::new(DATA);
+        let v = StructReader::new_le()
+            .add_u64_field("test1")
+            .add_u64_field("test2")
+            .add_u64_field("test3")
+            .read(stream)
+            .unwrap();
+
+        assert_eq!(
+            TryInto::<u64>::try_into(v.get("test1").unwrap()).unwrap(),
+            0x69735f78616d2f00
+        );
+
+        assert_eq!(
+            TryInto::<u64>::try_into(&v["test2"]).unwrap(),
+            0x5545573722e657a
+        );
+
+        assert_eq!(
+            TryInto::<u64>::try_into(v.get("test3").unwrap()).unwrap(),
+            0x4b5063eebaa90100
+        );
+    }
+
+    #[test]
+    fn test_read_floats() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0x00, 0x3E]);
+        data.extend_from_slice(&1.25f32.to_le_bytes());
+        data.extend_from_slice(&(-3.5f64).to_le_bytes());
+        data.push(0x07);
+
+        let mut reader = PatternReader::new_le();
+        reader.add_f16().add_f32().add_f64().add_u8();
+        assert_eq!(reader.pattern_required_bytes(), 15);
+        let v = reader.read_pattern(std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(
+            v,
+            vec![
+                AnyValue::F16(1.5),
+                AnyValue::F32(1.25),
+                AnyValue::F64(-3.5),
+                AnyValue::Int(AnyInt::U8(7))
+            ]
+        );
+
+        let v = StructReader::new_be()
+            .add_f32_field("a")
+            .add_f64_field("b")
+            .read(std::io::Cursor::new([
+                0x3F, 0x80, 0x00, 0x00, 0x40, 0x09, 0x21, 0xFB, 0x54, 0x44, 0x2D, 0x18,
+            ]))
+            .unwrap();
+        assert_eq!(f32::try_from(&v["a"]).unwrap(), 1.0);
+        assert_eq!(f64::try_from(&v["b"]).unwrap(), std::f64::consts::PI);
+        assert!(u32::try_from(&v["a"]).is_err());
+    }
+
+    #[test]
+    fn test_read_odd_widths() {
+        let data = [
+            0x12, 0x34, 0x56, // u24
+            0xFE, 0xFF, 0xFF, // i24
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x80, // u48
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // i48
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x80, // u128
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0x80, // i128
+        ];
+        let mut reader = PatternReader::new_le();
+        reader
+            .add_u24()
+            .add_i24()
+            .add_u48()
+            .add_i48()
+            .add_u128()
+            .add_i128();
+        assert_eq!(reader.pattern_required_bytes(), data.len() as u64);
+        let v = reader.read_pattern(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(
+            v,
+            vec![
+                AnyInt::U32(0x56_3412),
+                AnyInt::I32(-2),
+                AnyInt::U48(0x8000_0000_0001),
+                AnyInt::I48(-1),
+                AnyInt::U128((1 << 127) | 1),
+                AnyInt::I128(i128::MIN + (1 << 120) - 1),
+            ]
+        );
+
+        let v = StructReader::new_be()
+            .add_i24_field("a")
+            .add_i48_field("b")
+            .read(std::io::Cursor::new([
+                0x80, 0x00, 0x00, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ]))
+            .unwrap();
+        assert_eq!(v["a"], AnyInt::I32(-0x80_0000));
+        assert_eq!(v["b"], AnyInt::I48((1 << 47) - 1));
+    }
+
+    #[test]
+    fn test_from_format() {
+        let v = PatternReader::new_le()
+            .add_padding(1)
+            .add_u16()
+            .add_u16()
+            .add_u32()
+            .add_padding(7)
+            .add_u64()
+            .read_pattern(std::io::Cursor::new(DATA))
+            .unwrap();
+        let from_fmt = PatternReader::from_format("<x2HI 7x Q").unwrap();
+        assert_eq!(from_fmt.pattern_required_bytes(), 24);
+        assert_eq!(from_fmt.endianness(), Endianness::LittleEndian);
+        assert_eq!(
+            from_fmt.read_pattern(std::io::Cursor::new(DATA)).unwrap(),
+            v
+        );
+        assert_eq!(
+            read_format(std::io::Cursor::new(DATA), "<xHHI7xQ").unwrap(),
+            v
+        );
+
+        let v = read_format(std::io::Cursor::new([0x3C, 0x00, 0xFF, 0x01]), ">eb?").unwrap();
+        assert_eq!(
+            v,
+            vec![
+                AnyValue::F16(1.0),
+                AnyValue::Int(AnyInt::I8(-1)),
+                AnyValue::Int(AnyInt::Bool(true))
+            ]
+        );
+        assert_eq!(parse_format("0H0x").unwrap().1.len(), 0);
+    }
+
+    #[test]
+    fn test_from_format_errors() {
+        assert!(matches!(
+            PatternReader::from_format("HHz"),
+            Err(StreamError::InvalidChar('z', 2))
+        ));
+        assert!(matches!(
+            parse_format("H<H"),
+            Err(StreamError::InvalidChar('<', 1))
+        ));
+        assert!(matches!(
+            parse_format("2 H"),
+            Err(StreamError::InvalidChar(' ', 1))
+        ));
+        assert!(matches!(
+            parse_format("HH3"),
+            Err(StreamError::InvalidPattern(_))
+        ));
+        assert!(matches!(
+            parse_format("99999999999999999999999H"),
+            Err(StreamError::InvalidPattern(_))
+        ));
     }
 
-    /// reuturns the results as a BTreeMap
-    /// and consumes the StructReader
-    pub fn into_inner(self) -> HashMap<String, AnyInt> {
-        self.results
+    #[test]
+    fn test_format_repeat_limit() {
+        assert_eq!(parse_format("4096B").unwrap().1.len(), MAX_FORMAT_REPEAT);
+        assert!(matches!(
+            parse_format("<1000000000H"),
+            Err(StreamError::InvalidPattern(msg)) if msg == "repeat count 1000000000 exceeds 4096"
+        ));
+        // padding and buffers are single tokens whatever their length
+        assert_eq!(parse_format("100000x100000s").unwrap().1.len(), 2);
     }
 
-    pub fn get_inner_pattern(&self) -> &PatternReader<Ord> {
-        &self.fields
+    #[test]
+    fn test_read_arrays() {
+        let mut reader = PatternReader::new_le();
+        reader.add_u8().add_array(3, PatternReaderTokens::U16);
+        assert_eq!(reader.pattern_required_bytes(), 7);
+        let v = reader.read_pattern(std::io::Cursor::new(DATA)).unwrap();
+        assert_eq!(
+            v,
+            vec![
+                AnyValue::Int(AnyInt::U8(0x00)),
+                AnyValue::List(vec![
+                    AnyValue::Int(AnyInt::U16(0x6D2F)),
+                    AnyValue::Int(AnyInt::U16(0x7861)),
+                    AnyValue::Int(AnyInt::U16(0x735F)),
+                ])
+            ]
+        );
+
+        let entry = StructReader::new_le()
+            .add_u8_field("kind")
+            .add_padding(1)
+            .add_u16_field("len");
+        let v = StructReader::new_le()
+            .add_u16_field("count")
+            .add_array_field("magic", 2, PatternReaderTokens::U8)
+            .add_counted_array("entries", "count", entry)
+            .read(std::io::Cursor::new([
+                0x02, 0x00, 0x7F, 0x45, 0x01, 0xFF, 0x10, 0x00, 0x02, 0xFF, 0x20, 0x00, 0xAA,
+            ]))
+            .unwrap();
+        assert_eq!(
+            v["magic"],
+            AnyValue::List(vec![AnyInt::U8(0x7F).into(), AnyInt::U8(0x45).into()])
+        );
+        let entries = v["entries"].as_list().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[1],
+            AnyValue::Struct(vec![
+                ("kind".into(), AnyInt::U8(2).into()),
+                ("len".into(), AnyInt::U16(0x20).into()),
+            ])
+        );
     }
 
-    pub fn results(&self) -> &HashMap<String, AnyInt> {
-        &self.results
+    #[test]
+    fn test_read_counted_array_errors() {
+        let v = StructReader::new_le()
+            .add_u8_field("count")
+            .add_counted_array(
+                "entries",
+                "count",
+                StructReader::new_le().add_u32_field("x"),
+            )
+            .read(std::io::Cursor::new([0x02, 0x01, 0x00, 0x00, 0x00]));
+        assert!(v.is_err());
+
+        let v = StructReader::new_le()
+            .add_f32_field("count")
+            .add_counted_array("entries", "count", StructReader::new_le().add_u8_field("x"))
+            .read(std::io::Cursor::new([0x00; 8]));
+        assert!(matches!(v, Err(StreamError::InvalidPattern(_))));
     }
 
-    pub fn into_vec(self) -> Vec<(String, AnyInt)> {
-        self.results.into_iter().collect()
+    #[test]
+    fn test_counted_array_empty_elements() {
+        let reader = || {
+            StructReader::new_le()
+                .add_u32_field("count")
+                .add_counted_array("entries", "count", StructReader::new_le())
+        };
+        let v = reader()
+            .read(std::io::Cursor::new(3u32.to_le_bytes()))
+            .unwrap();
+        assert_eq!(v["entries"].as_list().unwrap().len(), 3);
+
+        let r = reader().read(std::io::Cursor::new(u32::MAX.to_le_bytes()));
+        assert_eq!(
+            invalid_pattern(r),
+            "4294967295 elements that don't read any bytes exceed 4096"
+        );
+
+        // elements that read bytes are bounded by the input
+        let r = StructReader::new_le()
+            .add_u32_field("count")
+            .add_counted_array("entries", "count", StructReader::new_le().add_u8_field("x"))
+            .read(std::io::Cursor::new([0xFF, 0xFF, 0xFF, 0xFF, 0x01]));
+        assert!(matches!(r, Err(StreamError::IOError(_))));
     }
-}
 
-impl<Ord: byteorder::ByteOrder> std::ops::Index<&str> for StructReader<Ord> {
-    type Output = AnyInt;
-    /// Warning: panics if the field is not found
-    fn index(&self, name: &str) -> &Self::Output {
-        self.results.get(name).unwrap()
+    #[test]
+    fn test_counted_array_unknown_field() {
+        let r = StructReader::new_le()
+            .add_counted_array("entries", "count", StructReader::new_le().add_u8_field("x"))
+            .add_u8_field("count")
+            .read(std::io::Cursor::new([0x01, 0x02]));
+        assert_eq!(invalid_pattern(r), "field count wasn't read");
     }
-}
 
-impl<Ord: byteorder::ByteOrder> Default for StructReader<Ord> {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_field_paths() {
+        let header = StructReader::new_le()
+            .add_u8_field("class")
+            .add_u8_field("count");
+        // the branch and the sub struct refer to the fields around them
+        let wide = StructReader::new_le()
+            .add_u8_field("len")
+            .add_counted_bytes_field("wide", "header.count");
+        let v = StructReader::new_le()
+            .add_struct_field("header", header)
+            .add_counted_bytes_field("data", "header.count")
+            .add_if("header.class", 2u8, wide, None)
+            .add_struct_field(
+                "tail",
+                StructReader::new_le().add_counted_str_field("name", "len"),
+            )
+            .read(std::io::Cursor::new([
+                2, 2, 0xAA, 0xBB, 1, 0xCC, 0xDD, b'h',
+            ]))
+            .unwrap();
+        assert_eq!(v["data"], AnyValue::Bytes(vec![0xAA, 0xBB]));
+        assert_eq!(v["wide"], AnyValue::Bytes(vec![0xCC, 0xDD]));
+        assert_eq!(v["tail.name"], AnyValue::Str("h".into()));
+
+        let r = StructReader::new_le()
+            .add_struct_field("header", StructReader::new_le().add_u8_field("count"))
+            .add_counted_bytes_field("data", "header.missing")
+            .read(std::io::Cursor::new([0x01, 0x02]));
+        assert_eq!(invalid_pattern(r), "field header.missing wasn't read");
     }
-}
 
-impl<Ord: byteorder::ByteOrder> Default for PatternReader<Ord> {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_read_nested_struct() {
+        let ident = StructReader::new_le()
+            .add_u32_field("ei_mag")
+            .add_u8_field("ei_class")
+            .add_u8_field("ei_data");
+        let entry = StructReader::new_le().add_u8_field("kind");
+        let v = StructReader::new_le()
+            .add_struct_field("e_ident", ident)
+            .add_u16_field("e_type")
+            .add_u8_field("count")
+            .add_counted_array("entries", "count", entry)
+            .read(std::io::Cursor::new([
+                0x7F, 0x45, 0x4C, 0x46, 0x02, 0x01, 0x03, 0x00, 0x02, 0xAA, 0xBB,
+            ]))
+            .unwrap();
+
+        assert_eq!(v["e_ident.ei_class"], AnyInt::U8(2));
+        assert_eq!(
+            v.get("e_ident.ei_mag"),
+            Some(AnyInt::U32(0x464C457F).into())
+        );
+        assert_eq!(v["entries.1.kind"], AnyInt::U8(0xBB));
+        assert_eq!(v.get("e_ident.missing"), None);
+        assert_eq!(v.get("entries.2"), None);
+
+        let names: Vec<_> = v
+            .to_value()
+            .as_struct()
+            .unwrap()
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect();
+        assert_eq!(names, ["e_ident", "e_type", "count", "entries"]);
+        let ident_names: Vec<_> = v["e_ident"]
+            .as_struct()
+            .unwrap()
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect();
+        assert_eq!(ident_names, ["ei_mag", "ei_class", "ei_data"]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::streams::AnyInt;
+    #[test]
+    fn test_struct_order_and_layout() {
+        let v = StructReader::new_le()
+            .add_u8_field("z")
+            .add_padding(3)
+            .add_u32_field("a")
+            .add_array_field("m", 2, PatternReaderTokens::U16)
+            .add_u8_field("b")
+            .read(std::io::Cursor::new(DATA))
+            .unwrap();
 
-    const DATA: [u8; 168] = [
-        0x00, 0x2F, 0x6D, 0x61, 0x78, 0x5F, 0x73, 0x69, 0x7A, 0x65, 0x2E, 0x72, 0x73, 0x55, 0x54,
-        0x05, 0x00, 0x01, 0xA9, 0xBA, 0xEE, 0x63, 0x50, 0x4B, 0x01, 0x02, 0x00, 0x00, 0x0A, 0x00,
-        0x00, 0x00, 0x08, 0x00, 0xC8, 0x7A, 0x50, 0x56, 0xDB, 0x87, 0xEE, 0xBA, 0x1A, 0x02, 0x00,
-        0x00, 0x8C, 0x09, 0x00, 0x00, 0x1D, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0xF5, 0xEC, 0x00, 0x00, 0x70, 0x6F, 0x73, 0x74, 0x63, 0x61, 0x72,
-        0x64, 0x2D, 0x6D, 0x61, 0x69, 0x6E, 0x2F, 0x74, 0x65, 0x73, 0x74, 0x73, 0x2F, 0x73, 0x63,
-        0x68, 0x65, 0x6D, 0x61, 0x2E, 0x72, 0x73, 0x55, 0x54, 0x05, 0x00, 0x01, 0xA9, 0xBA, 0xEE,
-        0x63, 0x50, 0x4B, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x2C, 0x00, 0x82, 0x0E,
-        0x00, 0x00, 0x53, 0xEF, 0x00, 0x00, 0x28, 0x00, 0x61, 0x31, 0x63, 0x33, 0x61, 0x66, 0x34,
-        0x37, 0x61, 0x65, 0x63, 0x34, 0x33, 0x33, 0x61, 0x34, 0x30, 0x30, 0x62, 0x39, 0x38, 0x37,
-        0x31, 0x38, 0x64, 0x36, 0x37, 0x65, 0x32, 0x62, 0x38, 0x38, 0x33, 0x61, 0x36, 0x36, 0x38,
-        0x64, 0x37, 0x37,
-    ];
+        let layout: Vec<_> = v.iter().map(|(n, o, s, _)| (n, o, s)).collect();
+        assert_eq!(
+            layout,
+            [("z", 0, 1), ("a", 4, 4), ("m", 8, 4), ("b", 12, 1)]
+        );
+        assert_eq!(v.iter().nth(1).unwrap().3, &AnyInt::U32(0x69735F78));
+
+        let dbg = format!("{:?}", v);
+        let pos: Vec<_> = ["\"z\"", "\"a\"", "\"m\"", "\"b\""]
+            .iter()
+            .map(|n| dbg.find(n).unwrap())
+            .collect();
+        assert!(pos.windows(2).all(|w| w[0] < w[1]));
+
+        let names: Vec<_> = v.into_vec().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, ["z", "a", "m", "b"]);
+    }
+
+    fn invalid_pattern(r: StreamResult<StructReader>) -> String {
+        match r {
+            Err(StreamError::InvalidPattern(msg)) => msg,
+            r => panic!(
+                "expected an invalid pattern, got {:?}",
+                r.map(|r| r.to_value())
+            ),
+        }
+    }
 
     #[test]
-    fn test_pattern_req_bytes() {
-        let v = PatternReader::new_le()
-            .add_padding(2)
-            .add_u64()
-            .add_u64()
-            .add_i16()
-            .add_padding(6)
-            .pattern_required_bytes();
-        assert_eq!(v, 26);
+    fn test_struct_duplicate_field() {
+        let reader = StructReader::new_le()
+            .add_u8_field("a")
+            .add_u16_field("b")
+            .add_u32_field("a");
+        assert_eq!(
+            invalid_pattern(reader.read(std::io::Cursor::new([0; 7]))),
+            "duplicate field name a"
+        );
     }
 
     #[test]
-    fn test_read_pattern() {
-        let stream = std::io::Cursor::new(DATA);
-        let v = PatternReader::new_le()
-            .add_u64()
-            .add_u64()
-            .add_u64()
-            .read_pattern(stream)
+    fn test_struct_invalid_field_name() {
+        let reader = StructReader::new_le()
+            .add_struct_field("header", StructReader::new_le().add_u8_field("a.b"));
+        assert_eq!(
+            invalid_pattern(reader.read(std::io::Cursor::new([0]))),
+            "invalid field name \"a.b\""
+        );
+    }
+
+    #[test]
+    fn test_schema_parse() {
+        let schema = StructReader::new_le()
+            .add_u8_field("kind")
+            .add_padding(1)
+            .add_u16_field("len")
+            .add_expr_field("odd", 1, |v| v.to_u8_checked().unwrap() % 2 == 1)
+            .into_schema();
+        assert_eq!(schema.required_bytes(), 5);
+        assert_eq!(
+            schema.field_names().collect::<Vec<_>>(),
+            ["kind", "len", "odd"]
+        );
+
+        let records: Vec<Record> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let schema = &schema;
+                    s.spawn(move || {
+                        schema
+                            .parse(std::io::Cursor::new([i, 0xFF, i, 0x00, i]))
+                            .unwrap()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record["kind"], AnyInt::U8(i as u8));
+            assert_eq!(record["len"], AnyInt::U16(i as u16));
+            assert_eq!(record["odd"], AnyInt::Bool(i % 2 == 1));
+            let layout: Vec<_> = record.iter().map(|(n, o, s, _)| (n, o, s)).collect();
+            assert_eq!(layout, [("kind", 0, 1), ("len", 2, 2), ("odd", 4, 1)]);
+        }
+
+        let copy = schema.clone();
+        let a = copy.parse(std::io::Cursor::new([1, 0, 2, 0, 3])).unwrap();
+        let b = schema.parse(std::io::Cursor::new([1, 0, 2, 0, 3])).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(
+            format!("{:?}", a),
+            "{\"kind\": Int(U8(1)), \"len\": Int(U16(2)), \"odd\": Int(Bool(true))}"
+        );
+    }
+
+    fn elf_like() -> StructReader {
+        StructReader::new_le()
+            .add_u8_field("class")
+            .add_if(
+                "class",
+                2u8,
+                StructReader::new_le()
+                    .add_u64_field("entry")
+                    .add_u64_field("phoff"),
+                Some(
+                    StructReader::new_le()
+                        .add_u32_field("entry")
+                        .add_u32_field("phoff"),
+                ),
+            )
+            .add_u16_field("flags")
+    }
+
+    #[test]
+    fn test_read_if() {
+        let reader = elf_like();
+        assert_eq!(reader.required_bytes(), 11);
+
+        let mut data64 = vec![2];
+        data64.extend_from_slice(&0x1000u64.to_le_bytes());
+        data64.extend_from_slice(&0x40u64.to_le_bytes());
+        data64.extend_from_slice(&[0x01, 0x00]);
+        let v = reader.read(std::io::Cursor::new(data64)).unwrap();
+        assert_eq!(v["entry"], AnyInt::U64(0x1000));
+        assert_eq!(v["phoff"], AnyInt::U64(0x40));
+        assert_eq!(v["flags"], AnyInt::U16(1));
+        let layout: Vec<_> = v.iter().map(|(n, o, s, _)| (n, o, s)).collect();
+        assert_eq!(
+            layout,
+            [
+                ("class", 0, 1),
+                ("entry", 1, 8),
+                ("phoff", 9, 8),
+                ("flags", 17, 2)
+            ]
+        );
+
+        let v = elf_like()
+            .read(std::io::Cursor::new([
+                1, 0x00, 0x10, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x02, 0x00,
+            ]))
             .unwrap();
+        assert_eq!(v["entry"], AnyInt::U32(0x1000));
+        assert_eq!(v["phoff"], AnyInt::U32(0x34));
+        assert_eq!(v["flags"], AnyInt::U16(2));
+        assert_eq!(v.iter().count(), 4);
+
+        // conditionals in sub structs are merged into the sub struct
+        let v = StructReader::new_le()
+            .add_struct_field("header", elf_like())
+            .read(std::io::Cursor::new([
+                1, 0x00, 0x10, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x02, 0x00,
+            ]))
+            .unwrap();
+        assert_eq!(v["header.phoff"], AnyInt::U32(0x34));
+        assert_eq!(v["header"].as_struct().unwrap().len(), 4);
+
+        // a missing else branch reads nothing
+        let v = StructReader::new_le()
+            .add_bool_field("has_crc")
+            .add_if(
+                "has_crc",
+                true,
+                StructReader::new_le().add_u32_field("crc"),
+                None,
+            )
+            .add_u8_field("end")
+            .read(std::io::Cursor::new([0x00, 0x07]))
+            .unwrap();
+        assert_eq!(v.get("crc"), None);
+        assert_eq!(v["end"], AnyInt::U8(7));
+    }
+
+    #[test]
+    fn test_read_switch() {
+        let reader = || {
+            StructReader::new_be()
+                .add_u8_field("tag")
+                .add_switch_field(
+                    "body",
+                    "tag",
+                    vec![
+                        (AnyInt::U8(1), StructReader::new_be().add_u16_field("short")),
+                        (AnyInt::U8(2), StructReader::new_be().add_u32_field("long")),
+                    ],
+                    Some(StructReader::new_be().add_u8_field("raw")),
+                )
+                .add_u8_field("end")
+        };
+        assert_eq!(reader().required_bytes(), 3);
+
+        let v = reader()
+            .read(std::io::Cursor::new([2, 0, 0, 1, 0, 9]))
+            .unwrap();
+        assert_eq!(v["body.long"], AnyInt::U32(0x100));
+        assert_eq!(v["end"], AnyInt::U8(9));
+
+        let v = reader().read(std::io::Cursor::new([7, 5, 9])).unwrap();
+        assert_eq!(v["body.raw"], AnyInt::U8(5));
+
+        let v = StructReader::new_be()
+            .add_u8_field("tag")
+            .add_switch_field(
+                "body",
+                "tag",
+                vec![(AnyInt::U16(1), StructReader::new_be().add_u8_field("x"))],
+                None,
+            )
+            .read(std::io::Cursor::new([1, 2]))
+            .unwrap();
+        assert_eq!(v["body.x"], AnyInt::U8(2));
+
+        let v = StructReader::new_be()
+            .add_u8_field("tag")
+            .add_switch_field("body", "tag", vec![], None)
+            .read(std::io::Cursor::new([1, 2]));
+        assert!(matches!(v, Err(StreamError::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_if_duplicate_field() {
+        let reader = StructReader::new_le()
+            .add_u8_field("class")
+            .add_u8_field("entry")
+            .add_if(
+                "class",
+                1u8,
+                StructReader::new_le().add_u8_field("entry"),
+                None,
+            );
+        assert_eq!(
+            invalid_pattern(reader.read(std::io::Cursor::new([1, 2, 3]))),
+            "duplicate field name entry"
+        );
+    }
+
+    #[test]
+    fn test_field_after_if_duplicate() {
+        let reader = elf_like().add_u8_field("entry");
+        assert_eq!(
+            invalid_pattern(reader.read(std::io::Cursor::new([0; 20]))),
+            "duplicate field name entry"
+        );
+    }
+
+    fn endian_switched() -> StructReader {
+        StructReader::new_le()
+            .add_u8_field("data")
+            .add_u16_field("magic")
+            .add_endian_switch(
+                "data",
+                &[
+                    (AnyInt::U8(1), Endianness::LittleEndian),
+                    (AnyInt::U8(2), Endianness::BigEndian),
+                ],
+            )
+            .add_u16_field("kind")
+            .add_struct_field("inner", StructReader::new_le().add_u32_field("x"))
+            .add_endian_field("fixed", Endianness::LittleEndian, PatternReaderTokens::U16)
+    }
+
+    #[test]
+    fn test_read_endian_switch() {
+        let data = [
+            0x02, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x05, 0x00,
+        ];
+        let reader = endian_switched().read(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(reader["magic"], AnyInt::U16(1));
+        assert_eq!(reader["kind"], AnyInt::U16(3));
+        assert_eq!(reader["inner.x"], AnyInt::U32(4));
+        assert_eq!(reader["fixed"], AnyInt::U16(5));
+        assert_eq!(endian_switched().required_bytes(), 11);
+
+        let data = [
+            0x01, 0x01, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x05, 0x00,
+        ];
+        let reader = endian_switched().read(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(reader["kind"], AnyInt::U16(3));
+        assert_eq!(reader["inner.x"], AnyInt::U32(4));
+
+        let data = [0x03; 11];
+        assert!(matches!(
+            endian_switched().read(std::io::Cursor::new(data)),
+            Err(StreamError::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_runtime_endianness() {
+        let mut pattern = PatternReader::new(Endianness::BigEndian);
+        pattern
+            .add_u16()
+            .add_endian(Endianness::LittleEndian, PatternReaderTokens::U16);
+        assert_eq!(
+            pattern
+                .read_pattern(std::io::Cursor::new([0x00, 0x01, 0x01, 0x00]))
+                .unwrap(),
+            vec![AnyValue::Int(AnyInt::U16(1)), AnyValue::Int(AnyInt::U16(1))]
+        );
+        assert_eq!(
+            PatternReader::default().endianness(),
+            Endianness::LittleEndian
+        );
+    }
+
+    fn strings() -> StructReader {
+        StructReader::new_le()
+            .add_u8_field("len")
+            .add_lpstr_field("lpstr", LPWidth::LP16)
+            .add_cstr_field("cstr", 8)
+            .add_str_field("name", 6)
+            .add_counted_bytes_field("data", "len")
+            .add_lpbuf_field("lpbuf", LPWidth::LP8)
+    }
+
+    const STRINGS: [u8; 19] = [
+        0x03, 0x02, 0x00, b'h', b'i', b'a', b'b', 0x00, b'.', b't', b'e', b'x', b't', 0x00, 0x01,
+        0x02, 0x03, 0x01, 0xFF,
+    ];
+
+    #[test]
+    fn test_read_huge_lengths() {
+        let is_eof = |r: StreamResult<StructReader>| matches!(r, Err(StreamError::IOError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof);
+        // lengths read from the stream don't size the buffer before the data is read
+        assert!(is_eof(
+            StructReader::new_le()
+                .add_u64_field("n")
+                .add_counted_bytes_field("b", "n")
+                .read(std::io::Cursor::new([0xFF; 8]))
+        ));
+        assert!(is_eof(
+            StructReader::new_le()
+                .add_u32_field("n")
+                .add_counted_str_field("s", "n")
+                .read(std::io::Cursor::new([0xFF, 0xFF, 0xFF, 0xFF, b'a']))
+        ));
+        assert!(is_eof(
+            StructReader::new_le()
+                .add_lpbuf_field("b", LPWidth::LP32)
+                .read(std::io::Cursor::new([0xFF, 0xFF, 0xFF, 0xFF, 1, 2]))
+        ));
+    }
+
+    #[test]
+    fn test_read_strings() {
+        assert_eq!(strings().required_bytes(), 11);
+        let reader = strings().read(std::io::Cursor::new(STRINGS)).unwrap();
+        assert_eq!(reader["lpstr"].as_str(), Some("hi"));
+        assert_eq!(reader["cstr"].as_str(), Some("ab"));
+        assert_eq!(reader["name"].as_str(), Some(".text"));
+        assert_eq!(reader["data"], AnyValue::Bytes(vec![1, 2, 3]));
+        assert_eq!(reader["lpbuf"], AnyValue::Bytes(vec![0xFF]));
+
+        let mut data = STRINGS;
+        data[8] = 0xFF;
+        assert!(strings().read(std::io::Cursor::new(data)).is_err());
+        assert!(strings()
+            .read(std::io::Cursor::new(&STRINGS[..16]))
+            .is_err());
+
+        let v = read_format(std::io::Cursor::new(b"abcd"), "<3sB").unwrap();
         assert_eq!(
             v,
             vec![
-                AnyInt::U64(0x69735f78616d2f00),
-                AnyInt::U64(0x5545573722e657a),
-                AnyInt::U64(0x4b5063eebaa90100)
+                AnyValue::Bytes(b"abc".to_vec()),
+                AnyValue::Int(AnyInt::U8(b'd'))
             ]
         );
+        assert_eq!(parse_format("0s").unwrap().1.len(), 1);
+    }
+
+    fn bit_fields() -> StructReader {
+        StructReader::new_be()
+            .add_bits_field("data_offset", 4)
+            .add_bits_field("reserved", 3)
+            .add_bits_field("flags", 9)
+            .add_u16_field("window")
+            .set_bit_order(BitOrder::LsbFirst)
+            .add_bits_field("seconds", 5)
+            .add_bits_field("minutes", 6)
+            .add_bits_field("hours", 5)
+            .add_bits_field("odd", 3)
+            .add_u8_field("end")
     }
 
     #[test]
-    fn test_read_struct() {
-        let stream = std::io::Cursor::new(DATA);
-        let v = StructReader::new_le()
-            .add_u64_field("test1")
-            .add_u64_field("test2")
-            .add_u64_field("test3")
-            .read(stream)
+    fn test_read_bits() {
+        let data = [0x50, 0x12, 0x20, 0x00, 0xAF, 0x6D, 0x05, 0x07];
+        assert_eq!(bit_fields().required_bytes(), 8);
+        let reader = bit_fields().read(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(reader["data_offset"], AnyInt::U8(5));
+        assert_eq!(reader["reserved"], AnyInt::U8(0));
+        assert_eq!(reader["flags"], AnyInt::U16(0x12));
+        assert_eq!(reader["window"], AnyInt::U16(0x2000));
+        assert_eq!(reader["seconds"], AnyInt::U8(15));
+        assert_eq!(reader["minutes"], AnyInt::U8(45));
+        assert_eq!(reader["hours"], AnyInt::U8(13));
+        assert_eq!(reader["odd"], AnyInt::U8(5));
+        assert_eq!(reader["end"], AnyInt::U8(7));
+
+        let layout: Vec<_> = reader.iter().map(|(n, o, s, _)| (n, o, s)).collect();
+        assert_eq!(layout[2], ("flags", 0, 2));
+        assert_eq!(layout[6], ("hours", 4, 3));
+        assert_eq!(layout[7], ("odd", 4, 3));
+
+        let mut pattern = PatternReader::new_le();
+        pattern.add_bits(BitOrder::MsbFirst, 12).add_u8();
+        assert_eq!(pattern.pattern_required_bytes(), 3);
+        assert_eq!(
+            pattern
+                .read_pattern(std::io::Cursor::new([0xAB, 0xC0, 0x01]))
+                .unwrap(),
+            vec![
+                AnyValue::Int(AnyInt::U16(0xABC)),
+                AnyValue::Int(AnyInt::U8(1))
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "bits fields must be 1 to 64 bits wide")]
+    fn test_bits_field_too_wide() {
+        let _ = StructReader::new_le().add_bits_field("x", 65);
+    }
+
+    #[test]
+    fn test_checked_bits_field() {
+        let r = StructReader::new_le()
+            .add_range_field(
+                "x",
+                PatternReaderTokens::Bits(BitOrder::MsbFirst, 3),
+                0u8..=5,
+            )
+            .read(std::io::Cursor::new([0x00]));
+        assert_eq!(invalid_pattern(r), "checks can't wrap bits fields");
+    }
+
+    #[test]
+    fn test_enum_field_not_int() {
+        let r = StructReader::new_le()
+            .add_enum_field(
+                "x",
+                PatternReaderTokens::Str(ArrayLen::Fixed(4)),
+                &[(1u8, "one")],
+                UnknownValue::KeepRaw,
+            )
+            .read(std::io::Cursor::new(*b"one\0"));
+        assert_eq!(
+            invalid_pattern(r),
+            "enums must wrap an integer token, not Str(Fixed(4))"
+        );
+    }
+
+    #[test]
+    fn test_enum_fixed_endianness() {
+        let reader = StructReader::new_le()
+            .add_enum_field(
+                "kind",
+                PatternReaderTokens::Endian(
+                    Endianness::BigEndian,
+                    Box::new(PatternReaderTokens::U16),
+                ),
+                &[(0x0102u16, "known")],
+                UnknownValue::Error,
+            )
+            .read(std::io::Cursor::new([0x01, 0x02]))
             .unwrap();
+        assert_eq!(reader["kind"].enum_name(), Some("known"));
+    }
 
+    #[test]
+    fn test_enum_bits_field() {
+        let r = StructReader::new_le()
+            .add_enum_field(
+                "x",
+                PatternReaderTokens::Bits(BitOrder::MsbFirst, 3),
+                &[(1u8, "one")],
+                UnknownValue::KeepRaw,
+            )
+            .read(std::io::Cursor::new([0x00]));
         assert_eq!(
-            TryInto::<u64>::try_into(v.get("test1").unwrap()).unwrap(),
-            0x69735f78616d2f00
+            invalid_pattern(r),
+            "enums must wrap an integer token, not Bits(MsbFirst, 3)"
         );
+    }
 
+    fn checked() -> StructReader {
+        StructReader::new_le()
+            .add_magic_field("magic", PatternReaderTokens::U32, 0x464C457Fu32)
+            .add_range_field("class", PatternReaderTokens::U8, 1u8..=2)
+            .add_predicate_field("flags", PatternReaderTokens::U16, |v| {
+                u16::try_from(v).is_ok_and(|v| v & 0x8000 == 0)
+            })
+            .add_struct_field(
+                "name",
+                StructReader::new_le().add_u8_field("len").add_magic_field(
+                    "tag",
+                    PatternReaderTokens::Str(ArrayLen::Fixed(2)),
+                    "ok",
+                ),
+            )
+    }
+
+    fn validation_error(data: &[u8]) -> (String, u64, AnyValue) {
+        match checked().read(std::io::Cursor::new(data)) {
+            Err(StreamError::Validation {
+                field,
+                offset,
+                value,
+                ..
+            }) => (field, offset, *value),
+            r => panic!("expected a validation error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_read_checks() {
+        let data = [0x7F, b'E', b'L', b'F', 0x02, 0x01, 0x00, 0x05, b'o', b'k'];
+        assert_eq!(checked().required_bytes(), 10);
+        let reader = checked().read(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(reader["magic"], AnyInt::U32(0x464C457F));
+        assert_eq!(reader["class"], AnyInt::U8(2));
+        assert_eq!(reader["name.tag"].as_str(), Some("ok"));
+
+        let mut bad = data;
+        bad[0] = 0;
         assert_eq!(
-            TryInto::<u64>::try_into(v["test2"]).unwrap(),
-            0x5545573722e657a
+            validation_error(&bad),
+            ("magic".into(), 0, AnyInt::U32(0x464C4500).into())
         );
+        let mut bad = data;
+        bad[4] = 3;
+        assert_eq!(
+            validation_error(&bad),
+            ("class".into(), 4, AnyInt::U8(3).into())
+        );
+        let mut bad = data;
+        bad[6] = 0x80;
+        assert_eq!(
+            validation_error(&bad),
+            ("flags".into(), 5, AnyInt::U16(0x8001).into())
+        );
+        let mut bad = data;
+        bad[9] = b'K';
+        assert_eq!(validation_error(&bad), ("name.tag".into(), 8, "oK".into()));
+
+        let mut pattern = PatternReader::new_le();
+        pattern
+            .add_u8()
+            .add_check(
+                PatternReaderTokens::U8,
+                Constraint::Range(AnyInt::I8(-1), AnyInt::U8(1)),
+            )
+            .add_expr(2, |v| v == AnyInt::U16(1));
+        assert_eq!(pattern.pattern_required_bytes(), 4);
+        assert!(matches!(
+            pattern.read_pattern(std::io::Cursor::new([0, 2, 1, 0])),
+            Err(StreamError::Validation { field, offset: 1, .. }) if field == "1"
+        ));
+    }
+
+    const MACHINES: [(u16, &str); 2] = [(0x03, "x86"), (0x3E, "x86_64")];
+
+    #[test]
+    fn test_read_enums() {
+        let reader = StructReader::new_le()
+            .add_u16_enum_field("machine", &MACHINES)
+            .add_u8_enum_field("osabi", &[(0, "sysv"), (3, "linux")])
+            .add_enum_field(
+                "class",
+                PatternReaderTokens::U8,
+                &[(1u8, "32"), (2, "64")],
+                UnknownValue::Error,
+            );
+        assert_eq!(reader.required_bytes(), 4);
 
+        let reader = reader
+            .read(std::io::Cursor::new([0x3E, 0x00, 0x09, 0x02]))
+            .unwrap();
         assert_eq!(
-            TryInto::<u64>::try_into(v.get("test3").unwrap()).unwrap(),
-            0x4b5063eebaa90100
+            reader["machine"],
+            AnyValue::Enum(AnyInt::U16(0x3E), "x86_64".into())
+        );
+        assert_eq!(reader["machine"], AnyInt::U16(0x3E));
+        assert_eq!(reader["machine"].enum_name(), Some("x86_64"));
+        assert_eq!(u16::try_from(&reader["machine"]).unwrap(), 0x3E);
+        assert_eq!(reader["osabi"], AnyValue::Int(AnyInt::U8(9)));
+        assert_eq!(reader["osabi"].enum_name(), None);
+        assert_eq!(reader["class"].enum_name(), Some("64"));
+
+        let err = StructReader::new_le()
+            .add_u16_enum_field("machine", &MACHINES)
+            .add_enum_field(
+                "class",
+                PatternReaderTokens::U8,
+                &[(1u8, "32"), (2, "64")],
+                UnknownValue::Error,
+            )
+            .read(std::io::Cursor::new([0x03, 0x00, 0x05]));
+        assert!(matches!(
+            err,
+            Err(StreamError::Validation { field, offset: 2, .. }) if field == "class"
+        ));
+    }
+
+    #[test]
+    fn test_read_pattern_seek() {
+        let mut cursor = std::io::Cursor::new([0xAA, 0xBB, 0xCC, 1, 2, 3, 4]);
+        cursor.set_position(3);
+        let values = PatternReader::new_be()
+            .add_u8()
+            .add_skip(1)
+            .add_u8()
+            .add_skip(-2)
+            .add_u16()
+            .add_seek_to(0)
+            .add_u8()
+            .read_pattern_seek(&mut cursor)
+            .unwrap();
+        assert_eq!(
+            values,
+            [
+                AnyValue::Int(AnyInt::U8(1)),
+                AnyValue::Int(AnyInt::U8(3)),
+                AnyValue::Int(AnyInt::U16(0x0203)),
+                AnyValue::Int(AnyInt::U8(1)),
+            ]
+        );
+        assert_eq!(cursor.position(), 4);
+        // offsets are counted from the start of the read, which can't be skipped back over
+        assert!(PatternReader::new_be()
+            .add_skip(-1)
+            .read_pattern_seek(&mut cursor)
+            .is_err());
+
+        // aligning reads the skipped bytes and works on any stream
+        let values = PatternReader::new_be()
+            .add_u8()
+            .add_align(4)
+            .add_u8()
+            .read_pattern(&[1, 2, 3, 4, 5][..])
+            .unwrap();
+        assert_eq!(
+            values,
+            [AnyValue::Int(AnyInt::U8(1)), AnyValue::Int(AnyInt::U8(5))]
         );
+        assert!(PatternReader::new_be()
+            .add_seek_to(0)
+            .read_pattern(&[1][..])
+            .is_err());
+        assert!(matches!(
+            PatternReader::new_be().add_align(0).read_pattern(&[1][..]),
+            Err(StreamError::InvalidPattern(msg)) if msg == "can't align to 0 bytes"
+        ));
+    }
+
+    #[test]
+    fn test_read_fields_at() {
+        let data = [
+            2, 0xFF, 0xFF, 0xFF, 12, 0, 0, 0, 0x7A, 0, 0, 0, // header
+            20, 0, 1, 0, 23, 0, 2, 0, // table
+            b'a', b'b', 0, b'c', b'd', b'e', 0, // names
+        ];
+        let schema = StructReader::new_le()
+            .add_u8_field("count")
+            .add_align(4)
+            .add_u32_field("table_off")
+            .add_counted_array_at(
+                "entries",
+                "table_off",
+                "count",
+                StructReader::new_le()
+                    .add_u16_field("name_off")
+                    .add_u16_field("value")
+                    .add_field_at("name", "name_off", PatternReaderTokens::CStr(16)),
+            )
+            .add_u8_field("tail")
+            .into_schema();
+        assert_eq!(schema.required_bytes(), 6);
+
+        let mut cursor = std::io::Cursor::new(data);
+        let record = schema.parse_seek(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), 9);
+        assert_eq!(record["tail"], AnyInt::U8(0x7A));
+        let names: Vec<_> = match &record["entries"] {
+            AnyValue::List(entries) => entries
+                .iter()
+                .map(|e| match e {
+                    AnyValue::Struct(fields) => fields[2].1.clone(),
+                    e => panic!("expected a struct, got {:?}", e),
+                })
+                .collect(),
+            v => panic!("expected a list, got {:?}", v),
+        };
+        assert_eq!(names, [AnyValue::from("ab"), AnyValue::from("cde")]);
+        let layout: Vec<_> = record
+            .iter()
+            .map(|(name, offset, size, _)| (name, offset, size))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("count", 0, 1),
+                ("table_off", 4, 4),
+                ("entries", 12, 8),
+                ("tail", 8, 1)
+            ]
+        );
+
+        assert!(matches!(
+            schema.parse(std::io::Cursor::new(data)),
+            Err(StreamError::InvalidPattern(_))
+        ));
     }
 }
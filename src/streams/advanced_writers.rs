@@ -0,0 +1,1904 @@
+//! Module containing advanced writer utilities for serializing streams,
+//! these mirror the readers in [`super::advanced_readers`].
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use byteorder::WriteBytesExt;
+
+use super::advanced_readers::{
+    bit_group, check_not_bits, enum_table, grouped_size, native_endianness, parse_format, ArrayLen,
+    PatternReaderTokens, Scope, UnknownValue, ValueRef,
+};
+use super::bits::{BitOrder, BitWriter};
+use super::helpers::f32_to_f16;
+use super::read::StreamResult;
+use super::write::{write_lpbuf, write_lpstr};
+use super::{AnyInt, AnyIntKind, AnyValue, Endianness, LPWidth, StreamError};
+
+/// Write a number of elements to a stream,
+///
+/// usage of PatternWriter is to build a pattern with the provided methods
+/// and then call the write method with the stream and the values to write,
+/// the values must match the pattern in order and type, padding is written as zeroes.
+///
+/// The byte order is chosen when the pattern is created, [`PatternWriter::default`]
+/// writes little endian.
+#[derive(Clone, Debug)]
+pub struct PatternWriter {
+    pattern: Vec<PatternReaderTokens>,
+    endianness: Endianness,
+}
+
+impl PatternWriter {
+    /// Create an empty pattern, values are written with `endianness`
+    /// unless a token overrides it.
+    pub fn new(endianness: Endianness) -> Self {
+        Self {
+            pattern: Vec::new(),
+            endianness,
+        }
+    }
+
+    pub fn new_be() -> Self {
+        Self::new(Endianness::BigEndian)
+    }
+
+    pub fn new_le() -> Self {
+        Self::new(Endianness::LittleEndian)
+    }
+
+    /// The endianness values are written with, unless a token overrides it.
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    /// Build a pattern from a Python `struct` style format string,
+    /// see [`super::advanced_readers::parse_format`] for the syntax.
+    ///
+    /// # Errors
+    /// The same as [`super::advanced_readers::PatternReader::from_format`].
+    pub fn from_format(fmt: &str) -> StreamResult<Self> {
+        let (endianness, pattern) = parse_format(fmt)?;
+        Ok(Self {
+            pattern,
+            endianness: endianness.unwrap_or_else(native_endianness),
+        })
+    }
+
+    pub fn add_u8(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U8);
+        self
+    }
+
+    pub fn add_u16(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U16);
+        self
+    }
+
+    /// Add a 24 bit unsigned integer, the value must be an [`super::AnyInt::U32`] that fits.
+    pub fn add_u24(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U24);
+        self
+    }
+
+    pub fn add_u32(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U32);
+        self
+    }
+
+    pub fn add_u48(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U48);
+        self
+    }
+
+    pub fn add_u64(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U64);
+        self
+    }
+
+    pub fn add_u128(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::U128);
+        self
+    }
+
+    pub fn add_i8(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I8);
+        self
+    }
+
+    pub fn add_i16(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I16);
+        self
+    }
+
+    /// Add a 24 bit signed integer, the value must be an [`super::AnyInt::I32`] that fits.
+    pub fn add_i24(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I24);
+        self
+    }
+
+    pub fn add_i32(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I32);
+        self
+    }
+
+    pub fn add_i48(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I48);
+        self
+    }
+
+    pub fn add_i64(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I64);
+        self
+    }
+
+    pub fn add_i128(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::I128);
+        self
+    }
+
+    pub fn add_usize(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::USize);
+        self
+    }
+
+    /// Add an IEEE 754 half precision float, the value is rounded to the nearest `f16`.
+    pub fn add_f16(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F16);
+        self
+    }
+
+    pub fn add_f32(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F32);
+        self
+    }
+
+    pub fn add_f64(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::F64);
+        self
+    }
+
+    pub fn add_padding(&mut self, len: usize) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Padding(len));
+        self
+    }
+
+    /// Write zeros up to the next multiple of `n` bytes counted from the start of the write,
+    /// see [`PatternReaderTokens::Align`].
+    ///
+    /// Writing fails with [`StreamError::InvalidPattern`] if `n` is 0.
+    pub fn add_align(&mut self, n: u64) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Align(n));
+        self
+    }
+
+    pub fn add_bool(&mut self) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Bool);
+        self
+    }
+
+    /// Add a string with a length prefix of `width`, the value must be an [`AnyValue::Str`].
+    pub fn add_lpstr(&mut self, width: LPWidth) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::LPStr(width));
+        self
+    }
+
+    /// Add a byte buffer with a length prefix of `width`, the value must be an [`AnyValue::Bytes`].
+    pub fn add_lpbuf(&mut self, width: LPWidth) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::LPBuf(width));
+        self
+    }
+
+    /// Add a NUL terminated string, the value must be an [`AnyValue::Str`] without NULs
+    /// shorter than `maxlen` bytes.
+    pub fn add_cstr(&mut self, maxlen: usize) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::CStr(maxlen));
+        self
+    }
+
+    /// Add a string padded with NULs to `len` bytes, the value must be an
+    /// [`AnyValue::Str`] of at most `len` bytes.
+    pub fn add_str(&mut self, len: usize) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Str(ArrayLen::Fixed(len)));
+        self
+    }
+
+    /// Add a byte buffer, the value must be an [`AnyValue::Bytes`] of exactly `len` bytes.
+    pub fn add_bytes(&mut self, len: usize) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Bytes(ArrayLen::Fixed(len)));
+        self
+    }
+
+    /// Add the integer token `tkn` encoded with a table of names, the value can be the raw
+    /// integer, an [`AnyValue::Enum`] or the name as an [`AnyValue::Str`].
+    ///
+    /// With [`UnknownValue::Error`] writing a value that isn't in the table fails,
+    /// writing fails with [`StreamError::InvalidPattern`] if `tkn` isn't an integer
+    /// token, e.g. a string, a float or a [`PatternReaderTokens::Bits`] token.
+    pub fn add_enum<T: Into<AnyInt> + Copy>(
+        &mut self,
+        tkn: PatternReaderTokens,
+        values: &[(T, &str)],
+        unknown: UnknownValue,
+    ) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Enum(
+            Box::new(tkn),
+            enum_table(values),
+            unknown,
+        ));
+        self
+    }
+
+    /// Add an unsigned integer of `nbits` bits, consecutive bits with the same
+    /// `order` are packed together and the unused bits of the last byte are zero.
+    ///
+    /// # Panics
+    /// If `nbits` is 0 or greater than 64.
+    pub fn add_bits(&mut self, order: BitOrder, nbits: u32) -> &mut Self {
+        assert!(
+            (1..=64).contains(&nbits),
+            "bits fields must be 1 to 64 bits wide"
+        );
+        self.pattern.push(PatternReaderTokens::Bits(order, nbits));
+        self
+    }
+
+    /// Add `tkn` written with a fixed `endianness`, regardless of the endianness of the pattern.
+    pub fn add_endian(&mut self, endianness: Endianness, tkn: PatternReaderTokens) -> &mut Self {
+        self.pattern
+            .push(PatternReaderTokens::Endian(endianness, Box::new(tkn)));
+        self
+    }
+
+    /// Add an array of `len` elements of `tkn`, the value must be an [`AnyValue::List`]
+    /// with exactly `len` elements.
+    pub fn add_array(&mut self, len: usize, tkn: PatternReaderTokens) -> &mut Self {
+        self.pattern.push(PatternReaderTokens::Array(
+            ArrayLen::Fixed(len),
+            Box::new(tkn),
+        ));
+        self
+    }
+
+    /// How many bytes will be written by this pattern, arrays with a counted length
+    /// and strings and buffers of variable length are counted as empty.
+    pub fn pattern_required_bytes(&self) -> u64 {
+        grouped_size(&self.pattern, token_size) as u64
+    }
+
+    /// Write `values` to the stream according to the pattern,
+    /// `values` can be a slice of [`super::AnyInt`]s or of [`AnyValue`]s.
+    ///
+    /// # Returns
+    /// The number of bytes written.
+    ///
+    /// # Errors
+    /// This function will return an error in the following cases:
+    /// * There are fewer or more values than the pattern requires.
+    /// * A value doesn't have the type of its token.
+    /// * The stream returns an error.
+    pub fn write_pattern<S: Write, V: Clone + Into<AnyValue>>(
+        &self,
+        mut stream: S,
+        values: &[V],
+    ) -> StreamResult<u64> {
+        let values: Vec<AnyValue> = values.iter().cloned().map(Into::into).collect();
+        write_tokens(
+            &mut stream,
+            &self.pattern,
+            &[],
+            &values,
+            self.endianness,
+            0,
+            None,
+        )
+    }
+}
+
+/// Write a sequence of tokens to `stream` starting with `endianness`,
+/// `values` must contain exactly one value for each token that takes one,
+/// `names` are the names of the values, if any.
+///
+/// `offset` is the number of bytes written since the start of the write,
+/// [`PatternReaderTokens::Align`] pads relative to it, and `parent` is the scope
+/// of the enclosing pattern, if any.
+fn write_tokens<S: Write>(
+    stream: &mut S,
+    tkns: &[PatternReaderTokens],
+    names: &[String],
+    values: &[AnyValue],
+    mut endianness: Endianness,
+    offset: u64,
+    parent: Option<&Scope>,
+) -> StreamResult<u64> {
+    let mut used = 0;
+    let mut written = 0;
+
+    let mut i = 0;
+    while i < tkns.len() {
+        let tkn = &tkns[i];
+        let group = bit_group(&tkns[i..]);
+        if !group.is_empty() {
+            let group_values = values.get(used..used + group.len()).ok_or_else(|| {
+                StreamError::InvalidPattern("not enough values for pattern".into())
+            })?;
+            written += write_bit_group(stream, group, group_values)?;
+            used += group.len();
+            i += group.len();
+            continue;
+        }
+        i += 1;
+
+        let v = if tkn.has_value() {
+            let v = values.get(used).ok_or_else(|| {
+                StreamError::InvalidPattern("not enough values for pattern".into())
+            })?;
+            used += 1;
+            Some(v)
+        } else {
+            None
+        };
+        written += write_token(
+            stream,
+            tkn,
+            v,
+            &Scope::new(names, &values[..used], parent),
+            &mut endianness,
+            offset + written,
+        )?;
+    }
+
+    if used != values.len() {
+        return Err(StreamError::InvalidPattern(
+            "too many values for pattern".into(),
+        ));
+    }
+    Ok(written)
+}
+
+/// Write a group of [`PatternReaderTokens::Bits`] tokens packed into whole bytes,
+/// the unused bits of the last byte are zero.
+fn write_bit_group<S: Write>(
+    stream: &mut S,
+    group: &[PatternReaderTokens],
+    values: &[AnyValue],
+) -> StreamResult<u64> {
+    let mut writer = None;
+    for (tkn, v) in group.iter().zip(values) {
+        if let PatternReaderTokens::Bits(order, n) = tkn {
+            let writer = writer.get_or_insert_with(|| BitWriter::new(Vec::new(), *order));
+            let v = v.as_int().ok_or_else(|| mismatch(v, tkn))?;
+            writer.write_bits(v.to_u64_checked()?, *n)?;
+        }
+    }
+    let buf = match writer {
+        Some(writer) => writer.finish()?,
+        None => Vec::new(),
+    };
+    stream.write_all(&buf)?;
+    Ok(buf.len() as u64)
+}
+
+fn token_size(tkn: &PatternReaderTokens) -> usize {
+    match tkn {
+        PatternReaderTokens::Padding(sz) => *sz,
+        PatternReaderTokens::U8 | PatternReaderTokens::I8 | PatternReaderTokens::Bool => 1,
+        PatternReaderTokens::U16 | PatternReaderTokens::I16 | PatternReaderTokens::F16 => 2,
+        PatternReaderTokens::U32 | PatternReaderTokens::I32 | PatternReaderTokens::F32 => 4,
+        PatternReaderTokens::U24 | PatternReaderTokens::I24 => 3,
+        PatternReaderTokens::U48 | PatternReaderTokens::I48 => 6,
+        PatternReaderTokens::U64 | PatternReaderTokens::I64 | PatternReaderTokens::F64 => 8,
+        PatternReaderTokens::U128 | PatternReaderTokens::I128 => 16,
+        PatternReaderTokens::USize => std::mem::size_of::<usize>(),
+        PatternReaderTokens::LPStr(w) | PatternReaderTokens::LPBuf(w) => w.size(),
+        PatternReaderTokens::CStr(_) => 1,
+        PatternReaderTokens::Str(ArrayLen::Fixed(n))
+        | PatternReaderTokens::Bytes(ArrayLen::Fixed(n)) => *n,
+        PatternReaderTokens::Str(ArrayLen::Counted(_))
+        | PatternReaderTokens::Bytes(ArrayLen::Counted(_)) => 0,
+        PatternReaderTokens::Bits(..) => grouped_size(std::slice::from_ref(tkn), token_size),
+        PatternReaderTokens::Check(tkn, _) | PatternReaderTokens::Enum(tkn, ..) => token_size(tkn),
+        PatternReaderTokens::Expr((w, _)) => *w as usize,
+        PatternReaderTokens::Array(ArrayLen::Fixed(n), tkn) => n * token_size(tkn),
+        PatternReaderTokens::Array(ArrayLen::Counted(_), _) => 0,
+        PatternReaderTokens::Struct(_, tkns) => grouped_size(tkns, token_size),
+        PatternReaderTokens::If(_, _, then, otherwise) => {
+            token_size(then).min(token_size(otherwise))
+        }
+        PatternReaderTokens::Switch(_, cases, default) => cases
+            .iter()
+            .map(|(_, tkn)| tkn)
+            .chain(default.as_deref())
+            .map(token_size)
+            .min()
+            .unwrap_or(0),
+        PatternReaderTokens::Endian(_, tkn) => token_size(tkn),
+        PatternReaderTokens::EndianSwitch(..)
+        | PatternReaderTokens::SeekTo(_)
+        | PatternReaderTokens::Skip(_)
+        | PatternReaderTokens::Align(_)
+        | PatternReaderTokens::At(..) => 0,
+    }
+}
+
+/// Write a single token to `stream`, `scope` has the values of the enclosing pattern
+/// up to and including this one and `endianness` is the current endianness of the pattern,
+/// which is updated by [`PatternReaderTokens::EndianSwitch`].
+///
+/// `value` is ignored for tokens that don't take a value, like padding,
+/// and `offset` is the number of bytes written before this token.
+fn write_token<S: Write>(
+    stream: &mut S,
+    tkn: &PatternReaderTokens,
+    value: Option<&AnyValue>,
+    scope: &Scope,
+    endianness: &mut Endianness,
+    offset: u64,
+) -> StreamResult<u64> {
+    let v = match (tkn, value) {
+        (PatternReaderTokens::Padding(size), _) => {
+            stream.write_all(&vec![0; *size])?;
+            return Ok(*size as u64);
+        }
+        (PatternReaderTokens::Align(n), _) => {
+            let rem = offset
+                .checked_rem(*n)
+                .ok_or_else(|| StreamError::InvalidPattern("can't align to 0 bytes".into()))?;
+            let size = (n - rem) % n;
+            stream.write_all(&vec![0; size as usize])?;
+            return Ok(size);
+        }
+        (PatternReaderTokens::EndianSwitch(..), _) => {
+            *endianness = tkn.select_endianness(scope)?;
+            return Ok(0);
+        }
+        (
+            PatternReaderTokens::SeekTo(_)
+            | PatternReaderTokens::Skip(_)
+            | PatternReaderTokens::At(..),
+            _,
+        ) => {
+            return Err(StreamError::InvalidPattern(format!(
+                "{:?} can't be written",
+                tkn
+            )))
+        }
+        (_, Some(v)) => v.clone(),
+        (_, None) => {
+            return Err(StreamError::InvalidPattern(format!(
+                "missing value for {:?}",
+                tkn
+            )))
+        }
+    };
+
+    match (tkn, &v) {
+        (PatternReaderTokens::Array(len, elem), AnyValue::List(list)) => {
+            let len = len.resolve(scope)?;
+            if list.len() != len {
+                return Err(StreamError::InvalidPattern(format!(
+                    "array has {} elements but {} are required",
+                    list.len(),
+                    len
+                )));
+            }
+            let mut written = 0;
+            for e in list.iter() {
+                written += write_token(
+                    stream,
+                    elem,
+                    Some(e),
+                    &scope.inner(),
+                    &mut endianness.clone(),
+                    offset + written,
+                )?;
+            }
+            return Ok(written);
+        }
+        (PatternReaderTokens::Struct(names, tkns), AnyValue::Struct(fields)) => {
+            let values = names
+                .iter()
+                .map(|name| {
+                    if name.is_empty() {
+                        // a conditional field takes its fields from the enclosing struct
+                        return Ok(v.clone());
+                    }
+                    fields
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, v)| v.clone())
+                        .ok_or_else(|| {
+                            StreamError::InvalidPattern(format!("missing value for field {}", name))
+                        })
+                })
+                .collect::<StreamResult<Vec<_>>>()?;
+            return write_tokens(
+                stream,
+                tkns,
+                names,
+                &values,
+                *endianness,
+                offset,
+                Some(scope),
+            );
+        }
+        (PatternReaderTokens::If(..) | PatternReaderTokens::Switch(..), _) => {
+            let branch = tkn.select_branch(scope)?.unwrap();
+            return write_token(
+                stream,
+                branch,
+                Some(&v),
+                &scope.inner(),
+                &mut endianness.clone(),
+                offset,
+            );
+        }
+        (PatternReaderTokens::Endian(fixed, tkn), v) => {
+            return write_token(stream, tkn, Some(v), scope, &mut fixed.clone(), offset);
+        }
+        (PatternReaderTokens::Check(tkn, _), v) => {
+            check_not_bits(tkn)?;
+            return write_token(stream, tkn, Some(v), scope, endianness, offset);
+        }
+        (PatternReaderTokens::Enum(inner, names, unknown), v) => {
+            if !inner.is_int() {
+                return Err(StreamError::InvalidPattern(format!(
+                    "enums must wrap an integer token, not {:?}",
+                    inner
+                )));
+            }
+            let raw = match v {
+                AnyValue::Str(name) => names
+                    .iter()
+                    .find(|(_, n)| n == name)
+                    .map(|(raw, _)| *raw)
+                    .ok_or_else(|| mismatch(v, tkn))?,
+                v => v.as_int().ok_or_else(|| mismatch(v, tkn))?,
+            };
+            if *unknown == UnknownValue::Error && !names.iter().any(|(n, _)| n.num_eq(&raw)) {
+                return Err(out_of_range(v, tkn));
+            }
+            return write_token(stream, inner, Some(&raw.into()), scope, endianness, offset);
+        }
+        (PatternReaderTokens::Bits(..), v) => {
+            return write_bit_group(stream, std::slice::from_ref(tkn), std::slice::from_ref(v));
+        }
+        (PatternReaderTokens::LPStr(w), AnyValue::Str(s)) => {
+            return Ok(write_lpstr(stream, *w, *endianness, s)?);
+        }
+        (PatternReaderTokens::LPBuf(w), AnyValue::Bytes(b)) => {
+            return Ok(write_lpbuf(stream, *w, *endianness, b)?);
+        }
+        (PatternReaderTokens::CStr(maxlen), AnyValue::Str(s)) => {
+            if s.len() >= *maxlen || s.contains('\0') {
+                return Err(out_of_range(&v, tkn));
+            }
+            stream.write_all(s.as_bytes())?;
+            stream.write_u8(0)?;
+            return Ok(s.len() as u64 + 1);
+        }
+        (PatternReaderTokens::Str(len), AnyValue::Str(s)) => {
+            let len = len.resolve(scope)?;
+            if s.len() > len {
+                return Err(out_of_range(&v, tkn));
+            }
+            stream.write_all(s.as_bytes())?;
+            stream.write_all(&vec![0; len - s.len()])?;
+            return Ok(len as u64);
+        }
+        (PatternReaderTokens::Bytes(len), AnyValue::Bytes(b)) => {
+            let len = len.resolve(scope)?;
+            if b.len() != len {
+                return Err(StreamError::InvalidPattern(format!(
+                    "buffer has {} bytes but {} are required",
+                    b.len(),
+                    len
+                )));
+            }
+            stream.write_all(b)?;
+            return Ok(len as u64);
+        }
+        (
+            PatternReaderTokens::Array(..)
+            | PatternReaderTokens::Struct(..)
+            | PatternReaderTokens::LPStr(_)
+            | PatternReaderTokens::LPBuf(_)
+            | PatternReaderTokens::CStr(_)
+            | PatternReaderTokens::Str(_)
+            | PatternReaderTokens::Bytes(_),
+            v,
+        ) => return Err(mismatch(v, tkn)),
+        _ => {}
+    }
+
+    match endianness {
+        Endianness::LittleEndian => write_scalar::<_, byteorder::LittleEndian>(stream, tkn, v),
+        Endianness::BigEndian => write_scalar::<_, byteorder::BigEndian>(stream, tkn, v),
+    }
+}
+
+/// Write a token that doesn't contain other tokens using the byte order `Ord`.
+fn write_scalar<S: Write, Ord: byteorder::ByteOrder>(
+    stream: &mut S,
+    tkn: &PatternReaderTokens,
+    v: AnyValue,
+) -> StreamResult<u64> {
+    const U24_MAX: u32 = (1 << 24) - 1;
+    const I24_RANGE: std::ops::RangeInclusive<i32> = -(1 << 23)..=(1 << 23) - 1;
+
+    match tkn {
+        PatternReaderTokens::U8 => stream.write_u8(v.try_into()?)?,
+        PatternReaderTokens::U24 => match u32::try_from(&v)? {
+            n if n <= U24_MAX => stream.write_u24::<Ord>(n)?,
+            _ => return Err(out_of_range(&v, tkn)),
+        },
+        PatternReaderTokens::I24 => match i32::try_from(&v)? {
+            n if I24_RANGE.contains(&n) => stream.write_i24::<Ord>(n)?,
+            _ => return Err(out_of_range(&v, tkn)),
+        },
+        PatternReaderTokens::U48 => {
+            let n = AnyInt::try_from(&v)?.as_width(AnyIntKind::U48)?;
+            stream.write_u48::<Ord>(n.try_into()?)?
+        }
+        PatternReaderTokens::I48 => {
+            let n = AnyInt::try_from(&v)?.as_width(AnyIntKind::I48)?;
+            stream.write_i48::<Ord>(n.try_into()?)?
+        }
+        PatternReaderTokens::U128 => stream.write_u128::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::I128 => stream.write_i128::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::I8 => stream.write_i8(v.try_into()?)?,
+        PatternReaderTokens::U16 => stream.write_u16::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::U32 => stream.write_u32::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::U64 => stream.write_u64::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::I16 => stream.write_i16::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::I32 => stream.write_i32::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::I64 => stream.write_i64::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::USize => {
+            if std::mem::size_of::<usize>() == 4 {
+                stream.write_u32::<Ord>(v.try_into()?)?
+            } else {
+                stream.write_u64::<Ord>(v.try_into()?)?
+            }
+        }
+        PatternReaderTokens::Bool => stream.write_u8(bool::try_from(v)? as u8)?,
+        PatternReaderTokens::F16 => stream.write_u16::<Ord>(f32_to_f16(v.try_into()?))?,
+        PatternReaderTokens::F32 => stream.write_f32::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::F64 => stream.write_f64::<Ord>(v.try_into()?)?,
+        PatternReaderTokens::Expr(_) => {
+            return Err(StreamError::InvalidPattern(
+                "expression tokens can't be written".into(),
+            ))
+        }
+        PatternReaderTokens::Padding(_)
+        | PatternReaderTokens::Array(..)
+        | PatternReaderTokens::Struct(..)
+        | PatternReaderTokens::If(..)
+        | PatternReaderTokens::Switch(..)
+        | PatternReaderTokens::Endian(..)
+        | PatternReaderTokens::EndianSwitch(..)
+        | PatternReaderTokens::LPStr(_)
+        | PatternReaderTokens::LPBuf(_)
+        | PatternReaderTokens::CStr(_)
+        | PatternReaderTokens::Str(_)
+        | PatternReaderTokens::Bytes(_)
+        | PatternReaderTokens::Bits(..)
+        | PatternReaderTokens::Check(..)
+        | PatternReaderTokens::Enum(..)
+        | PatternReaderTokens::SeekTo(_)
+        | PatternReaderTokens::Skip(_)
+        | PatternReaderTokens::Align(_)
+        | PatternReaderTokens::At(..) => unreachable!(),
+    }
+    Ok(token_size(tkn) as u64)
+}
+
+fn out_of_range(v: &AnyValue, tkn: &PatternReaderTokens) -> StreamError {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{:?} is out of range for {:?}", v, tkn),
+    )
+    .into()
+}
+
+fn mismatch(v: &AnyValue, tkn: &PatternReaderTokens) -> StreamError {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Cannot write {:?} as {:?}", v, tkn),
+    )
+    .into()
+}
+
+/// Write a struct to a stream from a map of field names to values,
+/// this is the counterpart of [`super::advanced_readers::StructReader`]
+/// so a map produced by it can be written back in the same layout.
+///
+/// The byte order is chosen when the writer is created, [`StructWriter::default`]
+/// writes little endian.
+///
+/// A field name that is empty, contains a `.` or is declared twice doesn't stop
+/// the declarations, writing fails with [`StreamError::InvalidPattern`] instead.
+///
+/// Fields refer to other fields by path like the ones of a
+/// [`super::advanced_readers::StructReader`].
+#[derive(Clone, Debug)]
+pub struct StructWriter {
+    fields: PatternWriter,
+    /// The name of each value of `fields`, conditional fields are unnamed.
+    field_names: Vec<String>,
+    /// Names declared inside the branches of conditional fields.
+    branch_names: Vec<String>,
+    bit_order: BitOrder,
+    /// The first error found while declaring the fields, returned by every write.
+    error: Option<String>,
+}
+
+impl StructWriter {
+    /// Create an empty struct, fields are written with `endianness`
+    /// unless it's overridden or switched.
+    pub fn new(endianness: Endianness) -> Self {
+        Self {
+            fields: PatternWriter::new(endianness),
+            field_names: Vec::new(),
+            branch_names: Vec::new(),
+            bit_order: BitOrder::MsbFirst,
+            error: None,
+        }
+    }
+
+    pub fn new_be() -> Self {
+        Self::new(Endianness::BigEndian)
+    }
+
+    pub fn new_le() -> Self {
+        Self::new(Endianness::LittleEndian)
+    }
+
+    /// Keep `error` unless an earlier one was found.
+    fn set_error(&mut self, error: String) {
+        self.error.get_or_insert(error);
+    }
+
+    /// Declare a field name, an empty name, a name containing a `.` or a name
+    /// that was already declared is an error returned when writing.
+    fn push_name(&mut self, name: &str) {
+        if name.is_empty() || name.contains('.') {
+            self.set_error(format!("invalid field name {:?}", name));
+        } else if self.declared_names().any(|n| n == name) {
+            self.set_error(format!("duplicate field name {}", name));
+        }
+        self.field_names.push(name.to_string());
+    }
+
+    /// All the names declared in this struct, including the ones inside conditional fields.
+    fn declared_names(&self) -> impl Iterator<Item = &str> {
+        self.field_names
+            .iter()
+            .chain(self.branch_names.iter())
+            .map(String::as_str)
+            .filter(|n| !n.is_empty())
+    }
+
+    /// Add `tkn` as an unnamed field, with the names declared by `branches`.
+    fn push_branches(&mut self, tkn: PatternReaderTokens, branches: &[&Self]) {
+        let mut names: Vec<String> = Vec::new();
+        for branch in branches {
+            if let Some(e) = &branch.error {
+                self.set_error(e.clone());
+            }
+        }
+        for name in branches.iter().flat_map(|b| b.declared_names()) {
+            if self.declared_names().any(|n| n == name) {
+                self.set_error(format!("duplicate field name {}", name));
+            }
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+        self.branch_names.extend(names);
+        self.fields.pattern.push(tkn);
+        self.field_names.push(String::new());
+    }
+
+    /// Turn the struct into a [`PatternReaderTokens::Struct`], dropping its error.
+    fn into_token(self) -> PatternReaderTokens {
+        PatternReaderTokens::Struct(self.field_names, self.fields.pattern)
+    }
+
+    /// Turn `fields` into a [`PatternReaderTokens::Struct`] nested in this struct,
+    /// an error of `fields` becomes an error of this struct.
+    fn nest(&mut self, mut fields: Self) -> PatternReaderTokens {
+        if let Some(e) = fields.error.take() {
+            self.set_error(e);
+        }
+        fields.into_token()
+    }
+
+    pub fn add_u8_field(mut self, name: &str) -> Self {
+        self.fields.add_u8();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u16_field(mut self, name: &str) -> Self {
+        self.fields.add_u16();
+        self.push_name(name);
+        self
+    }
+
+    /// Add a 24 bit unsigned integer field, the value must be an [`super::AnyInt::U32`] that fits.
+    pub fn add_u24_field(mut self, name: &str) -> Self {
+        self.fields.add_u24();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u32_field(mut self, name: &str) -> Self {
+        self.fields.add_u32();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u48_field(mut self, name: &str) -> Self {
+        self.fields.add_u48();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u64_field(mut self, name: &str) -> Self {
+        self.fields.add_u64();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u128_field(mut self, name: &str) -> Self {
+        self.fields.add_u128();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_usize_field(mut self, name: &str) -> Self {
+        self.fields.add_usize();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i8_field(mut self, name: &str) -> Self {
+        self.fields.add_i8();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i16_field(mut self, name: &str) -> Self {
+        self.fields.add_i16();
+        self.push_name(name);
+        self
+    }
+
+    /// Add a 24 bit signed integer field, the value must be an [`super::AnyInt::I32`] that fits.
+    pub fn add_i24_field(mut self, name: &str) -> Self {
+        self.fields.add_i24();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i32_field(mut self, name: &str) -> Self {
+        self.fields.add_i32();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i48_field(mut self, name: &str) -> Self {
+        self.fields.add_i48();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i64_field(mut self, name: &str) -> Self {
+        self.fields.add_i64();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_i128_field(mut self, name: &str) -> Self {
+        self.fields.add_i128();
+        self.push_name(name);
+        self
+    }
+
+    /// Add an IEEE 754 half precision float field, the value is rounded to the nearest `f16`.
+    pub fn add_f16_field(mut self, name: &str) -> Self {
+        self.fields.add_f16();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_f32_field(mut self, name: &str) -> Self {
+        self.fields.add_f32();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_f64_field(mut self, name: &str) -> Self {
+        self.fields.add_f64();
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_padding(mut self, size: usize) -> Self {
+        self.fields.add_padding(size);
+        self
+    }
+
+    /// Write zeros up to the next offset that is a multiple of `n` bytes counted from
+    /// the start of the write.
+    ///
+    /// Writing fails with [`StreamError::InvalidPattern`] if `n` is 0.
+    pub fn add_align(mut self, n: u64) -> Self {
+        self.fields.add_align(n);
+        self
+    }
+
+    pub fn add_bool_field(mut self, name: &str) -> Self {
+        self.fields.add_bool();
+        self.push_name(name);
+        self
+    }
+
+    /// Set the bit order of the bits fields added after this, the default is
+    /// [`BitOrder::MsbFirst`].
+    pub fn set_bit_order(mut self, order: BitOrder) -> Self {
+        self.bit_order = order;
+        self
+    }
+
+    /// Add an unsigned integer field of `nbits` bits, consecutive bits fields
+    /// are packed together into whole bytes.
+    ///
+    /// # Panics
+    /// If `nbits` is 0 or greater than 64.
+    pub fn add_bits_field(mut self, name: &str, nbits: u32) -> Self {
+        self.fields.add_bits(self.bit_order, nbits);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_lpstr_field(mut self, name: &str, width: LPWidth) -> Self {
+        self.fields.add_lpstr(width);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_lpbuf_field(mut self, name: &str, width: LPWidth) -> Self {
+        self.fields.add_lpbuf(width);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_cstr_field(mut self, name: &str, maxlen: usize) -> Self {
+        self.fields.add_cstr(maxlen);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_str_field(mut self, name: &str, len: usize) -> Self {
+        self.fields.add_str(len);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_bytes_field(mut self, name: &str, len: usize) -> Self {
+        self.fields.add_bytes(len);
+        self.push_name(name);
+        self
+    }
+
+    /// Add a NUL padded string field whose length in bytes must be the value
+    /// of the previously written integer field `len_field`.
+    pub fn add_counted_str_field(mut self, name: &str, len_field: &str) -> Self {
+        let len = ValueRef::Field(len_field.to_string());
+        self.fields
+            .pattern
+            .push(PatternReaderTokens::Str(ArrayLen::Counted(len)));
+        self.push_name(name);
+        self
+    }
+
+    /// Add a byte buffer field whose length must be the value of the previously
+    /// written integer field `len_field`.
+    pub fn add_counted_bytes_field(mut self, name: &str, len_field: &str) -> Self {
+        let len = ValueRef::Field(len_field.to_string());
+        self.fields
+            .pattern
+            .push(PatternReaderTokens::Bytes(ArrayLen::Counted(len)));
+        self.push_name(name);
+        self
+    }
+
+    /// Add an integer field of `tkn` encoded with a table of `values` and their names,
+    /// see [`PatternWriter::add_enum`].
+    ///
+    /// `tkn` must be an integer token.
+    pub fn add_enum_field<T: Into<AnyInt> + Copy>(
+        mut self,
+        name: &str,
+        tkn: PatternReaderTokens,
+        values: &[(T, &str)],
+        unknown: UnknownValue,
+    ) -> Self {
+        self.fields.add_enum(tkn, values, unknown);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_u8_enum_field(self, name: &str, values: &[(u8, &str)]) -> Self {
+        self.add_enum_field(name, PatternReaderTokens::U8, values, UnknownValue::KeepRaw)
+    }
+
+    pub fn add_u16_enum_field(self, name: &str, values: &[(u16, &str)]) -> Self {
+        self.add_enum_field(
+            name,
+            PatternReaderTokens::U16,
+            values,
+            UnknownValue::KeepRaw,
+        )
+    }
+
+    pub fn add_u32_enum_field(self, name: &str, values: &[(u32, &str)]) -> Self {
+        self.add_enum_field(
+            name,
+            PatternReaderTokens::U32,
+            values,
+            UnknownValue::KeepRaw,
+        )
+    }
+
+    /// Add a sub struct field, the value must be an [`AnyValue::Struct`]
+    /// containing the fields of `fields`.
+    ///
+    /// The sub struct is written with the endianness of this struct at that point,
+    /// the endianness `fields` was created with is ignored.
+    pub fn add_struct_field(mut self, name: &str, fields: Self) -> Self {
+        let tkn = self.nest(fields);
+        self.fields.pattern.push(tkn);
+        self.push_name(name);
+        self
+    }
+
+    pub fn add_array_field(mut self, name: &str, len: usize, tkn: PatternReaderTokens) -> Self {
+        self.fields.add_array(len, tkn);
+        self.push_name(name);
+        self
+    }
+
+    /// Add an array field of `element` structs, the number of elements must be
+    /// the value of the previously written integer field `count_field`.
+    pub fn add_counted_array(mut self, name: &str, count_field: &str, element: Self) -> Self {
+        let count = ValueRef::Field(count_field.to_string());
+        let element = self.nest(element);
+        self.fields.pattern.push(PatternReaderTokens::Array(
+            ArrayLen::Counted(count),
+            Box::new(element),
+        ));
+        self.push_name(name);
+        self
+    }
+
+    /// Write the fields of `then` if the integer field `field` equals `value`,
+    /// the fields of `otherwise`, if any, if not.
+    ///
+    /// The values of the branch fields are taken from the values of this struct,
+    /// see [`super::advanced_readers::StructReader::add_if`] for the limits of branches.
+    ///
+    /// Writing fails with [`StreamError::InvalidPattern`] if the branches declare names
+    /// already used in this struct.
+    pub fn add_if(
+        mut self,
+        field: &str,
+        value: impl Into<AnyInt>,
+        then: Self,
+        otherwise: Option<Self>,
+    ) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        let otherwise = otherwise.unwrap_or_default();
+        let tkn = PatternReaderTokens::If(
+            index,
+            value.into(),
+            Box::new(then.clone().into_token()),
+            Box::new(otherwise.clone().into_token()),
+        );
+        self.push_branches(tkn, &[&then, &otherwise]);
+        self
+    }
+
+    /// Add a sub struct field whose layout is selected by the value of the integer
+    /// field `field`, the value must be an [`AnyValue::Struct`] containing the fields
+    /// of the first matching case, or of `default` if no case matches.
+    ///
+    /// Writing fails if no case matches and there's no `default`.
+    pub fn add_switch_field(
+        mut self,
+        name: &str,
+        field: &str,
+        cases: Vec<(AnyInt, Self)>,
+        default: Option<Self>,
+    ) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        let cases = cases.into_iter().map(|(v, s)| (v, self.nest(s))).collect();
+        let default = default.map(|s| Box::new(self.nest(s)));
+        self.fields
+            .pattern
+            .push(PatternReaderTokens::Switch(index, cases, default));
+        self.push_name(name);
+        self
+    }
+
+    /// Add a field written with a fixed `endianness`, regardless of the endianness of the struct.
+    pub fn add_endian_field(
+        mut self,
+        name: &str,
+        endianness: Endianness,
+        tkn: PatternReaderTokens,
+    ) -> Self {
+        self.fields.add_endian(endianness, tkn);
+        self.push_name(name);
+        self
+    }
+
+    /// Write all the following fields of this struct with the endianness of the first case
+    /// equal to the value of the integer field `field`.
+    ///
+    /// Writing fails if no case matches.
+    pub fn add_endian_switch(mut self, field: &str, cases: &[(AnyInt, Endianness)]) -> Self {
+        let index = ValueRef::Field(field.to_string());
+        self.fields
+            .pattern
+            .push(PatternReaderTokens::EndianSwitch(index, cases.to_vec()));
+        self
+    }
+
+    pub fn required_bytes(&self) -> u64 {
+        self.fields.pattern_required_bytes()
+    }
+
+    /// Write the fields in `values` to the stream in the order they were declared.
+    ///
+    /// # Returns
+    /// The number of bytes written.
+    ///
+    /// # Errors
+    /// This function will return an error in the following cases:
+    /// * A field name is invalid or declared twice, in this struct or in its sub structs.
+    /// * A field refers to a field that isn't written before it.
+    /// * A field is missing from `values`.
+    /// * A value doesn't have the type of its field.
+    /// * The stream returns an error.
+    pub fn write<S: Write, V: Clone + Into<AnyValue>>(
+        &self,
+        mut stream: S,
+        values: &HashMap<String, V>,
+    ) -> StreamResult<u64> {
+        if let Some(e) = &self.error {
+            return Err(StreamError::InvalidPattern(e.clone()));
+        }
+        let values = self
+            .field_names
+            .iter()
+            .map(|name| {
+                if name.is_empty() {
+                    // a conditional field, its branch takes the fields it needs by name
+                    let fields = values
+                        .iter()
+                        .map(|(n, v)| (n.clone(), v.clone().into()))
+                        .collect();
+                    return Ok(AnyValue::Struct(fields));
+                }
+                values.get(name).cloned().map(Into::into).ok_or_else(|| {
+                    StreamError::InvalidPattern(format!("missing value for field {}", name))
+                })
+            })
+            .collect::<StreamResult<Vec<_>>>()?;
+        write_tokens(
+            &mut stream,
+            &self.fields.pattern,
+            &self.field_names,
+            &values,
+            self.fields.endianness,
+            0,
+            None,
+        )
+    }
+
+    pub fn get_inner_pattern(&self) -> &PatternWriter {
+        &self.fields
+    }
+}
+
+impl Default for StructWriter {
+    fn default() -> Self {
+        Self::new_le()
+    }
+}
+
+impl Default for PatternWriter {
+    fn default() -> Self {
+        Self::new_le()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::advanced_readers::{PatternReader, StructReader};
+    use std::io::Cursor;
+
+    const ELF_HEADER: [u8; 64] = [
+        0x7F, 0x45, 0x4C, 0x46, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x03, 0x00, 0x3E, 0x00, 0x01, 0x00, 0x00, 0x00, 0x50, 0x6F, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x2B, 0x02, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00, 0x0D, 0x00, 0x40, 0x00,
+        0x1F, 0x00, 0x1E, 0x00,
+    ];
+
+    #[test]
+    fn test_write_pattern() {
+        let mut buf = Vec::new();
+        let written = PatternWriter::new_be()
+            .add_u16()
+            .add_padding(2)
+            .add_i32()
+            .add_bool()
+            .write_pattern(
+                &mut buf,
+                &[AnyInt::U16(0x1234), AnyInt::I32(-2), AnyInt::Bool(true)],
+            )
+            .unwrap();
+        assert_eq!(written, 9);
+        assert_eq!(buf, [0x12, 0x34, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFE, 0x01]);
+    }
+
+    #[test]
+    fn test_write_from_format() {
+        let mut buf = Vec::new();
+        PatternWriter::from_format("!H2xi?")
+            .unwrap()
+            .write_pattern(
+                &mut buf,
+                &[AnyInt::U16(0x1234), AnyInt::I32(-2), AnyInt::Bool(true)],
+            )
+            .unwrap();
+        assert_eq!(buf, [0x12, 0x34, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFE, 0x01]);
+        assert_eq!(
+            PatternWriter::from_format("<H").unwrap().endianness(),
+            Endianness::LittleEndian
+        );
+    }
+
+    #[test]
+    fn test_write_pattern_errors() {
+        let mut pattern = PatternWriter::new_le();
+        pattern.add_u16().add_u8();
+        assert!(pattern
+            .write_pattern(Vec::new(), &[AnyInt::U16(1)])
+            .is_err());
+        assert!(pattern
+            .write_pattern(Vec::new(), &[AnyInt::U16(1), AnyInt::U32(1)])
+            .is_err());
+        assert!(pattern
+            .write_pattern(Vec::new(), &[AnyInt::U16(1), AnyInt::U8(1), AnyInt::U8(1)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_struct_round_trip() {
+        let header = StructReader::new_le()
+            .add_u32_field("ei_mag")
+            .add_u8_field("ei_class")
+            .add_u8_field("ei_data")
+            .add_u8_field("ei_version")
+            .add_u8_field("ei_osabi")
+            .add_u8_field("ei_abiversion")
+            .add_padding(7)
+            .add_u16_field("e_type")
+            .add_u16_field("e_machine")
+            .add_u32_field("e_version")
+            .add_u64_field("e_entry")
+            .add_u64_field("e_phoff")
+            .add_u64_field("e_shoff")
+            .add_u32_field("e_flags")
+            .add_u16_field("e_ehsize")
+            .add_u16_field("e_phentsize")
+            .add_u16_field("e_phnum")
+            .add_u16_field("e_shentsize")
+            .add_u16_field("e_shnum")
+            .add_u16_field("e_shstrndx")
+            .read(Cursor::new(ELF_HEADER))
+            .unwrap();
+
+        let writer = StructWriter::new_le()
+            .add_u32_field("ei_mag")
+            .add_u8_field("ei_class")
+            .add_u8_field("ei_data")
+            .add_u8_field("ei_version")
+            .add_u8_field("ei_osabi")
+            .add_u8_field("ei_abiversion")
+            .add_padding(7)
+            .add_u16_field("e_type")
+            .add_u16_field("e_machine")
+            .add_u32_field("e_version")
+            .add_u64_field("e_entry")
+            .add_u64_field("e_phoff")
+            .add_u64_field("e_shoff")
+            .add_u32_field("e_flags")
+            .add_u16_field("e_ehsize")
+            .add_u16_field("e_phentsize")
+            .add_u16_field("e_phnum")
+            .add_u16_field("e_shentsize")
+            .add_u16_field("e_shnum")
+            .add_u16_field("e_shstrndx");
+        assert_eq!(writer.required_bytes(), 64);
+
+        let mut values = header.into_inner();
+        let mut buf = Vec::new();
+        writer.write(&mut buf, &values).unwrap();
+        assert_eq!(buf, ELF_HEADER);
+
+        values.insert("e_type".into(), AnyInt::U16(2).into());
+        let mut buf = Vec::new();
+        writer.write(&mut buf, &values).unwrap();
+        assert_eq!(&buf[..16], &ELF_HEADER[..16]);
+        assert_eq!(&buf[16..18], &[0x02, 0x00]);
+        assert_eq!(&buf[18..], &ELF_HEADER[18..]);
+
+        values.remove("e_machine");
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_floats() {
+        let mut buf = Vec::new();
+        let written = PatternWriter::new_le()
+            .add_f16()
+            .add_f32()
+            .add_f64()
+            .write_pattern(
+                &mut buf,
+                &[AnyValue::F16(1.5), AnyValue::F32(1.25), AnyValue::F64(-3.5)],
+            )
+            .unwrap();
+        assert_eq!(written, 14);
+
+        let v = PatternReader::new_le()
+            .add_f16()
+            .add_f32()
+            .add_f64()
+            .read_pattern(Cursor::new(&buf))
+            .unwrap();
+        assert_eq!(
+            v,
+            vec![AnyValue::F16(1.5), AnyValue::F32(1.25), AnyValue::F64(-3.5)]
+        );
+
+        assert!(PatternWriter::new_le()
+            .add_f64()
+            .write_pattern(Vec::new(), &[AnyValue::F32(1.0)])
+            .is_err());
+        assert!(PatternWriter::new_le()
+            .add_u32()
+            .write_pattern(Vec::new(), &[AnyValue::F32(1.0)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_write_odd_widths() {
+        let mut pattern = PatternWriter::new_be();
+        pattern
+            .add_u24()
+            .add_i24()
+            .add_u48()
+            .add_i48()
+            .add_u128()
+            .add_i128();
+        assert_eq!(pattern.pattern_required_bytes(), 50);
+
+        let values = [
+            AnyInt::U32(0xFF_FFFF),
+            AnyInt::I32(-(1 << 23)),
+            AnyInt::U48(0x1234_5678_9ABC),
+            AnyInt::I48(-2),
+            AnyInt::U128(u128::MAX),
+            AnyInt::I128(i128::MIN),
+        ];
+        let mut buf = Vec::new();
+        assert_eq!(pattern.write_pattern(&mut buf, &values).unwrap(), 50);
+        assert_eq!(&buf[..6], &[0xFF, 0xFF, 0xFF, 0x80, 0x00, 0x00]);
+
+        let mut reader = PatternReader::new_be();
+        reader
+            .add_u24()
+            .add_i24()
+            .add_u48()
+            .add_i48()
+            .add_u128()
+            .add_i128();
+        assert_eq!(reader.read_pattern(Cursor::new(&buf)).unwrap(), values);
+
+        let mut pattern = PatternWriter::new_le();
+        pattern.add_u24();
+        assert!(pattern
+            .write_pattern(Vec::new(), &[AnyInt::U32(1 << 24)])
+            .is_err());
+        let mut pattern = PatternWriter::new_le();
+        pattern.add_i24();
+        assert!(pattern
+            .write_pattern(Vec::new(), &[AnyInt::I32(1 << 23)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_write_48_bit_widths() {
+        let mut pattern = PatternWriter::new_be();
+        pattern.add_u48().add_i48();
+        let mut buf = Vec::new();
+        pattern
+            .write_pattern(&mut buf, &[AnyInt::U64(5), AnyInt::I64(-1)])
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 5, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        buf.clear();
+        pattern
+            .write_pattern(&mut buf, &[AnyInt::U8(5), AnyInt::I8(-1)])
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 5, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        buf.clear();
+        pattern
+            .write_pattern(&mut buf, &[AnyInt::U32(5), AnyInt::U16(7)])
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 7]);
+
+        let mut pattern = PatternWriter::new_le();
+        pattern.add_u48();
+        assert!(matches!(
+            pattern.write_pattern(Vec::new(), &[AnyInt::U48(1 << 48)]),
+            Err(StreamError::Overflow(AnyInt::U48(_), AnyIntKind::U48))
+        ));
+        assert!(matches!(
+            pattern.write_pattern(Vec::new(), &[AnyInt::U64(1 << 48)]),
+            Err(StreamError::Overflow(AnyInt::U64(_), AnyIntKind::U48))
+        ));
+        let mut pattern = PatternWriter::new_le();
+        pattern.add_i48();
+        assert!(matches!(
+            pattern.write_pattern(Vec::new(), &[AnyInt::I64(-(1 << 47) - 1)]),
+            Err(StreamError::Overflow(_, AnyIntKind::I48))
+        ));
+    }
+
+    #[test]
+    fn test_array_round_trip() {
+        let data = [
+            0x02, 0x00, 0x7F, 0x45, 0x01, 0x00, 0x10, 0x00, 0x02, 0x00, 0x20, 0x00,
+        ];
+        let header = StructReader::new_le()
+            .add_u16_field("count")
+            .add_array_field("magic", 2, PatternReaderTokens::U8)
+            .add_counted_array(
+                "entries",
+                "count",
+                StructReader::new_le()
+                    .add_u8_field("kind")
+                    .add_padding(1)
+                    .add_u16_field("len"),
+            )
+            .read(Cursor::new(data))
+            .unwrap();
+
+        let writer = StructWriter::new_le()
+            .add_u16_field("count")
+            .add_array_field("magic", 2, PatternReaderTokens::U8)
+            .add_counted_array(
+                "entries",
+                "count",
+                StructWriter::new_le()
+                    .add_u8_field("kind")
+                    .add_padding(1)
+                    .add_u16_field("len"),
+            );
+        assert_eq!(writer.required_bytes(), 4);
+
+        let mut values = header.into_inner();
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 12);
+        assert_eq!(buf, data);
+
+        values.insert("count".into(), AnyInt::U16(1).into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("magic".into(), AnyValue::List(vec![]));
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_nested_struct_round_trip() {
+        let data = [0x7F, 0x45, 0x4C, 0x46, 0x02, 0x03, 0x00];
+        let header = StructReader::new_le()
+            .add_struct_field(
+                "e_ident",
+                StructReader::new_le()
+                    .add_u32_field("ei_mag")
+                    .add_u8_field("ei_class"),
+            )
+            .add_u16_field("e_type")
+            .read(Cursor::new(data))
+            .unwrap();
+
+        let writer = StructWriter::new_le()
+            .add_struct_field(
+                "e_ident",
+                StructWriter::new_le()
+                    .add_u32_field("ei_mag")
+                    .add_u8_field("ei_class"),
+            )
+            .add_u16_field("e_type");
+        assert_eq!(writer.required_bytes(), 7);
+
+        let mut values = header.into_inner();
+        let mut buf = Vec::new();
+        writer.write(&mut buf, &values).unwrap();
+        assert_eq!(buf, data);
+
+        values.insert(
+            "e_ident".into(),
+            AnyValue::Struct(vec![("ei_mag".into(), AnyInt::U32(0).into())]),
+        );
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    const ELF_LIKE_64: [u8; 19] = [
+        0x02, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x00,
+    ];
+
+    const ELF_LIKE_32: [u8; 11] = [
+        0x01, 0x00, 0x10, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x02, 0x00,
+    ];
+
+    fn elf_like() -> StructWriter {
+        StructWriter::new_le()
+            .add_u8_field("class")
+            .add_if(
+                "class",
+                2u8,
+                StructWriter::new_le()
+                    .add_u64_field("entry")
+                    .add_u64_field("phoff"),
+                Some(
+                    StructWriter::new_le()
+                        .add_u32_field("entry")
+                        .add_u32_field("phoff"),
+                ),
+            )
+            .add_u16_field("flags")
+    }
+
+    #[test]
+    fn test_write_if_round_trip() {
+        for data in [&ELF_LIKE_64[..], &ELF_LIKE_32[..]] {
+            let values = StructReader::new_le()
+                .add_u8_field("class")
+                .add_if(
+                    "class",
+                    2u8,
+                    StructReader::new_le()
+                        .add_u64_field("entry")
+                        .add_u64_field("phoff"),
+                    Some(
+                        StructReader::new_le()
+                            .add_u32_field("entry")
+                            .add_u32_field("phoff"),
+                    ),
+                )
+                .add_u16_field("flags")
+                .read(Cursor::new(data))
+                .unwrap()
+                .into_inner();
+            let mut buf = Vec::new();
+            elf_like().write(&mut buf, &values).unwrap();
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[test]
+    fn test_write_if_selects_branch() {
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("class".into(), AnyInt::U8(1).into());
+        values.insert("entry".into(), AnyInt::U32(0x1000).into());
+        values.insert("phoff".into(), AnyInt::U32(0x34).into());
+        values.insert("flags".into(), AnyInt::U16(2).into());
+        let mut buf = Vec::new();
+        assert_eq!(elf_like().write(&mut buf, &values).unwrap(), 11);
+        assert_eq!(buf, ELF_LIKE_32);
+
+        // the 64 bit branch needs 64 bit values
+        values.insert("class".into(), AnyInt::U8(2).into());
+        assert!(elf_like().write(Vec::new(), &values).is_err());
+        values.remove("phoff");
+        values.insert("class".into(), AnyInt::U8(1).into());
+        assert!(elf_like().write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_if_duplicate_name() {
+        let writer = StructWriter::new_le()
+            .add_u8_field("class")
+            .add_u32_field("entry")
+            .add_if(
+                "class",
+                1u8,
+                StructWriter::new_le().add_u32_field("entry"),
+                None,
+            );
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("class".into(), AnyInt::U8(0).into());
+        values.insert("entry".into(), AnyInt::U32(0).into());
+        assert!(matches!(
+            writer.write(Vec::new(), &values),
+            Err(StreamError::InvalidPattern(msg)) if msg == "duplicate field name entry"
+        ));
+    }
+
+    #[test]
+    fn test_write_field_paths() {
+        let writer = StructWriter::new_le()
+            .add_struct_field("header", StructWriter::new_le().add_u8_field("count"))
+            .add_if(
+                "header.count",
+                1u8,
+                StructWriter::new_le().add_counted_bytes_field("data", "header.count"),
+                None,
+            );
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert(
+            "header".into(),
+            AnyValue::Struct(vec![("count".into(), AnyInt::U8(1).into())]),
+        );
+        values.insert("data".into(), AnyValue::Bytes(vec![0xAA]));
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 2);
+        assert_eq!(buf, [0x01, 0xAA]);
+
+        let writer = StructWriter::new_le().add_counted_bytes_field("data", "len");
+        values.insert("data".into(), AnyValue::Bytes(vec![0xAA]));
+        assert!(matches!(
+            writer.write(Vec::new(), &values),
+            Err(StreamError::InvalidPattern(msg)) if msg == "field len wasn't read"
+        ));
+    }
+
+    #[test]
+    fn test_write_switch() {
+        let writer = StructWriter::new_be()
+            .add_u8_field("kind")
+            .add_switch_field(
+                "body",
+                "kind",
+                vec![(AnyInt::U8(1), StructWriter::new_be().add_u16_field("x"))],
+                None,
+            );
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("kind".into(), AnyInt::U8(1).into());
+        values.insert(
+            "body".into(),
+            AnyValue::Struct(vec![("x".into(), AnyInt::U16(0x0203).into())]),
+        );
+
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 3);
+        assert_eq!(buf, [0x01, 0x02, 0x03]);
+        values.insert("kind".into(), AnyInt::U8(2).into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_endian_switch() {
+        let writer = StructWriter::new_le()
+            .add_u8_field("data")
+            .add_endian_switch(
+                "data",
+                &[
+                    (AnyInt::U8(1), Endianness::LittleEndian),
+                    (AnyInt::U8(2), Endianness::BigEndian),
+                ],
+            )
+            .add_u16_field("kind")
+            .add_endian_field("fixed", Endianness::LittleEndian, PatternReaderTokens::U16);
+        let mut values = HashMap::new();
+        values.insert("data".to_string(), AnyInt::U8(2));
+        values.insert("kind".to_string(), AnyInt::U16(3));
+        values.insert("fixed".to_string(), AnyInt::U16(4));
+
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 5);
+        assert_eq!(buf, [0x02, 0x00, 0x03, 0x04, 0x00]);
+
+        values.insert("data".to_string(), AnyInt::U8(3));
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_strings_round_trip() {
+        let writer = StructWriter::new_le()
+            .add_u8_field("len")
+            .add_lpstr_field("lpstr", LPWidth::LP16)
+            .add_cstr_field("cstr", 8)
+            .add_str_field("name", 6)
+            .add_counted_bytes_field("data", "len")
+            .add_lpbuf_field("lpbuf", LPWidth::LP8);
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("len".into(), 3u8.into());
+        values.insert("lpstr".into(), "hi".into());
+        values.insert("cstr".into(), "ab".into());
+        values.insert("name".into(), ".text".into());
+        values.insert("data".into(), vec![1u8, 2, 3].into());
+        values.insert("lpbuf".into(), vec![0xFFu8].into());
+
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 19);
+        let reader = StructReader::new_le()
+            .add_u8_field("len")
+            .add_lpstr_field("lpstr", LPWidth::LP16)
+            .add_cstr_field("cstr", 8)
+            .add_str_field("name", 6)
+            .add_counted_bytes_field("data", "len")
+            .add_lpbuf_field("lpbuf", LPWidth::LP8)
+            .read(Cursor::new(&buf))
+            .unwrap();
+        assert_eq!(reader.into_inner(), values);
+
+        values.insert("name".into(), ".textbss".into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("name".into(), ".text".into());
+        values.insert("cstr".into(), "a\0b".into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("cstr".into(), "ab".into());
+        values.insert("data".into(), vec![1u8, 2].into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("data".into(), "abc".into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_bits() {
+        let writer = StructWriter::new_be()
+            .add_bits_field("data_offset", 4)
+            .add_bits_field("reserved", 3)
+            .add_bits_field("flags", 9)
+            .add_u16_field("window")
+            .set_bit_order(BitOrder::LsbFirst)
+            .add_bits_field("seconds", 5)
+            .add_bits_field("minutes", 6)
+            .add_bits_field("hours", 5)
+            .add_bits_field("odd", 3)
+            .add_u8_field("end");
+        let mut values = HashMap::new();
+        for (name, v) in [
+            ("data_offset", AnyInt::U8(5)),
+            ("reserved", AnyInt::U8(0)),
+            ("flags", AnyInt::U16(0x12)),
+            ("window", AnyInt::U16(0x2000)),
+            ("seconds", AnyInt::U8(15)),
+            ("minutes", AnyInt::U8(45)),
+            ("hours", AnyInt::U8(13)),
+            ("odd", AnyInt::U8(5)),
+            ("end", AnyInt::U8(7)),
+        ] {
+            values.insert(name.to_string(), v);
+        }
+
+        let mut buf = Vec::new();
+        assert_eq!(writer.required_bytes(), 8);
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 8);
+        assert_eq!(buf, [0x50, 0x12, 0x20, 0x00, 0xAF, 0x6D, 0x05, 0x07]);
+
+        values.insert("reserved".to_string(), AnyInt::U8(8));
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("reserved".to_string(), AnyInt::I8(-1));
+        assert!(writer.write(Vec::new(), &values).is_err());
+    }
+
+    #[test]
+    fn test_write_enums() {
+        let writer = StructWriter::new_be()
+            .add_u16_enum_field("machine", &[(0x03, "x86"), (0x3E, "x86_64")])
+            .add_enum_field(
+                "class",
+                PatternReaderTokens::U8,
+                &[(1u8, "32"), (2, "64")],
+                UnknownValue::Error,
+            );
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("machine".into(), "x86_64".into());
+        values.insert("class".into(), AnyValue::Enum(AnyInt::U8(1), "32".into()));
+
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 3);
+        assert_eq!(buf, [0x00, 0x3E, 0x01]);
+
+        values.insert("machine".into(), AnyInt::U16(0x1234).into());
+        buf.clear();
+        writer.write(&mut buf, &values).unwrap();
+        assert_eq!(buf, [0x12, 0x34, 0x01]);
+
+        values.insert("machine".into(), "arm".into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+        values.insert("machine".into(), "x86".into());
+        values.insert("class".into(), AnyInt::U8(3).into());
+        assert!(writer.write(Vec::new(), &values).is_err());
+
+        let writer = StructWriter::new_le().add_enum_field(
+            "name",
+            PatternReaderTokens::CStr(8),
+            &[(1u8, "one")],
+            UnknownValue::KeepRaw,
+        );
+        let mut values: HashMap<String, AnyValue> = HashMap::new();
+        values.insert("name".into(), "one".into());
+        assert!(matches!(
+            writer.write(Vec::new(), &values),
+            Err(StreamError::InvalidPattern(msg))
+                if msg == "enums must wrap an integer token, not CStr(8)"
+        ));
+    }
+
+    #[test]
+    fn test_write_align() {
+        let mut buf = Vec::new();
+        let written = PatternWriter::new_le()
+            .add_u8()
+            .add_align(4)
+            .add_u16()
+            .add_align(4)
+            .add_u32()
+            .add_align(4)
+            .write_pattern(&mut buf, &[AnyInt::U8(1), AnyInt::U16(2), AnyInt::U32(3)])
+            .unwrap();
+        assert_eq!(written, 12);
+        assert_eq!(buf, [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+
+        assert!(matches!(
+            PatternWriter::new_le().add_align(0).write_pattern(Vec::new(), &[] as &[AnyInt]),
+            Err(StreamError::InvalidPattern(msg)) if msg == "can't align to 0 bytes"
+        ));
+
+        let mut pattern = PatternWriter::new_le();
+        pattern.pattern.push(PatternReaderTokens::SeekTo(0));
+        pattern.add_u8();
+        assert!(pattern.write_pattern(Vec::new(), &[AnyInt::U8(1)]).is_err());
+    }
+
+    #[test]
+    fn test_write_align_in_nested_struct() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0];
+        let reader = StructReader::new_le()
+            .add_u8_field("kind")
+            .add_align(4)
+            .add_struct_field(
+                "header",
+                StructReader::new_le().add_u8_field("version").add_align(8),
+            )
+            .add_u16_field("count");
+        let writer = StructWriter::new_le()
+            .add_u8_field("kind")
+            .add_align(4)
+            .add_struct_field(
+                "header",
+                StructWriter::new_le().add_u8_field("version").add_align(8),
+            )
+            .add_u16_field("count");
+
+        let values = reader.read(Cursor::new(data)).unwrap().into_inner();
+        let mut buf = Vec::new();
+        assert_eq!(writer.write(&mut buf, &values).unwrap(), 10);
+        assert_eq!(buf, data);
+    }
+}
@@ -0,0 +1,229 @@
+//! Module containing readers and writers of sub byte fields,
+//! e.g. the flags of a TCP header or an ID3 sync safe integer.
+
+use std::io::{Read, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use super::read::StreamResult;
+use super::StreamError;
+
+/// The order bits are packed into bytes.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy)]
+pub enum BitOrder {
+    /// The first bit is the most significant bit of a byte and values are read
+    /// most significant bit first, as in network protocols and MPEG streams.
+    MsbFirst,
+    /// The first bit is the least significant bit of a byte and values are read
+    /// least significant bit first, as in deflate streams.
+    LsbFirst,
+}
+
+/// Read values of any number of bits from a byte stream.
+///
+/// Bytes are read from the inner stream only when needed,
+/// the unread bits of the last byte are discarded by [`BitReader::align`].
+pub struct BitReader<R: Read> {
+    inner: R,
+    order: BitOrder,
+    byte: u8,
+    avail: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R, order: BitOrder) -> Self {
+        Self {
+            inner,
+            order,
+            byte: 0,
+            avail: 0,
+        }
+    }
+
+    pub fn read_bit(&mut self) -> StreamResult<bool> {
+        if self.avail == 0 {
+            self.byte = self.inner.read_u8()?;
+            self.avail = 8;
+        }
+        self.avail -= 1;
+        let bit = match self.order {
+            BitOrder::MsbFirst => self.byte >> self.avail,
+            BitOrder::LsbFirst => self.byte >> (7 - self.avail),
+        };
+        Ok(bit & 1 == 1)
+    }
+
+    /// Read a value of `nbits` bits.
+    ///
+    /// # Errors
+    /// * [`StreamError::InvalidPattern`] if `nbits` is greater than 64.
+    /// * The stream returns an error.
+    pub fn read_bits(&mut self, nbits: u32) -> StreamResult<u64> {
+        if nbits > 64 {
+            return Err(StreamError::InvalidPattern(format!(
+                "can't read {} bits into a u64",
+                nbits
+            )));
+        }
+        let mut v = 0u64;
+        for i in 0..nbits {
+            let bit = self.read_bit()? as u64;
+            match self.order {
+                BitOrder::MsbFirst => v = (v << 1) | bit,
+                BitOrder::LsbFirst => v |= bit << i,
+            }
+        }
+        Ok(v)
+    }
+
+    /// Discard the unread bits of the current byte.
+    pub fn align(&mut self) {
+        self.avail = 0;
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.avail == 0
+    }
+
+    /// Return the inner stream, the unread bits of the current byte are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Write values of any number of bits to a byte stream.
+///
+/// A byte is written to the inner stream once all its bits are set,
+/// call [`BitWriter::align`] or [`BitWriter::finish`] to write a partial byte.
+pub struct BitWriter<W: Write> {
+    inner: W,
+    order: BitOrder,
+    byte: u8,
+    used: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W, order: BitOrder) -> Self {
+        Self {
+            inner,
+            order,
+            byte: 0,
+            used: 0,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> StreamResult<()> {
+        let shift = match self.order {
+            BitOrder::MsbFirst => 7 - self.used,
+            BitOrder::LsbFirst => self.used,
+        };
+        self.byte |= (bit as u8) << shift;
+        self.used += 1;
+        if self.used == 8 {
+            self.inner.write_u8(self.byte)?;
+            self.byte = 0;
+            self.used = 0;
+        }
+        Ok(())
+    }
+
+    /// Write the low `nbits` bits of `value`.
+    ///
+    /// # Errors
+    /// * [`StreamError::InvalidPattern`] if `nbits` is greater than 64.
+    /// * An [`std::io::ErrorKind::InvalidInput`] error if `value` doesn't fit in `nbits` bits.
+    /// * The stream returns an error.
+    pub fn write_bits(&mut self, value: u64, nbits: u32) -> StreamResult<()> {
+        if nbits > 64 {
+            return Err(StreamError::InvalidPattern(format!(
+                "can't write {} bits from a u64",
+                nbits
+            )));
+        }
+        if nbits < 64 && value >> nbits != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} does not fit in {} bits", value, nbits),
+            )
+            .into());
+        }
+        for i in 0..nbits {
+            let shift = match self.order {
+                BitOrder::MsbFirst => nbits - 1 - i,
+                BitOrder::LsbFirst => i,
+            };
+            self.write_bit((value >> shift) & 1 == 1)?;
+        }
+        Ok(())
+    }
+
+    /// Write the current byte if it's partially set, the unset bits are zero.
+    pub fn align(&mut self) -> StreamResult<()> {
+        while self.used != 0 {
+            self.write_bit(false)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.used == 0
+    }
+
+    /// Align to a byte and return the inner stream.
+    pub fn finish(mut self) -> StreamResult<W> {
+        self.align()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_reader() {
+        let data = [0b1011_0010, 0b0111_0001];
+        let mut reader = BitReader::new(&data[..], BitOrder::MsbFirst);
+        assert!(reader.read_bit().unwrap());
+        assert_eq!(reader.read_bits(3).unwrap(), 0b011);
+        assert_eq!(reader.read_bits(8).unwrap(), 0b0010_0111);
+        assert!(!reader.is_aligned());
+        reader.align();
+        assert!(reader.read_bit().is_err());
+
+        let mut reader = BitReader::new(&data[..], BitOrder::LsbFirst);
+        assert!(!reader.read_bit().unwrap());
+        assert_eq!(reader.read_bits(3).unwrap(), 0b001);
+        assert_eq!(reader.read_bits(8).unwrap(), 0b0001_1011);
+        assert_eq!(reader.read_bits(0).unwrap(), 0);
+        assert!(reader.read_bits(65).is_err());
+    }
+
+    #[test]
+    fn test_bit_writer() {
+        for order in [BitOrder::MsbFirst, BitOrder::LsbFirst] {
+            let mut writer = BitWriter::new(Vec::new(), order);
+            writer.write_bit(true).unwrap();
+            writer.write_bits(0b011, 3).unwrap();
+            writer.write_bits(0x1FF, 9).unwrap();
+            writer.write_bits(u64::MAX, 64).unwrap();
+            assert!(writer.write_bits(4, 2).is_err());
+            let buf = writer.finish().unwrap();
+            assert_eq!(buf.len(), 10);
+
+            let mut reader = BitReader::new(&buf[..], order);
+            assert!(reader.read_bit().unwrap());
+            assert_eq!(reader.read_bits(3).unwrap(), 0b011);
+            assert_eq!(reader.read_bits(9).unwrap(), 0x1FF);
+            assert_eq!(reader.read_bits(64).unwrap(), u64::MAX);
+            assert_eq!(reader.read_bits(3).unwrap(), 0);
+        }
+
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::MsbFirst);
+        writer.write_bits(0b101, 3).unwrap();
+        assert_eq!(writer.finish().unwrap(), [0b1010_0000]);
+        let mut writer = BitWriter::new(Vec::new(), BitOrder::LsbFirst);
+        writer.write_bits(0b101, 3).unwrap();
+        assert_eq!(writer.finish().unwrap(), [0b0000_0101]);
+    }
+}
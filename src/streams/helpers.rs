@@ -1,7 +1,7 @@
 use crate::streams::read::StreamResult;
-use crate::streams::{Endianness, LPWidth};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
-use std::io::Read;
+use crate::streams::{Endianness, LPWidth, StreamError};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 pub(crate) fn read_lpend<S: Read>(
     mut stream: S,
@@ -23,3 +23,223 @@ pub(crate) fn read_lpend<S: Read>(
         },
     })
 }
+
+pub(crate) fn write_lpend<S: Write>(
+    mut stream: S,
+    lptype: LPWidth,
+    lpend: Endianness,
+    len: usize,
+) -> Result<u64, std::io::Error> {
+    if !LPWidth::usize_fits(lptype, len) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Length prefix does not fit in specified width",
+        ));
+    }
+    match lpend {
+        Endianness::BigEndian => match lptype {
+            LPWidth::LP8 => stream.write_u8(len as u8)?,
+            LPWidth::LP16 => stream.write_u16::<BigEndian>(len as u16)?,
+            LPWidth::LP32 => stream.write_u32::<BigEndian>(len as u32)?,
+            LPWidth::LP64 => stream.write_u64::<BigEndian>(len as u64)?,
+        },
+        Endianness::LittleEndian => match lptype {
+            LPWidth::LP8 => stream.write_u8(len as u8)?,
+            LPWidth::LP16 => stream.write_u16::<LittleEndian>(len as u16)?,
+            LPWidth::LP32 => stream.write_u32::<LittleEndian>(len as u32)?,
+            LPWidth::LP64 => stream.write_u64::<LittleEndian>(len as u64)?,
+        },
+    }
+    Ok(lptype.size() as u64)
+}
+
+type SeekFn<R> = fn(&mut R, SeekFrom) -> std::io::Result<u64>;
+
+/// Read exactly `len` bytes, the buffer grows as the bytes are read so a length
+/// read from the stream can't allocate more than the stream holds.
+///
+/// # Errors
+/// An [`std::io::ErrorKind::UnexpectedEof`] error if the stream ends before `len` bytes.
+pub(crate) fn read_len<S: Read>(stream: S, len: usize) -> StreamResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    stream.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "expected {} bytes but the stream ended after {}",
+                len,
+                buf.len()
+            ),
+        )
+        .into());
+    }
+    Ok(buf)
+}
+
+/// A reader that counts the bytes read through it.
+///
+/// A reader created with [`CountingReader::seekable`] can also move around the stream,
+/// the count is then the offset from the position the read started at.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: u64,
+    /// The position of `inner` when the read started and its seek function.
+    seek: Option<(u64, SeekFn<R>)>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            count: 0,
+            seek: None,
+        }
+    }
+
+    pub(crate) fn seekable(mut inner: R) -> StreamResult<Self>
+    where
+        R: Seek,
+    {
+        let start = inner.stream_position()?;
+        Ok(Self {
+            inner,
+            count: 0,
+            seek: Some((start, R::seek)),
+        })
+    }
+
+    /// The number of bytes read so far, or the current offset for a seekable reader.
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Move to `offset` counted from the position the read started at.
+    ///
+    /// # Errors
+    /// [`StreamError::InvalidPattern`] if the reader isn't seekable.
+    pub(crate) fn seek_to(&mut self, offset: u64) -> StreamResult<()> {
+        let (start, seek) = self.seek.ok_or_else(|| {
+            StreamError::InvalidPattern(
+                "seeking requires a SeekRead stream, see read_pattern_seek and parse_seek".into(),
+            )
+        })?;
+        let pos = start.checked_add(offset).ok_or_else(|| {
+            StreamError::InvalidPattern(format!("offset {} is out of range", offset))
+        })?;
+        seek(&mut self.inner, SeekFrom::Start(pos))?;
+        self.count = offset;
+        Ok(())
+    }
+
+    /// Move `delta` bytes from the current offset, backwards if negative.
+    pub(crate) fn seek_by(&mut self, delta: i64) -> StreamResult<()> {
+        let offset = self.count.checked_add_signed(delta).ok_or_else(|| {
+            StreamError::InvalidPattern(format!(
+                "can't skip {} bytes from offset {}",
+                delta, self.count
+            ))
+        })?;
+        self.seek_to(offset)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Convert the bits of an IEEE 754 half precision float to an `f32`, this is exact.
+pub(crate) fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1F) as u32;
+    let man = (h & 0x3FF) as u32;
+    let bits = match (exp, man) {
+        (0, 0) => sign,
+        (0, man) => {
+            // subnormal, the value is man * 2^-24 which is a normal f32
+            let v = man as f32 / (1 << 24) as f32;
+            return if sign != 0 { -v } else { v };
+        }
+        (0x1F, 0) => sign | 0x7F80_0000,
+        (0x1F, man) => sign | 0x7FC0_0000 | (man << 13),
+        (exp, man) => sign | ((exp + 127 - 15) << 23) | (man << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// Convert an `f32` to the bits of an IEEE 754 half precision float,
+/// rounding to the nearest representable value, ties to even.
+pub(crate) fn f32_to_f16(v: f32) -> u16 {
+    let x = v.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xFF) as i32;
+    let man = x & 0x7F_FFFF;
+
+    if exp == 0xFF {
+        let nan = if man != 0 {
+            0x200 | (man >> 13) as u16
+        } else {
+            0
+        };
+        return sign | 0x7C00 | nan;
+    }
+
+    let exp = exp - 127 + 15;
+    if exp >= 0x1F {
+        return sign | 0x7C00;
+    }
+
+    let round = |v: u32, shift: u32| {
+        let rem = v & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let v = v >> shift;
+        if rem > halfway || (rem == halfway && v & 1 == 1) {
+            v + 1
+        } else {
+            v
+        }
+    };
+
+    if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        // subnormal, include the implicit bit and shift into place
+        return sign | round(man | 0x80_0000, (14 - exp) as u32) as u16;
+    }
+    // a carry out of the mantissa correctly bumps the exponent, up to infinity
+    sign | round(((exp as u32) << 23) | man, 13) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f16_conversions() {
+        let cases: [(u16, f32); 9] = [
+            (0x0000, 0.0),
+            (0x8000, -0.0),
+            (0x3C00, 1.0),
+            (0xC000, -2.0),
+            (0x7BFF, 65504.0),
+            (0x0001, 5.960_464_5e-8),
+            (0x03FF, 6.097_555e-5),
+            (0x7C00, f32::INFINITY),
+            (0xFC00, f32::NEG_INFINITY),
+        ];
+        for (bits, v) in cases {
+            assert_eq!(f16_to_f32(bits), v);
+            assert_eq!(f32_to_f16(v), bits);
+        }
+        assert_eq!(f32_to_f16(0.1), 0x2E66);
+        assert_eq!(f32_to_f16(65520.0), 0x7C00);
+        assert_eq!(f32_to_f16(1e-9), 0x0000);
+        assert!(f16_to_f32(0x7E00).is_nan());
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    }
+}
@@ -1,11 +1,15 @@
 //! This module has utilities for reading and writing to streams
 //! of binary data see [`mod@read`] and [`mod@write`] for more information.
 
-use byteorder::WriteBytesExt;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use helpers::f32_to_f16;
+use read::StreamResult;
 use std::io::{Cursor, Read, Seek, Write};
 use thiserror::Error;
 
 pub mod advanced_readers;
+pub mod advanced_writers;
+pub mod bits;
 mod helpers;
 pub mod read;
 pub mod write;
@@ -26,7 +30,10 @@ impl<T: Read + Seek> SeekRead for T {}
 impl<T: Write + Seek> SeekWrite for T {}
 impl<T: Read + Write + Seek> SeekReadWrite for T {}
 
+/// Errors returned by the stream functions,
+/// new variants may be added in minor releases so matches need a wildcard arm.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum StreamError {
     #[error("Stream error: {0}")]
     StreamError(String),
@@ -36,8 +43,40 @@ pub enum StreamError {
     InvalidPattern(String),
     #[error("Stream error: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("Value {0:?} does not fit in {1:?}")]
+    Overflow(AnyInt, AnyIntKind),
+    #[error("Arithmetic overflow or division by zero: {0:?} {1} {2:?}")]
+    Arithmetic(AnyInt, &'static str, AnyInt),
+    /// A checked field has an invalid value, `field` is the dot separated path of the
+    /// field and `offset` is counted from the start of the read.
+    #[error("Invalid value {value:?} for field {field} at offset {offset}, expected {expected}")]
+    Validation {
+        field: String,
+        offset: u64,
+        value: Box<AnyValue>,
+        expected: String,
+    },
 }
 
+/// Version of the map encoding used by [`write::write_map`] and [`read::read_map`].
+///
+/// A map is encoded as follows, all multi byte integers use the endianness
+/// passed to the functions and `lp` is an integer of the given [`LPWidth`]:
+///
+/// | Field   | Size        | Description                                  |
+/// |---------|-------------|----------------------------------------------|
+/// | version | 1           | always `MAP_FORMAT_VERSION`                  |
+/// | count   | lp          | the number of entries                        |
+/// | entries | ...         | `count` entries, one after the other         |
+///
+/// And each entry is encoded as:
+///
+/// | Field   | Size        | Description                                  |
+/// |---------|-------------|----------------------------------------------|
+/// | key     | lp + len    | the key as a length prefixed UTF-8 string    |
+/// | value   | 1 + ser_size| the value in the tagged encoding, see [`AnyInt::write_tagged`] |
+pub const MAP_FORMAT_VERSION: u8 = 1;
+
 /// The endianness of a stream
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy)]
 pub enum Endianness {
@@ -475,6 +514,156 @@ impl AnyInt {
         }
     }
 
+    /// Type tag of the variant used by the tagged encoding, see [`AnyIntKind::tag`].
+    pub fn tag(&self) -> u8 {
+        self.kind().tag()
+    }
+
+    /// The variant of the integer.
+    pub fn kind(&self) -> AnyIntKind {
+        match self {
+            AnyInt::U8(_) => AnyIntKind::U8,
+            AnyInt::U16(_) => AnyIntKind::U16,
+            AnyInt::U32(_) => AnyIntKind::U32,
+            AnyInt::U48(_) => AnyIntKind::U48,
+            AnyInt::U64(_) => AnyIntKind::U64,
+            AnyInt::U128(_) => AnyIntKind::U128,
+            AnyInt::I8(_) => AnyIntKind::I8,
+            AnyInt::I16(_) => AnyIntKind::I16,
+            AnyInt::I32(_) => AnyIntKind::I32,
+            AnyInt::I48(_) => AnyIntKind::I48,
+            AnyInt::I64(_) => AnyIntKind::I64,
+            AnyInt::I128(_) => AnyIntKind::I128,
+            AnyInt::Bool(_) => AnyIntKind::Bool,
+        }
+    }
+
+    /// Parse an integer of the given `kind` from it's serialized form,
+    /// this is the inverse of [`AnyInt::to_bytes_le`] and [`AnyInt::to_bytes_be`].
+    ///
+    /// # Errors
+    /// This function will return an error if `bytes` is not exactly [`AnyIntKind::ser_size`] bytes long.
+    pub fn from_bytes(
+        kind: AnyIntKind,
+        bytes: &[u8],
+        endianness: Endianness,
+    ) -> StreamResult<AnyInt> {
+        if bytes.len() != kind.ser_size() {
+            return Err(StreamError::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} requires {} bytes, got {}",
+                    kind,
+                    kind.ser_size(),
+                    bytes.len()
+                ),
+            )));
+        }
+        read::read_anyint(bytes, kind, endianness)
+    }
+
+    /// Write the integer in the self describing tagged encoding,
+    /// a one byte type tag (see [`AnyIntKind::tag`]) followed by the value
+    /// in `endianness` as written by [`AnyInt::to_bytes_le`] or [`AnyInt::to_bytes_be`].
+    ///
+    /// # Returns
+    /// The number of bytes written, the tag byte plus [`AnyInt::ser_size`].
+    pub fn write_tagged<W: Write>(
+        &self,
+        mut writer: W,
+        endianness: Endianness,
+    ) -> Result<u64, std::io::Error> {
+        writer.write_u8(self.tag())?;
+        match endianness {
+            Endianness::LittleEndian => writer.write_all(&self.to_bytes_le())?,
+            Endianness::BigEndian => writer.write_all(&self.to_bytes_be())?,
+        }
+        Ok(1 + self.ser_size() as u64)
+    }
+
+    /// Read an integer written by [`AnyInt::write_tagged`],
+    /// the variant is restored from the tag.
+    ///
+    /// # Errors
+    /// This function will return an error if the tag is unknown or the stream returns an error.
+    pub fn read_tagged<R: Read>(mut reader: R, endianness: Endianness) -> StreamResult<AnyInt> {
+        let tag = reader.read_u8()?;
+        Self::read_tagged_payload(reader, tag, endianness)
+    }
+
+    /// Read the value that follows an already read `tag`.
+    pub(crate) fn read_tagged_payload<R: Read>(
+        reader: R,
+        tag: u8,
+        endianness: Endianness,
+    ) -> StreamResult<AnyInt> {
+        match AnyIntKind::from_tag(tag) {
+            Some(kind) => read::read_anyint(reader, kind, endianness),
+            None => Err(StreamError::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown value type tag {}", tag),
+            ))),
+        }
+    }
+
+    /// The numeric value widened to 128 bits, booleans are 0 or 1.
+    fn wide(&self) -> Wide {
+        match *self {
+            AnyInt::U8(v) => Wide::Unsigned(v as u128),
+            AnyInt::U16(v) => Wide::Unsigned(v as u128),
+            AnyInt::U32(v) => Wide::Unsigned(v as u128),
+            AnyInt::U48(v) => Wide::Unsigned(v as u128),
+            AnyInt::U64(v) => Wide::Unsigned(v as u128),
+            AnyInt::U128(v) => Wide::Unsigned(v),
+            AnyInt::I8(v) => Wide::Signed(v as i128),
+            AnyInt::I16(v) => Wide::Signed(v as i128),
+            AnyInt::I32(v) => Wide::Signed(v as i128),
+            AnyInt::I48(v) => Wide::Signed(v as i128),
+            AnyInt::I64(v) => Wide::Signed(v as i128),
+            AnyInt::I128(v) => Wide::Signed(v),
+            AnyInt::Bool(v) => Wide::Unsigned(v as u128),
+        }
+    }
+
+    /// Convert the value to another kind of integer,
+    /// unlike the [`TryFrom`] impls this succeeds whenever the value fits the
+    /// target kind regardless of the current variant, e.g. `U8(1)` as `I64` is `I64(1)`.
+    /// `Bool` is treated as 0 or 1 and only those values can be converted to `Bool`.
+    ///
+    /// # Errors
+    /// This function will return [`StreamError::Overflow`] if the value doesn't fit in `kind`.
+    pub fn as_width(&self, kind: AnyIntKind) -> StreamResult<AnyInt> {
+        const U48_MAX: u64 = (1 << 48) - 1;
+        const I48_MIN: i64 = -(1 << 47);
+        const I48_MAX: i64 = (1 << 47) - 1;
+
+        let overflow = || StreamError::Overflow(*self, kind);
+        Ok(match kind {
+            AnyIntKind::U8 => AnyInt::U8(self.to_u8_checked()?),
+            AnyIntKind::U16 => AnyInt::U16(self.to_u16_checked()?),
+            AnyIntKind::U32 => AnyInt::U32(self.to_u32_checked()?),
+            AnyIntKind::U48 => match self.to_u64_checked() {
+                Ok(v) if v <= U48_MAX => AnyInt::U48(v),
+                _ => return Err(overflow()),
+            },
+            AnyIntKind::U64 => AnyInt::U64(self.to_u64_checked()?),
+            AnyIntKind::U128 => AnyInt::U128(self.to_u128_checked()?),
+            AnyIntKind::I8 => AnyInt::I8(self.to_i8_checked()?),
+            AnyIntKind::I16 => AnyInt::I16(self.to_i16_checked()?),
+            AnyIntKind::I32 => AnyInt::I32(self.to_i32_checked()?),
+            AnyIntKind::I48 => match self.to_i64_checked() {
+                Ok(v) if (I48_MIN..=I48_MAX).contains(&v) => AnyInt::I48(v),
+                _ => return Err(overflow()),
+            },
+            AnyIntKind::I64 => AnyInt::I64(self.to_i64_checked()?),
+            AnyIntKind::I128 => AnyInt::I128(self.to_i128_checked()?),
+            AnyIntKind::Bool => match self.to_u8_checked() {
+                Ok(v @ (0 | 1)) => AnyInt::Bool(v == 1),
+                _ => return Err(overflow()),
+            },
+        })
+    }
+
     fn write_u48(v: u64, endianness: Endianness) -> Vec<u8> {
         let mut buf = [0u8; 8];
         let mut cur = Cursor::new(&mut buf[..]);
@@ -506,6 +695,297 @@ impl AnyInt {
     }
 }
 
+/// The value of an [`AnyInt`] widened to 128 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Wide {
+    Unsigned(u128),
+    Signed(i128),
+}
+
+impl Wide {
+    fn to_i128(self) -> Option<i128> {
+        match self {
+            Wide::Unsigned(v) => i128::try_from(v).ok(),
+            Wide::Signed(v) => Some(v),
+        }
+    }
+}
+
+macro_rules! impl_checked_conversion {
+    ($t:ident, $kind:ident) => {
+        impl_checked_conversion!($t, kind AnyIntKind::$kind);
+    };
+    ($t:ident, kind $kind:expr) => {
+        paste::item! {
+            #[doc = "Convert the value to a `" $t "` if it fits, regardless of the variant."]
+            ///
+            /// # Errors
+            /// This function will return [`StreamError::Overflow`] if the value doesn't fit.
+            pub fn [<to_ $t _checked>](&self) -> StreamResult<$t> {
+                let v = match self.wide() {
+                    Wide::Unsigned(v) => $t::try_from(v).ok(),
+                    Wide::Signed(v) => $t::try_from(v).ok(),
+                };
+                v.ok_or(StreamError::Overflow(*self, $kind))
+            }
+        }
+    };
+}
+
+macro_rules! impl_checked_arith {
+    ($name:ident, $op:literal, bit $tok:tt) => {
+        impl_checked_arith!($name, $op, |a, b| Some(a $tok b), |a, b| Some(a $tok b));
+    };
+    ($name:ident, $op:literal, $method:ident) => {
+        impl_checked_arith!($name, $op, |a, b| a.$method(b), |a, b| a.$method(b));
+    };
+    ($name:ident, $op:literal, $unsigned:expr, $signed:expr) => {
+        #[doc = concat!("Checked `", $op, "` of two integers of any kind.")]
+        ///
+        /// The operands are promoted to a common kind first, see [`AnyIntKind::promote`],
+        /// and the result is of that kind.
+        ///
+        /// # Errors
+        /// This function will return [`StreamError::Arithmetic`] if the result doesn't fit
+        /// in the promoted kind or on division by zero.
+        pub fn $name(self, rhs: AnyInt) -> StreamResult<AnyInt> {
+            let kind = AnyIntKind::promote(self.kind(), rhs.kind());
+            let err = || StreamError::Arithmetic(self, $op, rhs);
+            let unsigned: fn(u128, u128) -> Option<u128> = $unsigned;
+            let signed: fn(i128, i128) -> Option<i128> = $signed;
+            let v = match (self.wide(), rhs.wide()) {
+                (Wide::Unsigned(a), Wide::Unsigned(b)) if !kind.is_signed() => {
+                    AnyInt::U128(unsigned(a, b).ok_or_else(err)?)
+                }
+                (a, b) => {
+                    let a = a.to_i128().ok_or_else(err)?;
+                    let b = b.to_i128().ok_or_else(err)?;
+                    AnyInt::I128(signed(a, b).ok_or_else(err)?)
+                }
+            };
+            v.as_width(kind).map_err(|_| err())
+        }
+    };
+}
+
+impl AnyInt {
+    impl_checked_arith!(checked_add, "+", checked_add);
+    impl_checked_arith!(checked_sub, "-", checked_sub);
+    impl_checked_arith!(checked_mul, "*", checked_mul);
+    impl_checked_arith!(checked_div, "/", checked_div);
+    impl_checked_arith!(checked_rem, "%", checked_rem);
+    impl_checked_arith!(checked_and, "&", bit &);
+    impl_checked_arith!(checked_or, "|", bit |);
+    impl_checked_arith!(checked_xor, "^", bit ^);
+
+    /// Checked left shift, the result has the kind of `self` (`Bool` is shifted as `U8`).
+    ///
+    /// # Errors
+    /// This function will return an error if `rhs` is negative or not smaller than the
+    /// width of `self`, or if set bits are shifted out of the value.
+    pub fn checked_shl(self, rhs: AnyInt) -> StreamResult<AnyInt> {
+        let (kind, sh) = self.shift_params(rhs, "<<")?;
+        let err = || StreamError::Arithmetic(self, "<<", rhs);
+        // shifting back must restore the value if no set bits were lost
+        let v = match self.wide() {
+            Wide::Unsigned(v) if (v << sh) >> sh == v => AnyInt::U128(v << sh),
+            Wide::Signed(v) if (v << sh) >> sh == v => AnyInt::I128(v << sh),
+            _ => return Err(err()),
+        };
+        v.as_width(kind).map_err(|_| err())
+    }
+
+    /// Checked right shift, the result has the kind of `self` (`Bool` is shifted as `U8`),
+    /// signed values are shifted arithmetically.
+    ///
+    /// # Errors
+    /// This function will return an error if `rhs` is negative or not smaller than the width of `self`.
+    pub fn checked_shr(self, rhs: AnyInt) -> StreamResult<AnyInt> {
+        let (kind, sh) = self.shift_params(rhs, ">>")?;
+        match self.wide() {
+            Wide::Unsigned(v) => AnyInt::U128(v >> sh),
+            Wide::Signed(v) => AnyInt::I128(v >> sh),
+        }
+        .as_width(kind)
+    }
+
+    fn shift_params(self, rhs: AnyInt, op: &'static str) -> StreamResult<(AnyIntKind, u32)> {
+        let kind = match self.kind() {
+            AnyIntKind::Bool => AnyIntKind::U8,
+            kind => kind,
+        };
+        match rhs.to_u32_checked() {
+            Ok(sh) if sh < kind.ser_size() as u32 * 8 => Ok((kind, sh)),
+            _ => Err(StreamError::Arithmetic(self, op, rhs)),
+        }
+    }
+
+    /// Compare the numeric values of two integers of any kind,
+    /// unlike the derived [`Ord`] which orders by variant first.
+    pub fn num_cmp(&self, other: &AnyInt) -> std::cmp::Ordering {
+        match (self.wide(), other.wide()) {
+            (Wide::Unsigned(a), Wide::Unsigned(b)) => a.cmp(&b),
+            (Wide::Signed(a), Wide::Signed(b)) => a.cmp(&b),
+            (Wide::Signed(a), Wide::Unsigned(b)) => match u128::try_from(a) {
+                Ok(a) => a.cmp(&b),
+                Err(_) => std::cmp::Ordering::Less,
+            },
+            (Wide::Unsigned(a), Wide::Signed(b)) => match u128::try_from(b) {
+                Ok(b) => a.cmp(&b),
+                Err(_) => std::cmp::Ordering::Greater,
+            },
+        }
+    }
+
+    /// Check if two integers of any kind have the same numeric value,
+    /// e.g. `U8(1)` and `I64(1)` are equal.
+    pub fn num_eq(&self, other: &AnyInt) -> bool {
+        self.num_cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl AnyInt {
+    impl_checked_conversion!(u8, U8);
+    impl_checked_conversion!(u16, U16);
+    impl_checked_conversion!(u32, U32);
+    impl_checked_conversion!(u64, U64);
+    impl_checked_conversion!(u128, U128);
+    impl_checked_conversion!(i8, I8);
+    impl_checked_conversion!(i16, I16);
+    impl_checked_conversion!(i32, I32);
+    impl_checked_conversion!(i64, I64);
+    impl_checked_conversion!(i128, I128);
+    impl_checked_conversion!(usize, kind USIZE_KIND);
+}
+
+/// The kind with the width of `usize` on the target, used to report `usize` overflows.
+const USIZE_KIND: AnyIntKind = match usize::BITS {
+    16 => AnyIntKind::U16,
+    32 => AnyIntKind::U32,
+    _ => AnyIntKind::U64,
+};
+
+/// The variant of an [`AnyInt`] without it's value,
+/// used to tell readers which kind of integer to parse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AnyIntKind {
+    U8,
+    U16,
+    U32,
+    U48,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I48,
+    I64,
+    I128,
+    Bool,
+}
+
+impl AnyIntKind {
+    /// Whether integers of this kind can hold negative values.
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            AnyIntKind::I8
+                | AnyIntKind::I16
+                | AnyIntKind::I32
+                | AnyIntKind::I48
+                | AnyIntKind::I64
+                | AnyIntKind::I128
+        )
+    }
+
+    /// The kind two operands are promoted to by the arithmetic operations on [`AnyInt`].
+    ///
+    /// The rules are:
+    /// * `Bool` is treated as `U8`.
+    /// * If both kinds have the same signedness, the wider kind is used.
+    /// * Otherwise the result is the narrowest signed kind that is wider than the
+    ///   unsigned kind and at least as wide as the signed kind, e.g. `U8` and `I8`
+    ///   promote to `I16`, `U32` and `I64` to `I64`, anything with `U128` to `I128`.
+    pub fn promote(a: AnyIntKind, b: AnyIntKind) -> AnyIntKind {
+        const SIGNED: [AnyIntKind; 6] = [
+            AnyIntKind::I8,
+            AnyIntKind::I16,
+            AnyIntKind::I32,
+            AnyIntKind::I48,
+            AnyIntKind::I64,
+            AnyIntKind::I128,
+        ];
+        let a = if a == AnyIntKind::Bool {
+            AnyIntKind::U8
+        } else {
+            a
+        };
+        let b = if b == AnyIntKind::Bool {
+            AnyIntKind::U8
+        } else {
+            b
+        };
+
+        match (a.is_signed(), b.is_signed()) {
+            (true, true) | (false, false) => {
+                if a.ser_size() >= b.ser_size() {
+                    a
+                } else {
+                    b
+                }
+            }
+            (signed, _) => {
+                let (s, u) = if signed { (a, b) } else { (b, a) };
+                SIGNED
+                    .into_iter()
+                    .find(|k| k.ser_size() > u.ser_size() && k.ser_size() >= s.ser_size())
+                    .unwrap_or(AnyIntKind::I128)
+            }
+        }
+    }
+
+    const ALL: [AnyIntKind; 13] = [
+        AnyIntKind::U8,
+        AnyIntKind::U16,
+        AnyIntKind::U32,
+        AnyIntKind::U48,
+        AnyIntKind::U64,
+        AnyIntKind::U128,
+        AnyIntKind::I8,
+        AnyIntKind::I16,
+        AnyIntKind::I32,
+        AnyIntKind::I48,
+        AnyIntKind::I64,
+        AnyIntKind::I128,
+        AnyIntKind::Bool,
+    ];
+
+    /// Size of an integer of this kind when serialized
+    pub fn ser_size(&self) -> usize {
+        match self {
+            AnyIntKind::U8 | AnyIntKind::I8 | AnyIntKind::Bool => 1,
+            AnyIntKind::U16 | AnyIntKind::I16 => 2,
+            AnyIntKind::U32 | AnyIntKind::I32 => 4,
+            AnyIntKind::U48 | AnyIntKind::I48 => 6,
+            AnyIntKind::U64 | AnyIntKind::I64 => 8,
+            AnyIntKind::U128 | AnyIntKind::I128 => 16,
+        }
+    }
+
+    /// Type tag used by the tagged encoding, see [`AnyInt::write_tagged`].
+    ///
+    /// The tags are, in order starting at 0: `U8`, `U16`, `U32`, `U48`, `U64`, `U128`,
+    /// `I8`, `I16`, `I32`, `I48`, `I64`, `I128` and `Bool`.
+    pub fn tag(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The kind with the given type tag, if any.
+    pub fn from_tag(tag: u8) -> Option<AnyIntKind> {
+        Self::ALL.get(tag as usize).copied()
+    }
+}
+
 impl From<u8> for AnyInt {
     fn from(v: u8) -> Self {
         AnyInt::U8(v)
@@ -728,6 +1208,354 @@ impl TryFrom<AnyInt> for bool {
     }
 }
 
+/// A value read from or written to a stream, either an integer, a floating point number,
+/// a string, a byte buffer, or a list or struct of other values.
+///
+/// `F16` is an IEEE 754 half precision float, it's widened to an `f32` in memory
+/// but serialized as 2 bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnyValue {
+    Int(AnyInt),
+    /// An integer and its name in the value table of an enum field.
+    Enum(AnyInt, String),
+    F16(f32),
+    F32(f32),
+    F64(f64),
+    /// A UTF-8 string, without its length prefix or NUL terminator.
+    Str(String),
+    /// A byte buffer, without its length prefix.
+    Bytes(Vec<u8>),
+    /// The elements of an array field.
+    List(Vec<AnyValue>),
+    /// The fields of a sub struct, in declaration order.
+    Struct(Vec<(String, AnyValue)>),
+}
+
+impl AnyValue {
+    pub fn to_bytes_le(&self) -> Vec<u8> {
+        match self {
+            AnyValue::Int(v) | AnyValue::Enum(v, _) => v.to_bytes_le(),
+            AnyValue::F16(v) => f32_to_f16(*v).to_le_bytes().to_vec(),
+            AnyValue::F32(v) => v.to_le_bytes().to_vec(),
+            AnyValue::F64(v) => v.to_le_bytes().to_vec(),
+            AnyValue::Str(v) => v.as_bytes().to_vec(),
+            AnyValue::Bytes(v) => v.clone(),
+            AnyValue::List(l) => l.iter().flat_map(|v| v.to_bytes_le()).collect(),
+            AnyValue::Struct(s) => s.iter().flat_map(|(_, v)| v.to_bytes_le()).collect(),
+        }
+    }
+
+    pub fn to_bytes_be(&self) -> Vec<u8> {
+        match self {
+            AnyValue::Int(v) | AnyValue::Enum(v, _) => v.to_bytes_be(),
+            AnyValue::F16(v) => f32_to_f16(*v).to_be_bytes().to_vec(),
+            AnyValue::F32(v) => v.to_be_bytes().to_vec(),
+            AnyValue::F64(v) => v.to_be_bytes().to_vec(),
+            AnyValue::Str(v) => v.as_bytes().to_vec(),
+            AnyValue::Bytes(v) => v.clone(),
+            AnyValue::List(l) => l.iter().flat_map(|v| v.to_bytes_be()).collect(),
+            AnyValue::Struct(s) => s.iter().flat_map(|(_, v)| v.to_bytes_be()).collect(),
+        }
+    }
+
+    /// Size of the value when serialized, strings and buffers are counted
+    /// without a length prefix or terminator.
+    pub fn ser_size(&self) -> usize {
+        match self {
+            AnyValue::Int(v) | AnyValue::Enum(v, _) => v.ser_size(),
+            AnyValue::F16(_) => 2,
+            AnyValue::F32(_) => 4,
+            AnyValue::F64(_) => 8,
+            AnyValue::Str(v) => v.len(),
+            AnyValue::Bytes(v) => v.len(),
+            AnyValue::List(l) => l.iter().map(AnyValue::ser_size).sum(),
+            AnyValue::Struct(s) => s.iter().map(|(_, v)| v.ser_size()).sum(),
+        }
+    }
+
+    /// The integer if this is an integer value, for enum values this is the raw value.
+    pub fn as_int(&self) -> Option<AnyInt> {
+        match self {
+            AnyValue::Int(v) | AnyValue::Enum(v, _) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// The name if this is an enum value.
+    pub fn enum_name(&self) -> Option<&str> {
+        match self {
+            AnyValue::Enum(_, name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The value as an `f64` if this is a floating point value.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            AnyValue::F16(v) | AnyValue::F32(v) => Some(*v as f64),
+            AnyValue::F64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// The string if this is a string value.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            AnyValue::Str(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The bytes if this is a string or byte buffer value.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            AnyValue::Str(v) => Some(v.as_bytes()),
+            AnyValue::Bytes(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The elements if this is a list value.
+    pub fn as_list(&self) -> Option<&[AnyValue]> {
+        match self {
+            AnyValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// The fields if this is a struct value.
+    pub fn as_struct(&self) -> Option<&[(String, AnyValue)]> {
+        match self {
+            AnyValue::Struct(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Get a nested value by a dot separated path, struct fields are addressed
+    /// by name and list elements by index, e.g. `"entries.2.len"`.
+    ///
+    /// An empty path returns the value itself.
+    pub fn get(&self, path: &str) -> Option<&AnyValue> {
+        if path.is_empty() {
+            return Some(self);
+        }
+        let (head, rest) = path.split_once('.').unwrap_or((path, ""));
+        let v = match self {
+            AnyValue::Struct(s) => s.iter().find(|(n, _)| n == head).map(|(_, v)| v),
+            AnyValue::List(l) => head.parse::<usize>().ok().and_then(|i| l.get(i)),
+            _ => None,
+        }?;
+        v.get(rest)
+    }
+}
+
+impl From<AnyInt> for AnyValue {
+    fn from(v: AnyInt) -> Self {
+        AnyValue::Int(v)
+    }
+}
+
+impl From<f32> for AnyValue {
+    fn from(v: f32) -> Self {
+        AnyValue::F32(v)
+    }
+}
+
+impl From<f64> for AnyValue {
+    fn from(v: f64) -> Self {
+        AnyValue::F64(v)
+    }
+}
+
+impl From<String> for AnyValue {
+    fn from(v: String) -> Self {
+        AnyValue::Str(v)
+    }
+}
+
+impl From<&str> for AnyValue {
+    fn from(v: &str) -> Self {
+        AnyValue::Str(v.to_string())
+    }
+}
+
+impl From<Vec<u8>> for AnyValue {
+    fn from(v: Vec<u8>) -> Self {
+        AnyValue::Bytes(v)
+    }
+}
+
+impl From<&[u8]> for AnyValue {
+    fn from(v: &[u8]) -> Self {
+        AnyValue::Bytes(v.to_vec())
+    }
+}
+
+impl PartialEq<AnyInt> for AnyValue {
+    fn eq(&self, other: &AnyInt) -> bool {
+        self.as_int().as_ref() == Some(other)
+    }
+}
+
+macro_rules! impl_anyvalue_int {
+    ($t:ty) => {
+        impl From<$t> for AnyValue {
+            fn from(v: $t) -> Self {
+                AnyValue::Int(v.into())
+            }
+        }
+
+        impl TryFrom<AnyValue> for $t {
+            type Error = std::io::Error;
+
+            fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+                Self::try_from(&v)
+            }
+        }
+
+        impl TryFrom<&AnyValue> for $t {
+            type Error = std::io::Error;
+
+            fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+                match v.as_int() {
+                    Some(n) => n.try_into(),
+                    None => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Cannot convert {:?} to {}", v, stringify!($t)),
+                    )),
+                }
+            }
+        }
+    };
+}
+
+impl_anyvalue_int!(u8);
+impl_anyvalue_int!(u16);
+impl_anyvalue_int!(u32);
+impl_anyvalue_int!(u64);
+impl_anyvalue_int!(u128);
+impl_anyvalue_int!(i8);
+impl_anyvalue_int!(i16);
+impl_anyvalue_int!(i32);
+impl_anyvalue_int!(i64);
+impl_anyvalue_int!(i128);
+impl_anyvalue_int!(bool);
+
+impl TryFrom<&AnyValue> for AnyInt {
+    type Error = std::io::Error;
+
+    fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+        v.as_int().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Cannot convert {:?} to AnyInt", v),
+            )
+        })
+    }
+}
+
+impl TryFrom<AnyValue> for AnyInt {
+    type Error = std::io::Error;
+
+    fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+        Self::try_from(&v)
+    }
+}
+
+impl TryFrom<&AnyValue> for f32 {
+    type Error = std::io::Error;
+
+    fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+        match v {
+            AnyValue::F16(v) | AnyValue::F32(v) => Ok(*v),
+            v => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Cannot convert {:?} to f32", v),
+            )),
+        }
+    }
+}
+
+impl TryFrom<AnyValue> for f32 {
+    type Error = std::io::Error;
+
+    fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+        Self::try_from(&v)
+    }
+}
+
+impl TryFrom<&AnyValue> for f64 {
+    type Error = std::io::Error;
+
+    fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+        match v {
+            AnyValue::F64(v) => Ok(*v),
+            v => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Cannot convert {:?} to f64", v),
+            )),
+        }
+    }
+}
+
+impl TryFrom<AnyValue> for f64 {
+    type Error = std::io::Error;
+
+    fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+        Self::try_from(&v)
+    }
+}
+
+impl TryFrom<&AnyValue> for String {
+    type Error = std::io::Error;
+
+    fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+        match v.as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Cannot convert {:?} to String", v),
+            )),
+        }
+    }
+}
+
+impl TryFrom<AnyValue> for String {
+    type Error = std::io::Error;
+
+    fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+        match v {
+            AnyValue::Str(s) => Ok(s),
+            v => Self::try_from(&v),
+        }
+    }
+}
+
+impl TryFrom<&AnyValue> for Vec<u8> {
+    type Error = std::io::Error;
+
+    fn try_from(v: &AnyValue) -> Result<Self, Self::Error> {
+        match v {
+            AnyValue::Bytes(b) => Ok(b.clone()),
+            v => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Cannot convert {:?} to Vec<u8>", v),
+            )),
+        }
+    }
+}
+
+impl TryFrom<AnyValue> for Vec<u8> {
+    type Error = std::io::Error;
+
+    fn try_from(v: AnyValue) -> Result<Self, Self::Error> {
+        match v {
+            AnyValue::Bytes(b) => Ok(b),
+            v => Self::try_from(&v),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -850,4 +1678,324 @@ mod tests {
         assert!(LPWidth::usize_fits(LPWidth::LP64, 0));
         assert!(LPWidth::usize_fits(LPWidth::LP64, 18446744073709551615));
     }
+
+    #[test]
+    fn test_tagged_round_trip() {
+        let values = [
+            AnyInt::U8(1),
+            AnyInt::U16(0x1234),
+            AnyInt::U32(0x1234),
+            AnyInt::U48(0x1234_5678_9ABC),
+            AnyInt::U64(0x1234),
+            AnyInt::U128(0x1234),
+            AnyInt::I8(-1),
+            AnyInt::I16(-0x1234),
+            AnyInt::I32(0x1234),
+            AnyInt::I48(-0x1234_5678_9ABC),
+            AnyInt::I64(0x1234),
+            AnyInt::I128(-0x1234),
+            AnyInt::Bool(true),
+        ];
+        for endianness in [Endianness::LittleEndian, Endianness::BigEndian] {
+            for v in values {
+                let mut buf = Vec::new();
+                let written = v.write_tagged(&mut buf, endianness).unwrap();
+                assert_eq!(written as usize, 1 + v.ser_size());
+                assert_eq!(buf.len(), written as usize);
+                assert_eq!(buf[0], v.tag());
+                assert_eq!(AnyInt::read_tagged(buf.as_slice(), endianness).unwrap(), v);
+            }
+        }
+        assert!(AnyInt::read_tagged(&[13u8, 0][..], Endianness::LittleEndian).is_err());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let values = [
+            AnyInt::U8(0xFE),
+            AnyInt::U16(0x1234),
+            AnyInt::U32(0x12345678),
+            AnyInt::U48(0xFEDC_BA98_7654),
+            AnyInt::U64(0x1234_5678_9ABC_DEF0),
+            AnyInt::U128(u128::MAX - 0x1234),
+            AnyInt::I8(-1),
+            AnyInt::I16(-0x1234),
+            AnyInt::I32(-0x12345678),
+            AnyInt::I48(-0x1234_5678_9ABC),
+            AnyInt::I64(i64::MIN),
+            AnyInt::I128(-0x1234),
+            AnyInt::Bool(true),
+        ];
+        for v in values {
+            assert_eq!(v.kind().ser_size(), v.ser_size());
+            assert_eq!(AnyIntKind::from_tag(v.tag()), Some(v.kind()));
+            assert_eq!(
+                AnyInt::from_bytes(v.kind(), &v.to_bytes_le(), Endianness::LittleEndian).unwrap(),
+                v
+            );
+            assert_eq!(
+                AnyInt::from_bytes(v.kind(), &v.to_bytes_be(), Endianness::BigEndian).unwrap(),
+                v
+            );
+        }
+        assert_eq!(AnyIntKind::from_tag(13), None);
+        assert!(AnyInt::from_bytes(AnyIntKind::U48, &[0; 8], Endianness::LittleEndian).is_err());
+        assert!(AnyInt::from_bytes(AnyIntKind::U16, &[0; 1], Endianness::LittleEndian).is_err());
+    }
+
+    #[test]
+    fn test_checked_conversions() {
+        assert_eq!(AnyInt::U8(1).to_u32_checked().unwrap(), 1);
+        assert_eq!(AnyInt::U48(0xFFFF).to_u16_checked().unwrap(), 0xFFFF);
+        assert_eq!(AnyInt::I64(-1).to_i8_checked().unwrap(), -1);
+        assert_eq!(AnyInt::U128(5).to_i128_checked().unwrap(), 5);
+        assert_eq!(AnyInt::Bool(true).to_u64_checked().unwrap(), 1);
+        assert_eq!(AnyInt::I16(300).to_usize_checked().unwrap(), 300);
+
+        assert!(matches!(
+            AnyInt::U16(256).to_u8_checked(),
+            Err(StreamError::Overflow(AnyInt::U16(256), AnyIntKind::U8))
+        ));
+        assert!(AnyInt::I8(-1).to_u64_checked().is_err());
+        assert!(matches!(
+            AnyInt::I8(-1).to_usize_checked(),
+            Err(StreamError::Overflow(_, kind)) if kind.ser_size() == std::mem::size_of::<usize>()
+        ));
+        assert!(AnyInt::U128(u128::MAX).to_i128_checked().is_err());
+        assert!(AnyInt::I128(i128::MIN).to_i64_checked().is_err());
+
+        // the strict conversions still require a matching variant
+        assert!(TryInto::<u32>::try_into(AnyInt::U8(1)).is_err());
+    }
+
+    #[test]
+    fn test_as_width() {
+        assert_eq!(
+            AnyInt::U8(1).as_width(AnyIntKind::I64).unwrap(),
+            AnyInt::I64(1)
+        );
+        assert_eq!(
+            AnyInt::I32(-5).as_width(AnyIntKind::I48).unwrap(),
+            AnyInt::I48(-5)
+        );
+        assert_eq!(
+            AnyInt::U64((1 << 48) - 1)
+                .as_width(AnyIntKind::U48)
+                .unwrap(),
+            AnyInt::U48((1 << 48) - 1)
+        );
+        assert_eq!(
+            AnyInt::I16(1).as_width(AnyIntKind::Bool).unwrap(),
+            AnyInt::Bool(true)
+        );
+        assert!(AnyInt::U64(1 << 48).as_width(AnyIntKind::U48).is_err());
+        assert!(AnyInt::I64(1 << 47).as_width(AnyIntKind::I48).is_err());
+        assert!(AnyInt::I64(-(1 << 47)).as_width(AnyIntKind::I48).is_ok());
+        assert!(AnyInt::U8(2).as_width(AnyIntKind::Bool).is_err());
+        assert!(matches!(
+            AnyInt::I8(-1).as_width(AnyIntKind::U128),
+            Err(StreamError::Overflow(AnyInt::I8(-1), AnyIntKind::U128))
+        ));
+    }
+
+    #[test]
+    fn test_promote() {
+        use AnyIntKind::*;
+        assert_eq!(AnyIntKind::promote(U8, U16), U16);
+        assert_eq!(AnyIntKind::promote(I64, I8), I64);
+        assert_eq!(AnyIntKind::promote(Bool, Bool), U8);
+        assert_eq!(AnyIntKind::promote(U8, I8), I16);
+        assert_eq!(AnyIntKind::promote(I32, U8), I32);
+        assert_eq!(AnyIntKind::promote(U32, I64), I64);
+        assert_eq!(AnyIntKind::promote(U32, I16), I48);
+        assert_eq!(AnyIntKind::promote(U64, I8), I128);
+        assert_eq!(AnyIntKind::promote(U128, I8), I128);
+    }
+
+    #[test]
+    fn test_checked_arith() {
+        // e_phoff + e_phnum * e_phentsize
+        let e_phoff = AnyInt::U64(0x40);
+        let e_phnum = AnyInt::U16(13);
+        let e_phentsize = AnyInt::U16(0x38);
+        let end = e_phoff
+            .checked_add(e_phnum.checked_mul(e_phentsize).unwrap())
+            .unwrap();
+        assert_eq!(end, AnyInt::U64(0x40 + 13 * 0x38));
+
+        assert_eq!(
+            AnyInt::U8(1).checked_sub(AnyInt::I8(2)).unwrap(),
+            AnyInt::I16(-1)
+        );
+        assert_eq!(
+            AnyInt::I32(-7).checked_div(AnyInt::U8(2)).unwrap(),
+            AnyInt::I32(-3)
+        );
+        assert_eq!(
+            AnyInt::I32(-7).checked_rem(AnyInt::U8(2)).unwrap(),
+            AnyInt::I32(-1)
+        );
+        assert_eq!(
+            AnyInt::U48(1).checked_add(AnyInt::U8(1)).unwrap(),
+            AnyInt::U48(2)
+        );
+        assert_eq!(
+            AnyInt::Bool(true).checked_add(AnyInt::Bool(true)).unwrap(),
+            AnyInt::U8(2)
+        );
+
+        assert!(AnyInt::U8(0).checked_sub(AnyInt::U8(1)).is_err());
+        assert!(matches!(
+            AnyInt::U32(1).checked_div(AnyInt::U32(0)),
+            Err(StreamError::Arithmetic(_, "/", _))
+        ));
+        assert!(AnyInt::U128(u128::MAX)
+            .checked_mul(AnyInt::U128(2))
+            .is_err());
+        assert!(AnyInt::U128(u128::MAX).checked_add(AnyInt::I8(-1)).is_err());
+        assert!(AnyInt::I128(i128::MIN)
+            .checked_div(AnyInt::I128(-1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_checked_arith_overflow() {
+        // results that don't fit in the promoted kind are reported with the operands
+        assert!(matches!(
+            AnyInt::U8(255).checked_add(AnyInt::U8(1)),
+            Err(StreamError::Arithmetic(AnyInt::U8(255), "+", AnyInt::U8(1)))
+        ));
+        assert!(matches!(
+            AnyInt::I8(-128).checked_sub(AnyInt::I8(1)),
+            Err(StreamError::Arithmetic(
+                AnyInt::I8(-128),
+                "-",
+                AnyInt::I8(1)
+            ))
+        ));
+        assert!(matches!(
+            AnyInt::U16(0x100).checked_mul(AnyInt::U16(0x100)),
+            Err(StreamError::Arithmetic(_, "*", _))
+        ));
+        assert!(matches!(
+            AnyInt::U64(u64::MAX).checked_add(AnyInt::U64(1)),
+            Err(StreamError::Arithmetic(_, "+", _))
+        ));
+    }
+
+    #[test]
+    fn test_checked_bitwise() {
+        assert_eq!(
+            AnyInt::U16(0xFF0F).checked_and(AnyInt::U8(0xF0)).unwrap(),
+            AnyInt::U16(0x0000)
+        );
+        assert_eq!(
+            AnyInt::U16(0xFF0F).checked_or(AnyInt::U8(0xF0)).unwrap(),
+            AnyInt::U16(0xFFFF)
+        );
+        assert_eq!(
+            AnyInt::U8(0xFF).checked_xor(AnyInt::U8(0x0F)).unwrap(),
+            AnyInt::U8(0xF0)
+        );
+        assert_eq!(
+            AnyInt::I8(-1).checked_and(AnyInt::U8(0xFF)).unwrap(),
+            AnyInt::I16(0xFF)
+        );
+
+        assert_eq!(
+            AnyInt::U8(1).checked_shl(AnyInt::U8(7)).unwrap(),
+            AnyInt::U8(0x80)
+        );
+        assert_eq!(
+            AnyInt::I16(-4).checked_shl(AnyInt::U8(2)).unwrap(),
+            AnyInt::I16(-16)
+        );
+        assert_eq!(
+            AnyInt::I16(-4).checked_shr(AnyInt::U8(1)).unwrap(),
+            AnyInt::I16(-2)
+        );
+        assert_eq!(
+            AnyInt::U128(1).checked_shl(AnyInt::U8(127)).unwrap(),
+            AnyInt::U128(1 << 127)
+        );
+        assert_eq!(
+            AnyInt::U8(0x80).checked_shr(AnyInt::U8(7)).unwrap(),
+            AnyInt::U8(1)
+        );
+        assert!(AnyInt::U8(0x80).checked_shl(AnyInt::U8(1)).is_err());
+        assert!(AnyInt::U8(1).checked_shl(AnyInt::U8(8)).is_err());
+        assert!(AnyInt::U8(1).checked_shr(AnyInt::I8(-1)).is_err());
+        assert!(AnyInt::I128(-1).checked_shl(AnyInt::U8(127)).is_ok());
+        assert!(AnyInt::I128(1).checked_shl(AnyInt::U8(127)).is_err());
+    }
+
+    #[test]
+    fn test_num_cmp() {
+        use std::cmp::Ordering;
+        assert_eq!(AnyInt::U8(1).num_cmp(&AnyInt::I64(1)), Ordering::Equal);
+        assert!(AnyInt::U8(1).num_eq(&AnyInt::Bool(true)));
+        assert_eq!(AnyInt::U8(2).num_cmp(&AnyInt::U64(1)), Ordering::Greater);
+        assert_eq!(AnyInt::I8(-1).num_cmp(&AnyInt::U8(0)), Ordering::Less);
+        assert_eq!(
+            AnyInt::U128(u128::MAX).num_cmp(&AnyInt::I128(i128::MAX)),
+            Ordering::Greater
+        );
+        // the derived ordering compares variants first
+        assert!(AnyInt::U8(2) < AnyInt::U16(1));
+    }
+
+    #[test]
+    fn test_anyvalue() {
+        let v: AnyValue = 1u16.into();
+        assert_eq!(v, AnyValue::Int(AnyInt::U16(1)));
+        assert_eq!(v, AnyInt::U16(1));
+        assert_eq!(u16::try_from(&v).unwrap(), 1);
+        assert!(u32::try_from(&v).is_err());
+        assert!(f32::try_from(&v).is_err());
+
+        let v = AnyValue::F32(1.5);
+        assert_eq!(f32::try_from(&v).unwrap(), 1.5);
+        assert_eq!(v.as_f64(), Some(1.5));
+        assert_eq!(v.as_int(), None);
+        assert!(u32::try_from(v).is_err());
+
+        assert_eq!(AnyValue::F16(-2.0).to_bytes_be(), vec![0xC0, 0x00]);
+        assert_eq!(
+            AnyValue::F32(1.0).to_bytes_le(),
+            vec![0x00, 0x00, 0x80, 0x3F]
+        );
+        assert_eq!(
+            AnyValue::F64(1.0).to_bytes_be(),
+            1.0f64.to_be_bytes().to_vec()
+        );
+        assert_eq!(AnyValue::F16(0.0).ser_size(), 2);
+        assert_eq!(AnyValue::Int(AnyInt::U48(0)).ser_size(), 6);
+
+        let v = AnyValue::List(vec![
+            AnyValue::Int(AnyInt::U16(0x0102)),
+            AnyValue::Struct(vec![("a".into(), AnyValue::F16(1.0))]),
+        ]);
+        assert_eq!(v.ser_size(), 4);
+        assert_eq!(v.to_bytes_be(), vec![0x01, 0x02, 0x3C, 0x00]);
+        assert_eq!(v.as_list().unwrap().len(), 2);
+        assert!(u16::try_from(&v).is_err());
+        assert_eq!(v.get("1.a"), Some(&AnyValue::F16(1.0)));
+        assert_eq!(v.get("0"), Some(&AnyValue::Int(AnyInt::U16(0x0102))));
+        assert_eq!(v.get(""), Some(&v));
+        assert_eq!(v.get("1.b"), None);
+        assert_eq!(v.get("2"), None);
+        assert_eq!(v.get("0.a"), None);
+        assert_eq!(v.get("x"), None);
+
+        let v: AnyValue = "abc".into();
+        assert_eq!(v.as_str(), Some("abc"));
+        assert_eq!(v.as_bytes(), Some(&b"abc"[..]));
+        assert_eq!(v.ser_size(), 3);
+        assert_eq!(String::try_from(&v).unwrap(), "abc");
+        assert!(Vec::<u8>::try_from(&v).is_err());
+        let v: AnyValue = vec![1u8, 2].into();
+        assert_eq!(v.to_bytes_be(), vec![1, 2]);
+        assert_eq!(Vec::<u8>::try_from(v.clone()).unwrap(), vec![1, 2]);
+        assert!(String::try_from(v).is_err());
+    }
 }
@@ -1,82 +1,81 @@
 //! Utilities for working with streams.
 //! Like finding a signature in a stream, or reading a struct from a stream.
 
-use crate::streams::helpers::read_lpend;
-use crate::streams::{AnyInt, Endianness, MapType, SeekRead, StreamError};
+use crate::const_fn::kib_to_byte;
+use crate::streams::helpers::{read_len, read_lpend};
+use crate::streams::{
+    AnyInt, AnyIntKind, Endianness, MapType, SeekRead, StreamError, MAP_FORMAT_VERSION,
+};
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
 use std::io::{Error, ErrorKind, Read, SeekFrom};
-
-use std::u8;
+use std::ops::Range;
 
 use super::LPWidth;
 
 pub type StreamResult<T> = Result<T, StreamError>;
 
-/// Finds a signature in a stream `S: Read + Seek` and returns it's position.
+/// Finds a byte signature of any length in a stream `S: Read + Seek` and returns it's position.
 /// The stream is left at the position of the signature.
 ///
+/// The stream is read in buffered chunks and searched with the Boyer-Moore-Horspool algorithm,
+/// so the search doesn't need to look at every byte of the stream.
+///
 /// A skip parameter can be used to skip a number of bytes before searching for the signature,
 /// this can speed up the search if the signature is known to be far away from
 /// the start of the stream.
 ///
 /// The limit parameter can be used to limit the search to a number of bytes, if not provided
-/// the search will happen until the end of the stream.
-///
-/// The endianness parameter can be used to specify the endianness of the signature in the stream.
+/// the search will happen until the end of the stream, the signature must start before `limit`.
 ///
 /// The rewind parameter can be used to rewind the stream to the position before the signature was found.
 ///
-#[inline]
-pub fn find_u32_signature<S: SeekRead>(
+/// # Errors
+/// This function will return an error in the following cases:
+/// * The signature is empty.
+/// * The signature is not found before the end of the stream or `limit`.
+/// * The stream returns an error.
+pub fn find_signature<S: SeekRead>(
     stream: &mut S,
-    sig: u32,
+    sig: &[u8],
+    skip: Option<u64>,
+    limit: Option<u64>,
+    rewind: bool,
+) -> StreamResult<u64> {
+    find_pattern(stream, &SignaturePattern::from(sig), skip, limit, rewind)
+}
+
+/// Finds a masked signature in a stream `S: Read + Seek` and returns it's position,
+/// see [`SignaturePattern`] for how patterns match and [`find_signature`] for the
+/// meaning of the other parameters.
+pub fn find_pattern<S: SeekRead>(
+    stream: &mut S,
+    pattern: &SignaturePattern,
     skip: Option<u64>,
     limit: Option<u64>,
-    endianness: Endianness,
     rewind: bool,
 ) -> StreamResult<u64> {
     let rewind_pos = stream.stream_position()?;
-    let byte = &mut [0; 1];
-    let sig_fbyte = match endianness {
-        Endianness::LittleEndian => sig.to_le_bytes()[0],
-        Endianness::BigEndian => sig.to_be_bytes()[0],
-    };
     let skip = skip.unwrap_or(0);
-    let limit = limit.unwrap_or(!0);
-
-    stream.seek(SeekFrom::Start(skip))?;
+    let end = limit
+        .map(|limit| limit.saturating_add(pattern.len().saturating_sub(1) as u64))
+        .unwrap_or(u64::MAX);
 
-    // Bytewise lookup
-    let mut pos = skip;
-    while pos < limit {
-        let read = stream.read(byte)?;
-        if read == 0 {
+    let found = SignatureScanner::with_pattern(stream, pattern, Some(skip..end))?.next();
+    let pos = match found {
+        Some(pos) => pos?,
+        None => {
             return Err(StreamError::from(Error::new(
                 ErrorKind::UnexpectedEof,
-                "Unexpected end of stream",
-            )));
-        }
-
-        if byte[0] == sig_fbyte {
-            // rewind 1 byte
-            stream.seek(SeekFrom::Current(-1))?;
-            // found first byte, check if the rest of the signature matches
-            let sig_candidate = match endianness {
-                Endianness::LittleEndian => stream.read_u32::<LittleEndian>()?,
-                Endianness::BigEndian => stream.read_u32::<BigEndian>()?,
-            };
-            if sig_candidate == sig {
-                break;
-            }
-            pos += 4;
-            continue;
+                "Signature not found before the end of the stream",
+            )))
         }
-        pos += 1;
-    }
+    };
 
     if rewind {
         stream.seek(SeekFrom::Start(rewind_pos))?;
+    } else {
+        stream.seek(SeekFrom::Start(pos))?;
     }
     Ok(pos)
 }
@@ -95,6 +94,37 @@ pub fn find_u32_signature<S: SeekRead>(
 ///
 /// The rewind parameter can be used to rewind the stream to the position before the signature was found.
 ///
+/// See [`find_signature`] for details.
+#[inline]
+pub fn find_u32_signature<S: SeekRead>(
+    stream: &mut S,
+    sig: u32,
+    skip: Option<u64>,
+    limit: Option<u64>,
+    endianness: Endianness,
+    rewind: bool,
+) -> StreamResult<u64> {
+    match endianness {
+        Endianness::LittleEndian => find_signature(stream, &sig.to_le_bytes(), skip, limit, rewind),
+        Endianness::BigEndian => find_signature(stream, &sig.to_be_bytes(), skip, limit, rewind),
+    }
+}
+
+/// Finds a signature in a stream `S: Read + Seek` and returns it's position.
+/// The stream is left at the position of the signature.
+///
+/// A skip parameter can be used to skip a number of bytes before searching for the signature,
+/// this can speed up the search if the signature is known to be far away from
+/// the start of the stream.
+///
+/// The limit parameter can be used to limit the search to a number of bytes, if not provided
+/// the search will happen until the end of the stream.
+///
+/// The endianness parameter can be used to specify the endianness of the signature in the stream.
+///
+/// The rewind parameter can be used to rewind the stream to the position before the signature was found.
+///
+/// See [`find_signature`] for details.
 #[inline]
 pub fn find_u64_signature<S: SeekRead>(
     stream: &mut S,
@@ -104,77 +134,545 @@ pub fn find_u64_signature<S: SeekRead>(
     endianness: Endianness,
     rewind: bool,
 ) -> StreamResult<u64> {
-    let rewind_pos = stream.stream_position()?;
-    let byte = &mut [0; 1];
-    let sig_fbyte = match endianness {
-        Endianness::LittleEndian => sig.to_le_bytes()[0],
-        Endianness::BigEndian => sig.to_be_bytes()[0],
-    };
-    let skip = skip.unwrap_or(0);
-    let limit = limit.unwrap_or(!0);
+    match endianness {
+        Endianness::LittleEndian => find_signature(stream, &sig.to_le_bytes(), skip, limit, rewind),
+        Endianness::BigEndian => find_signature(stream, &sig.to_be_bytes(), skip, limit, rewind),
+    }
+}
 
-    stream.seek(SeekFrom::Start(skip))?;
+/// A byte signature where some bits may be ignored when matching,
+/// used by [`find_pattern`] and [`SignatureScanner::with_pattern`].
+///
+/// A stream byte `b` matches position `i` of the pattern if `b & mask[i] == bytes[i] & mask[i]`,
+/// so a mask of `0xFF` is an exact match and `0x00` matches any byte.
+///
+/// Patterns can also be parsed from text, each byte is written as two hex digits
+/// and either digit can be replaced by `?` to ignore that nibble, bytes may be
+/// separated by whitespace:
+/// ```
+/// # use neoncore::streams::read::SignaturePattern;
+/// let pattern: SignaturePattern = "50 4B ?? ?? 14 0?".parse().unwrap();
+/// assert!(pattern.matches(b"PK\x03\x04\x14\x00"));
+/// assert!(pattern.matches(b"PK\x01\x02\x14\x0A"));
+/// assert!(!pattern.matches(b"PK\x01\x02\x15\x00"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SignaturePattern {
+    bytes: Vec<u8>,
+    mask: Vec<u8>,
+}
 
-    // Bytewise lookup
-    let mut pos = skip;
-    while pos < limit {
-        let read = stream.read(byte)?;
-        if read == 0 {
-            return Err(StreamError::from(Error::new(
-                ErrorKind::UnexpectedEof,
-                "Unexpected end of stream",
-            )));
+impl SignaturePattern {
+    /// Create a pattern from `bytes` and a `mask` of the same length.
+    ///
+    /// # Errors
+    /// This function will return an error if the pattern is empty or the lengths don't match.
+    pub fn new(bytes: &[u8], mask: &[u8]) -> StreamResult<Self> {
+        if bytes.is_empty() {
+            return Err(StreamError::InvalidPattern("empty signature".into()));
+        }
+        if bytes.len() != mask.len() {
+            return Err(StreamError::InvalidPattern(
+                "signature and mask have different lengths".into(),
+            ));
+        }
+        Ok(Self {
+            bytes: bytes.iter().zip(mask).map(|(b, m)| b & m).collect(),
+            mask: mask.to_vec(),
+        })
+    }
+
+    /// The length of the pattern in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn mask(&self) -> &[u8] {
+        &self.mask
+    }
+
+    /// Check if the pattern matches the start of `data`.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.len() && self.matches_at(data, 0, self.len())
+    }
+
+    fn matches_at(&self, data: &[u8], start: usize, len: usize) -> bool {
+        data[start..start + len]
+            .iter()
+            .zip(&self.bytes[..len])
+            .zip(&self.mask[..len])
+            .all(|((d, b), m)| d & m == *b)
+    }
+}
+
+impl From<&[u8]> for SignaturePattern {
+    fn from(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            mask: vec![0xFF; bytes.len()],
         }
+    }
+}
 
-        if byte[0] == sig_fbyte {
-            // rewind 1 byte
-            stream.seek(SeekFrom::Current(-1))?;
-            // found first byte, check if the rest of the signature matches
-            let sig_candidate = match endianness {
-                Endianness::LittleEndian => stream.read_u64::<LittleEndian>()?,
-                Endianness::BigEndian => stream.read_u64::<BigEndian>()?,
+impl std::str::FromStr for SignaturePattern {
+    type Err = StreamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = Vec::new();
+        let mut mask = Vec::new();
+        // the nibbles of the byte being parsed as (value, mask)
+        let mut pending: Option<(u8, u8)> = None;
+
+        for (pos, c) in s.chars().enumerate() {
+            let nibble = match c {
+                '?' => (0, 0),
+                c if c.is_ascii_hexdigit() => (c.to_digit(16).unwrap() as u8, 0xF),
+                c if c.is_whitespace() && pending.is_none() => continue,
+                c => return Err(StreamError::InvalidChar(c, pos)),
             };
-            if sig_candidate == sig {
-                break;
+            match pending.take() {
+                None => pending = Some(nibble),
+                Some((hi, hi_mask)) => {
+                    bytes.push(hi << 4 | nibble.0);
+                    mask.push(hi_mask << 4 | nibble.1);
+                }
             }
-            pos += 8;
-            continue;
         }
-        pos += 1;
+
+        if pending.is_some() {
+            return Err(StreamError::InvalidPattern(
+                "signature ends in the middle of a byte".into(),
+            ));
+        }
+        Self::new(&bytes, &mask)
     }
+}
 
-    if rewind {
-        stream.seek(SeekFrom::Start(rewind_pos))?;
+/// Boyer-Moore-Horspool searcher for a fixed, possibly masked, needle.
+struct Horspool {
+    pattern: SignaturePattern,
+    shift: [usize; 256],
+}
+
+impl Horspool {
+    fn new(pattern: &SignaturePattern) -> Self {
+        let last = pattern.len() - 1;
+        let mut shift = [pattern.len(); 256];
+        for i in 0..last {
+            let (b, m) = (pattern.bytes[i], pattern.mask[i]);
+            if m == 0xFF {
+                shift[b as usize] = last - i;
+                continue;
+            }
+            // every byte matching the masked position can shift to it
+            for (c, s) in shift.iter_mut().enumerate() {
+                if c as u8 & m == b {
+                    *s = last - i;
+                }
+            }
+        }
+        Self {
+            pattern: pattern.clone(),
+            shift,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.pattern.len()
+    }
+
+    /// Returns the index of the first occurrence of the needle in `haystack`.
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let last = self.pattern.len() - 1;
+        let (last_b, last_m) = (self.pattern.bytes[last], self.pattern.mask[last]);
+        let mut i = 0;
+        while i + last < haystack.len() {
+            let b = haystack[i + last];
+            if b & last_m == last_b && self.pattern.matches_at(&haystack[i..], 0, last) {
+                return Some(i);
+            }
+            i += self.shift[b as usize];
+        }
+        None
+    }
+}
+
+/// Size of the chunks read from the stream by [`SignatureScanner`].
+const SCAN_CHUNK_SIZE: usize = kib_to_byte(64);
+
+/// Lazily scans a stream `S: Read + Seek` for every occurrence of a signature.
+///
+/// The scanner yields the offset of each match in the order they appear in the stream,
+/// overlapping matches are reported as well, e.g. scanning `aaaa` for `aa` yields `0, 1, 2`.
+///
+/// The optional range limits the scan to the bytes in `range.start..range.end`,
+/// a match is only reported if it lies entirely within the range.
+/// Reaching the end of the stream or the range ends the scan, after that the
+/// stream is left at the end of the scanned data.
+pub struct SignatureScanner<'s, S: SeekRead> {
+    stream: &'s mut S,
+    searcher: Horspool,
+    end: u64,
+    buf: Vec<u8>,
+    buf_pos: u64,
+    cursor: usize,
+    chunk_size: usize,
+    done: bool,
+}
+
+impl<'s, S: SeekRead> SignatureScanner<'s, S> {
+    /// Create a scanner for the byte signature `sig`.
+    ///
+    /// # Errors
+    /// This function will return an error if `sig` is empty or seeking to the start of the range fails.
+    pub fn new(stream: &'s mut S, sig: &[u8], range: Option<Range<u64>>) -> StreamResult<Self> {
+        Self::with_pattern(stream, &SignaturePattern::from(sig), range)
+    }
+
+    /// Create a scanner for a masked signature, see [`SignaturePattern`].
+    ///
+    /// # Errors
+    /// This function will return an error if `pattern` is empty or seeking to the start of the range fails.
+    pub fn with_pattern(
+        stream: &'s mut S,
+        pattern: &SignaturePattern,
+        range: Option<Range<u64>>,
+    ) -> StreamResult<Self> {
+        if pattern.is_empty() {
+            return Err(StreamError::InvalidPattern("empty signature".into()));
+        }
+        let range = range.unwrap_or(0..u64::MAX);
+        stream.seek(SeekFrom::Start(range.start))?;
+
+        Ok(Self {
+            stream,
+            searcher: Horspool::new(pattern),
+            end: range.end,
+            buf: Vec::new(),
+            buf_pos: range.start,
+            cursor: 0,
+            chunk_size: SCAN_CHUNK_SIZE,
+            done: false,
+        })
+    }
+
+    /// Create a scanner for a u32 signature stored with the given `endianness`.
+    pub fn from_u32(
+        stream: &'s mut S,
+        sig: u32,
+        endianness: Endianness,
+        range: Option<Range<u64>>,
+    ) -> StreamResult<Self> {
+        match endianness {
+            Endianness::LittleEndian => Self::new(stream, &sig.to_le_bytes(), range),
+            Endianness::BigEndian => Self::new(stream, &sig.to_be_bytes(), range),
+        }
+    }
+
+    /// Create a scanner for a u64 signature stored with the given `endianness`.
+    pub fn from_u64(
+        stream: &'s mut S,
+        sig: u64,
+        endianness: Endianness,
+        range: Option<Range<u64>>,
+    ) -> StreamResult<Self> {
+        match endianness {
+            Endianness::LittleEndian => Self::new(stream, &sig.to_le_bytes(), range),
+            Endianness::BigEndian => Self::new(stream, &sig.to_be_bytes(), range),
+        }
+    }
+
+    /// Read the next chunk of the stream into the buffer,
+    /// returns the number of bytes read, 0 means the scan is over.
+    fn fill(&mut self) -> StreamResult<usize> {
+        let buf_end = self.buf_pos + self.buf.len() as u64;
+        let want = (self.end.saturating_sub(buf_end)).min(self.chunk_size as u64) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
+
+        let old_len = self.buf.len();
+        self.buf.resize(old_len + want, 0);
+        loop {
+            match self.stream.read(&mut self.buf[old_len..]) {
+                Ok(read) => {
+                    self.buf.truncate(old_len + read);
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(old_len);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
+
+impl<'s, S: SeekRead> Iterator for SignatureScanner<'s, S> {
+    type Item = StreamResult<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            if let Some(i) = self.searcher.find(&self.buf[self.cursor..]) {
+                let pos = self.buf_pos + (self.cursor + i) as u64;
+                self.cursor += i + 1;
+                return Some(Ok(pos));
+            }
+
+            // keep only the tail that could still be the start of a match
+            let keep_from = self
+                .buf
+                .len()
+                .saturating_sub(self.searcher.len() - 1)
+                .max(self.cursor);
+            self.buf.drain(..keep_from);
+            self.buf_pos += keep_from as u64;
+            self.cursor = 0;
+
+            match self.fill() {
+                Ok(0) => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Aho-Corasick automaton matching a set of byte signatures at once,
+/// the goto function is completed into a DFA so every byte is a single table lookup.
+struct AhoCorasick {
+    delta: Vec<[u32; 256]>,
+    outputs: Vec<Vec<usize>>,
+    lens: Vec<usize>,
+}
+
+impl AhoCorasick {
+    const NONE: u32 = u32::MAX;
+
+    fn new(signatures: &[&[u8]]) -> Self {
+        let mut delta = vec![[Self::NONE; 256]];
+        let mut outputs = vec![Vec::new()];
+
+        for (id, sig) in signatures.iter().enumerate() {
+            let mut state = 0;
+            for b in sig.iter() {
+                let next = delta[state][*b as usize];
+                state = if next == Self::NONE {
+                    delta.push([Self::NONE; 256]);
+                    outputs.push(Vec::new());
+                    delta[state][*b as usize] = (delta.len() - 1) as u32;
+                    delta.len() - 1
+                } else {
+                    next as usize
+                };
+            }
+            outputs[state].push(id);
+        }
+
+        // breadth first over the trie, filling in the failure transitions
+        let mut fail = vec![0usize; delta.len()];
+        let mut queue = std::collections::VecDeque::new();
+        for next in delta[0].iter_mut() {
+            match *next {
+                Self::NONE => *next = 0,
+                _ => queue.push_back(*next as usize),
+            }
+        }
+        while let Some(state) = queue.pop_front() {
+            let fallback_row = delta[fail[state]];
+            for (b, fallback) in fallback_row.into_iter().enumerate() {
+                match delta[state][b] {
+                    Self::NONE => delta[state][b] = fallback,
+                    next => {
+                        let next = next as usize;
+                        fail[next] = fallback as usize;
+                        let inherited = outputs[fallback as usize].clone();
+                        outputs[next].extend(inherited);
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+
+        Self {
+            delta,
+            outputs,
+            lens: signatures.iter().map(|sig| sig.len()).collect(),
+        }
+    }
+}
+
+/// Scans a stream for a set of named signatures in a single pass.
+///
+/// Works on any `S: Read`, the stream is only read forward and never seeked.
+/// Each hit is yielded as `(name, offset)` where the offset is relative to the
+/// position the scan started at, or absolute when created with
+/// [`MultiSignatureScanner::from_position`].
+///
+/// Overlapping hits of the same or different signatures are all reported,
+/// hits are yielded in the order they end in the stream, hits ending at the same
+/// byte are yielded longest signature first.
+pub struct MultiSignatureScanner<'s, S: Read> {
+    stream: &'s mut S,
+    names: Vec<String>,
+    automaton: AhoCorasick,
+    state: usize,
+    buf: Vec<u8>,
+    buf_len: usize,
+    buf_idx: usize,
+    pos: u64,
+    pending: std::collections::VecDeque<(usize, u64)>,
+    done: bool,
+}
+
+impl<'s, S: Read> MultiSignatureScanner<'s, S> {
+    /// Create a scanner for `signatures` given as `(name, signature)` pairs,
+    /// offsets are counted from the current position of the stream.
+    ///
+    /// # Errors
+    /// This function will return an error if `signatures` is empty or contains an empty signature.
+    pub fn new(stream: &'s mut S, signatures: &[(&str, &[u8])]) -> StreamResult<Self> {
+        if signatures.is_empty() || signatures.iter().any(|(_, sig)| sig.is_empty()) {
+            return Err(StreamError::InvalidPattern("empty signature".into()));
+        }
+        let sigs = signatures.iter().map(|(_, sig)| *sig).collect::<Vec<_>>();
+
+        Ok(Self {
+            stream,
+            names: signatures
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            automaton: AhoCorasick::new(&sigs),
+            state: 0,
+            buf: vec![0; SCAN_CHUNK_SIZE],
+            buf_len: 0,
+            buf_idx: 0,
+            pos: 0,
+            pending: std::collections::VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Read the next chunk of the stream into the buffer,
+    /// returns the number of bytes read, 0 means the scan is over.
+    fn fill(&mut self) -> StreamResult<usize> {
+        loop {
+            match self.stream.read(&mut self.buf) {
+                Ok(read) => {
+                    self.buf_len = read;
+                    self.buf_idx = 0;
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl<'s, S: SeekRead> MultiSignatureScanner<'s, S> {
+    /// Create a scanner that reports absolute stream offsets,
+    /// scanning starts at the current position of the stream.
+    ///
+    /// # Errors
+    /// This function will return an error if `signatures` is empty or contains an empty signature,
+    /// or the stream position can't be read.
+    pub fn from_position(stream: &'s mut S, signatures: &[(&str, &[u8])]) -> StreamResult<Self> {
+        let pos = stream.stream_position()?;
+        let mut scanner = Self::new(stream, signatures)?;
+        scanner.pos = pos;
+        Ok(scanner)
+    }
+}
+
+impl<'s, S: Read> Iterator for MultiSignatureScanner<'s, S> {
+    type Item = StreamResult<(String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((id, end)) = self.pending.pop_front() {
+                let start = end - self.automaton.lens[id] as u64;
+                return Some(Ok((self.names[id].clone(), start)));
+            }
+            if self.done {
+                return None;
+            }
+
+            if self.buf_idx == self.buf_len {
+                match self.fill() {
+                    Ok(0) => {
+                        self.done = true;
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+
+            while self.buf_idx < self.buf_len {
+                let b = self.buf[self.buf_idx];
+                self.buf_idx += 1;
+                self.pos += 1;
+                self.state = self.automaton.delta[self.state][b as usize] as usize;
+
+                let outputs = &self.automaton.outputs[self.state];
+                if !outputs.is_empty() {
+                    let mut hits = outputs.clone();
+                    hits.sort_by_key(|id| std::cmp::Reverse(self.automaton.lens[*id]));
+                    self.pending
+                        .extend(hits.into_iter().map(|id| (id, self.pos)));
+                    break;
+                }
+            }
+        }
     }
-    Ok(pos)
 }
 
 /// Scans `stream` for occurrences of `sig` and returns their positions.
-/// The stream is left at the position of the last occurrence of `sig`.
+/// The stream is left at the end of the stream.
+///
+/// This is a convenience wrapper around [`SignatureScanner`].
 pub fn find_all_u32_signatures<S: SeekRead>(
     stream: &mut S,
     sig: u32,
     endianness: Endianness,
 ) -> StreamResult<Vec<u64>> {
-    let mut positions = Vec::new();
-    loop {
-        let pos = find_u32_signature(stream, sig, None, None, endianness, true)?;
-        positions.push(pos);
-    }
+    SignatureScanner::from_u32(stream, sig, endianness, None)?.collect()
 }
 
 /// Scans `stream` for occurrences of `sig` and returns their positions.
-/// The stream is left at the position of the last occurrence of `sig`.
+/// The stream is left at the end of the stream.
+///
+/// This is a convenience wrapper around [`SignatureScanner`].
 pub fn find_all_u64_signatures<S: SeekRead>(
     stream: &mut S,
     sig: u64,
     endianness: Endianness,
 ) -> StreamResult<Vec<u64>> {
-    let mut positions = Vec::new();
-    loop {
-        let pos = find_u64_signature(stream, sig, None, None, endianness, true)?;
-        positions.push(pos);
-    }
+    SignatureScanner::from_u64(stream, sig, endianness, None)?.collect()
 }
 
 /// Read a length prefixed buffer from the stream.
@@ -198,11 +696,7 @@ pub fn read_lpbuf<S: Read>(
     lpend: Endianness,
 ) -> StreamResult<Vec<u8>> {
     let len = read_lpend(&mut stream, lptype, lpend)?;
-
-    let mut buf = vec![0; len];
-    stream.read_exact(&mut buf)?;
-
-    Ok(buf)
+    read_len(stream, len)
 }
 
 /// Read a length prefixed string from the stream.
@@ -220,7 +714,7 @@ pub fn read_lpbuf<S: Read>(
 /// * The stream ends before `len` bytes are read.
 /// * The read bytes are not valid UTF-8.
 /// * The stream returns an error.
-pub fn read_lpstr<S: SeekRead>(
+pub fn read_lpstr<S: Read>(
     mut stream: S,
     lptype: LPWidth,
     lpend: Endianness,
@@ -263,32 +757,118 @@ pub fn read_cstr<S: Read>(mut stream: S, maxlen: usize) -> StreamResult<String>
 }
 
 /// Read a length prefixed map from the stream.
+///
+/// The map must be in the format described in [`MAP_FORMAT_VERSION`],
+/// as written by [`super::write::write_map`].
+///
 /// # Arguments
 /// * `stream`: The stream to read from.
-/// * `endianness`: The endianness of the length prefix.
-/// * `lpwidth`: The width of the length prefix.
+/// * `endianness`: The endianness of the length prefixes and values.
+/// * `lpwidth`: The width of the length prefixes.
 ///
 /// # Returns
 /// The read map.
+///
+/// # Errors
+/// This function will return an error in the following cases:
+/// * The map was written with an unsupported format version.
+/// * A value has an unknown type tag.
+/// * A key is not valid UTF-8.
+/// * The stream returns an error.
 pub fn read_map<S: Read, M: MapType<'static, String, AnyInt>>(
     mut stream: S,
     endianness: Endianness,
     lpwidth: LPWidth,
 ) -> StreamResult<M> {
+    let version = stream.read_u8()?;
+    if version != MAP_FORMAT_VERSION {
+        return Err(StreamError::from(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported map format version {}", version),
+        )));
+    }
+
     let mut map = M::new();
     let len = read_lpend(&mut stream, lpwidth, endianness)?;
 
     for _ in 0..len {
-        let key = read_cstr(&mut stream, 256)?;
-        let value = match endianness {
-            Endianness::LittleEndian => AnyInt::from(stream.read_u64::<LittleEndian>()?),
-            Endianness::BigEndian => AnyInt::from(stream.read_u64::<BigEndian>()?),
-        };
+        let key = read_lpstr(&mut stream, lpwidth, endianness)?;
+        let value = AnyInt::read_tagged(&mut stream, endianness)?;
         map.insert(key, value);
     }
     Ok(map)
 }
 
+/// Read an integer of the given `kind` from the stream,
+/// U48 and I48 are read from 6 bytes, as written by [`AnyInt::to_bytes_le`] and [`AnyInt::to_bytes_be`].
+///
+/// # Arguments
+/// * `stream`: The stream to read from.
+/// * `kind`: The kind of integer to read.
+/// * `endianness`: The endianness of the integer.
+///
+/// # Returns
+/// The read integer.
+pub fn read_anyint<S: Read>(
+    mut stream: S,
+    kind: AnyIntKind,
+    endianness: Endianness,
+) -> StreamResult<AnyInt> {
+    match endianness {
+        Endianness::LittleEndian => read_anyint_ord::<_, LittleEndian>(&mut stream, kind),
+        Endianness::BigEndian => read_anyint_ord::<_, BigEndian>(&mut stream, kind),
+    }
+}
+
+fn read_anyint_ord<S: Read, Ord: byteorder::ByteOrder>(
+    mut stream: S,
+    kind: AnyIntKind,
+) -> StreamResult<AnyInt> {
+    Ok(match kind {
+        AnyIntKind::U8 => AnyInt::U8(stream.read_u8()?),
+        AnyIntKind::U16 => AnyInt::U16(stream.read_u16::<Ord>()?),
+        AnyIntKind::U32 => AnyInt::U32(stream.read_u32::<Ord>()?),
+        AnyIntKind::U48 => AnyInt::U48(stream.read_u48::<Ord>()?),
+        AnyIntKind::U64 => AnyInt::U64(stream.read_u64::<Ord>()?),
+        AnyIntKind::U128 => AnyInt::U128(stream.read_u128::<Ord>()?),
+        AnyIntKind::I8 => AnyInt::I8(stream.read_i8()?),
+        AnyIntKind::I16 => AnyInt::I16(stream.read_i16::<Ord>()?),
+        AnyIntKind::I32 => AnyInt::I32(stream.read_i32::<Ord>()?),
+        AnyIntKind::I48 => AnyInt::I48(stream.read_i48::<Ord>()?),
+        AnyIntKind::I64 => AnyInt::I64(stream.read_i64::<Ord>()?),
+        AnyIntKind::I128 => AnyInt::I128(stream.read_i128::<Ord>()?),
+        AnyIntKind::Bool => AnyInt::Bool(stream.read_u8()? != 0),
+    })
+}
+
+/// Read tagged values written by [`super::write::write_tagged_values`] until the end of the stream.
+///
+/// # Errors
+/// This function will return an error in the following cases:
+/// * A value has an unknown type tag.
+/// * The stream ends in the middle of a value.
+/// * The stream returns an error.
+pub fn read_tagged_values<S: Read>(
+    mut stream: S,
+    endianness: Endianness,
+) -> StreamResult<Vec<AnyInt>> {
+    let mut values = Vec::new();
+    let mut tag = [0; 1];
+    loop {
+        match stream.read(&mut tag) {
+            Ok(0) => break,
+            Ok(_) => values.push(AnyInt::read_tagged_payload(
+                &mut stream,
+                tag[0],
+                endianness,
+            )?),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(values)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -342,4 +922,315 @@ mod tests {
 
         assert_eq!(pos_1, 0x10);
     }
+
+    #[test]
+    fn test_find_signature_overlapping_prefix() {
+        let mut stream = std::io::Cursor::new(b"PPPK\x01\x02".to_vec());
+        let pos = find_u32_signature(
+            &mut stream,
+            0x02014b50,
+            None,
+            None,
+            Endianness::LittleEndian,
+            false,
+        )
+        .unwrap();
+        assert_eq!(pos, 2);
+        assert_eq!(stream.position(), 2);
+    }
+
+    #[test]
+    fn test_find_signature_bytes() {
+        let mut stream = std::io::Cursor::new(DATA);
+        stream.set_position(5);
+        let pos = find_signature(&mut stream, b"postcard-main", None, None, true).unwrap();
+        assert_eq!(pos, 0x44);
+        assert_eq!(stream.position(), 5);
+
+        assert!(find_signature(&mut stream, b"postcard-main", Some(0x45), None, true).is_err());
+        assert!(find_signature(&mut stream, b"postcard-main", None, Some(0x44), true).is_err());
+        assert_eq!(
+            find_signature(&mut stream, b"postcard-main", None, Some(0x45), true).unwrap(),
+            0x44
+        );
+        assert!(find_signature(&mut stream, b"not there", None, None, true).is_err());
+    }
+
+    #[test]
+    fn test_horspool() {
+        let searcher = Horspool::new(&b"abcab"[..].into());
+        assert_eq!(searcher.find(b"abcabcab"), Some(0));
+        assert_eq!(searcher.find(b"xabcacabcab"), Some(6));
+        assert_eq!(searcher.find(b"abca"), None);
+        assert_eq!(Horspool::new(&b"a"[..].into()).find(b"bba"), Some(2));
+
+        let searcher = Horspool::new(&"61 ?? 6? 61".parse().unwrap());
+        assert_eq!(searcher.find(b"xxaxbxaxyzaa"), None);
+        assert_eq!(searcher.find(b"xaaaalaxa"), Some(1));
+        assert_eq!(searcher.find(b"xxaxbaxx"), Some(2));
+    }
+
+    #[test]
+    fn test_parse_signature_pattern() {
+        let pattern: SignaturePattern = "7F 45 4C 46 ?? 01".parse().unwrap();
+        assert_eq!(pattern.bytes(), &[0x7F, 0x45, 0x4C, 0x46, 0x00, 0x01]);
+        assert_eq!(pattern.mask(), &[0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0xFF]);
+
+        let pattern: SignaturePattern = "7f454c46 ?1\t1?".parse().unwrap();
+        assert_eq!(pattern.bytes(), &[0x7F, 0x45, 0x4C, 0x46, 0x01, 0x10]);
+        assert_eq!(pattern.mask(), &[0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0xF0]);
+
+        assert!(matches!(
+            "7F 4G".parse::<SignaturePattern>(),
+            Err(StreamError::InvalidChar('G', 4))
+        ));
+        assert!(matches!(
+            "7 F".parse::<SignaturePattern>(),
+            Err(StreamError::InvalidChar(' ', 1))
+        ));
+        assert!(matches!(
+            "7F 4".parse::<SignaturePattern>(),
+            Err(StreamError::InvalidPattern(_))
+        ));
+        assert!(matches!(
+            "  ".parse::<SignaturePattern>(),
+            Err(StreamError::InvalidPattern(_))
+        ));
+        assert!(SignaturePattern::new(&[1, 2], &[0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_find_pattern() {
+        let mut stream = std::io::Cursor::new(DATA);
+        let pattern = "50 4B ?? ?? 0? 00".parse().unwrap();
+        let positions = SignatureScanner::with_pattern(&mut stream, &pattern, None)
+            .unwrap()
+            .collect::<StreamResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(positions, vec![0x16, 0x6A]);
+
+        let pos = find_pattern(&mut stream, &pattern, Some(0x17), None, false).unwrap();
+        assert_eq!(pos, 0x6A);
+    }
+
+    #[test]
+    fn test_find_all_signatures() {
+        let mut stream = std::io::Cursor::new(DATA);
+        let positions =
+            find_all_u32_signatures(&mut stream, 0x02014b50, Endianness::LittleEndian).unwrap();
+        assert_eq!(positions, vec![0x16]);
+
+        let positions =
+            find_all_u32_signatures(&mut stream, 0x00000000, Endianness::LittleEndian).unwrap();
+        assert_eq!(positions, vec![0x35, 0x36, 0x3B, 0x3C, 0x6E]);
+
+        let positions =
+            find_all_u64_signatures(&mut stream, 0xdeadbeef, Endianness::LittleEndian).unwrap();
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn test_signature_scanner_overlapping() {
+        let mut stream = std::io::Cursor::new(b"aaaabaaa".to_vec());
+        let positions = SignatureScanner::new(&mut stream, b"aa", None)
+            .unwrap()
+            .collect::<StreamResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(positions, vec![0, 1, 2, 5, 6]);
+    }
+
+    #[test]
+    fn test_signature_scanner_range() {
+        let mut stream = std::io::Cursor::new(b"PK12PK34PK56".to_vec());
+        let positions = SignatureScanner::new(&mut stream, b"PK", Some(1..11))
+            .unwrap()
+            .collect::<StreamResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(positions, vec![4, 8]);
+
+        let positions = SignatureScanner::new(&mut stream, b"PK", Some(8..9))
+            .unwrap()
+            .collect::<StreamResult<Vec<_>>>()
+            .unwrap();
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn test_signature_scanner_chunk_boundaries() {
+        let data = b"xxPK\x01\x02PK\x01\x02\x01\x02PK\x01".to_vec();
+        for chunk_size in 1..data.len() {
+            let mut stream = std::io::Cursor::new(data.clone());
+            let mut scanner = SignatureScanner::new(&mut stream, b"PK\x01\x02", None).unwrap();
+            scanner.chunk_size = chunk_size;
+            let positions = scanner.collect::<StreamResult<Vec<_>>>().unwrap();
+            assert_eq!(positions, vec![2, 6], "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_signature_scanner_empty() {
+        let mut stream = std::io::Cursor::new(DATA);
+        assert!(SignatureScanner::new(&mut stream, b"", None).is_err());
+    }
+
+    #[test]
+    fn test_multi_signature_scanner() {
+        let mut stream = &DATA[..];
+        let hits = MultiSignatureScanner::new(
+            &mut stream,
+            &[
+                ("central", b"PK\x01\x02"),
+                ("end", b"PK\x05\x06"),
+                ("pk", b"PK"),
+                ("elf", b"\x7fELF"),
+            ],
+        )
+        .unwrap()
+        .collect::<StreamResult<Vec<_>>>()
+        .unwrap();
+
+        assert_eq!(
+            hits,
+            vec![
+                ("pk".to_string(), 0x16),
+                ("central".to_string(), 0x16),
+                ("pk".to_string(), 0x6A),
+                ("end".to_string(), 0x6A),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_signature_scanner_overlapping() {
+        let data = b"ushers".to_vec();
+        let mut stream = std::io::Cursor::new(data);
+        stream.set_position(1);
+        let mut scanner = MultiSignatureScanner::from_position(
+            &mut stream,
+            &[
+                ("he", b"he"),
+                ("she", b"she"),
+                ("his", b"his"),
+                ("hers", b"hers"),
+            ],
+        )
+        .unwrap();
+        scanner.buf = vec![0; 1];
+
+        let hits = scanner.collect::<StreamResult<Vec<_>>>().unwrap();
+        assert_eq!(
+            hits,
+            vec![
+                ("she".to_string(), 1),
+                ("he".to_string(), 2),
+                ("hers".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_signature_scanner_empty() {
+        let mut stream = &DATA[..];
+        assert!(MultiSignatureScanner::new(&mut stream, &[]).is_err());
+        assert!(MultiSignatureScanner::new(&mut stream, &[("empty", b"")]).is_err());
+    }
+
+    fn map_values() -> Vec<(String, AnyInt)> {
+        vec![
+            ("u8".into(), AnyInt::U8(0xFE)),
+            ("u16".into(), AnyInt::U16(0xBEEF)),
+            ("u32".into(), AnyInt::U32(0xDEADBEEF)),
+            ("u48".into(), AnyInt::U48(0xFFFF_DEADBEEF)),
+            ("u64".into(), AnyInt::U64(u64::MAX)),
+            ("u128".into(), AnyInt::U128(u128::MAX - 1)),
+            ("i8".into(), AnyInt::I8(-2)),
+            ("i16".into(), AnyInt::I16(-300)),
+            ("i32".into(), AnyInt::I32(i32::MIN)),
+            ("i48".into(), AnyInt::I48(-0x7FFF_FFFFFFFF)),
+            ("i64".into(), AnyInt::I64(-1)),
+            ("i128".into(), AnyInt::I128(i128::MIN)),
+            ("bool".into(), AnyInt::Bool(true)),
+        ]
+    }
+
+    #[test]
+    fn test_map_round_trip_hashmap() {
+        use crate::streams::write::write_map;
+        use std::collections::HashMap;
+
+        let map = map_values().into_iter().collect::<HashMap<_, _>>();
+        for endianness in [Endianness::LittleEndian, Endianness::BigEndian] {
+            for lpwidth in [LPWidth::LP8, LPWidth::LP16, LPWidth::LP32, LPWidth::LP64] {
+                let mut buf = Vec::new();
+                let written = write_map(&mut buf, endianness, &map, lpwidth).unwrap();
+                assert_eq!(written, buf.len() as u64);
+
+                let read: HashMap<String, AnyInt> =
+                    read_map(buf.as_slice(), endianness, lpwidth).unwrap();
+                assert_eq!(read, map);
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_round_trip_btreemap() {
+        use crate::streams::write::write_map;
+        use std::collections::BTreeMap;
+
+        let map = map_values().into_iter().collect::<BTreeMap<_, _>>();
+        let mut buf = Vec::new();
+        write_map(&mut buf, Endianness::BigEndian, &map, LPWidth::LP16).unwrap();
+        let read: BTreeMap<String, AnyInt> =
+            read_map(buf.as_slice(), Endianness::BigEndian, LPWidth::LP16).unwrap();
+        assert_eq!(read, map);
+    }
+
+    #[test]
+    fn test_map_format() {
+        use crate::streams::write::write_map;
+        use std::collections::BTreeMap;
+
+        let mut map = BTreeMap::new();
+        map.insert("a".to_string(), AnyInt::I16(-2));
+        let mut buf = Vec::new();
+        write_map(&mut buf, Endianness::LittleEndian, &map, LPWidth::LP8).unwrap();
+        assert_eq!(buf, [MAP_FORMAT_VERSION, 1, 1, b'a', 7, 0xFE, 0xFF]);
+
+        buf[0] = MAP_FORMAT_VERSION + 1;
+        let read: StreamResult<BTreeMap<String, AnyInt>> =
+            read_map(buf.as_slice(), Endianness::LittleEndian, LPWidth::LP8);
+        assert!(read.is_err());
+
+        buf[0] = MAP_FORMAT_VERSION;
+        buf[4] = 0xFF;
+        let read: StreamResult<BTreeMap<String, AnyInt>> =
+            read_map(buf.as_slice(), Endianness::LittleEndian, LPWidth::LP8);
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn test_read_tagged_values() {
+        use crate::streams::write::write_tagged_values;
+
+        let values = map_values().into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        write_tagged_values(&mut buf, &values, Endianness::BigEndian).unwrap();
+        let read = read_tagged_values(buf.as_slice(), Endianness::BigEndian).unwrap();
+        assert_eq!(read, values);
+
+        buf.pop();
+        assert!(read_tagged_values(buf.as_slice(), Endianness::BigEndian).is_err());
+    }
+
+    #[test]
+    fn test_read_anyint() {
+        let mut stream = std::io::Cursor::new(DATA);
+        let v = read_anyint(&mut stream, AnyIntKind::U48, Endianness::BigEndian).unwrap();
+        assert_eq!(v, AnyInt::U48(0x002F6D61785F));
+        let v = read_anyint(&mut stream, AnyIntKind::I48, Endianness::LittleEndian).unwrap();
+        assert_eq!(v, AnyInt::I48(0x722E657A6973));
+        stream.set_position(0x12);
+        let v = read_anyint(&mut stream, AnyIntKind::I48, Endianness::BigEndian).unwrap();
+        assert_eq!(v, AnyInt::I48(-0x5645119CAFB5));
+    }
 }
@@ -2,19 +2,24 @@
 
 use std::io::Write;
 
-use crate::streams::SeekWrite;
+use crate::streams::helpers::write_lpend;
+use crate::streams::{SeekWrite, MAP_FORMAT_VERSION};
 use byteorder::WriteBytesExt;
 
-use super::{AnyInt, Endianness, LPWidth, MapType};
+use super::{AnyInt, AnyValue, Endianness, LPWidth, MapType};
 
-/// Write a list of `AnyInt`s to a stream
-pub fn write_values<S: Write>(
+/// Write a list of values to a stream
+///
+/// `values` can be a slice of [`AnyInt`]s or of [`AnyValue`]s, floats are
+/// written as their IEEE 754 representation.
+pub fn write_values<S: Write, V: Clone + Into<AnyValue>>(
     mut stream: S,
-    values: &[AnyInt],
+    values: &[V],
     endianness: Endianness,
 ) -> Result<u64, std::io::Error> {
     let mut written = 0;
     for v in values {
+        let v: AnyValue = v.clone().into();
         match endianness {
             Endianness::LittleEndian => {
                 let vb = v.to_bytes_le();
@@ -29,6 +34,21 @@ pub fn write_values<S: Write>(
     Ok(written as u64)
 }
 
+/// Write a list of `AnyInt`s to a stream, each prefixed with it's type tag
+/// so they can be read back with [`super::read::read_tagged_values`]
+/// without knowing their types in advance, see [`AnyInt::write_tagged`].
+pub fn write_tagged_values<S: Write>(
+    mut stream: S,
+    values: &[AnyInt],
+    endianness: Endianness,
+) -> Result<u64, std::io::Error> {
+    let mut written = 0;
+    for v in values {
+        written += v.write_tagged(&mut stream, endianness)?;
+    }
+    Ok(written)
+}
+
 /// Write a lpbuf to a stream
 ///
 /// # Arguments
@@ -46,53 +66,8 @@ pub fn write_lpbuf<S: Write>(
     lpend: Endianness,
     bytes: &[u8],
 ) -> Result<u64, std::io::Error> {
-    let mut written = 0;
     let len = bytes.len();
-    if !LPWidth::usize_fits(lptype, len) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Length prefix does not fit in specified width",
-        ));
-    }
-    match lptype {
-        LPWidth::LP8 => {
-            written += 1;
-            stream.write_u8(len as u8)?;
-        }
-        LPWidth::LP16 => {
-            written += 2;
-            match lpend {
-                Endianness::LittleEndian => {
-                    stream.write_u16::<byteorder::LittleEndian>(len as u16)?;
-                }
-                Endianness::BigEndian => {
-                    stream.write_u16::<byteorder::BigEndian>(len as u16)?;
-                }
-            }
-        }
-        LPWidth::LP32 => {
-            written += 4;
-            match lpend {
-                Endianness::LittleEndian => {
-                    stream.write_u32::<byteorder::LittleEndian>(len as u32)?;
-                }
-                Endianness::BigEndian => {
-                    stream.write_u32::<byteorder::BigEndian>(len as u32)?;
-                }
-            }
-        }
-        LPWidth::LP64 => {
-            written += 8;
-            match lpend {
-                Endianness::LittleEndian => {
-                    stream.write_u64::<byteorder::LittleEndian>(len as u64)?;
-                }
-                Endianness::BigEndian => {
-                    stream.write_u64::<byteorder::BigEndian>(len as u64)?;
-                }
-            }
-        }
-    }
+    let mut written = write_lpend(&mut stream, lptype, lpend, len)?;
     stream.write_all(bytes)?;
     written += len as u64;
     Ok(written)
@@ -137,28 +112,27 @@ pub fn write_cstr<S: SeekWrite>(mut stream: S, string: &str) -> Result<u64, std:
 }
 
 /// Write a map type to a stream
+///
+/// The map is written in the format described in [`MAP_FORMAT_VERSION`],
+/// and can be read back with [`super::read::read_map`] using the same
+/// `endianness` and `lpwidth`.
+///
+/// # Returns
+/// * `Ok(u64)` - The number of bytes written
+/// * `Err(std::io::Error)` - The error encountered while writing, this includes
+///   the number of entries or a key not fitting in `lpwidth`
 pub fn write_map<'a>(
     mut stream: impl Write,
     endianness: Endianness,
     map: &'a impl MapType<'a, String, AnyInt>,
     lpwidth: LPWidth,
 ) -> Result<u64, std::io::Error> {
-    let mut written = 0;
-    let entries = AnyInt::U48(map.len() as u64);
-    written += write_values(&mut stream, &[entries], endianness)?;
+    stream.write_u8(MAP_FORMAT_VERSION)?;
+    let mut written = 1;
+    written += write_lpend(&mut stream, lpwidth, endianness, map.len())?;
     for (k, v) in map.iter() {
-        match endianness {
-            Endianness::LittleEndian => {
-                written += write_lpstr(&mut stream, lpwidth, endianness, k)?;
-                let vb = v.to_bytes_le();
-                written += stream.write(vb.as_ref())? as u64;
-            }
-            Endianness::BigEndian => {
-                written += write_lpstr(&mut stream, lpwidth, endianness, k)?;
-                let vb = v.to_bytes_be();
-                written += stream.write(vb.as_ref())? as u64;
-            }
-        }
+        written += write_lpstr(&mut stream, lpwidth, endianness, k)?;
+        written += v.write_tagged(&mut stream, endianness)?;
     }
     Ok(written)
 }
@@ -177,6 +151,24 @@ mod tests {
         assert_eq!(buf, [0x78, 0x56, 0x34, 0x12, 0xf0, 0xde, 0xbc, 0x9a]);
     }
 
+    #[test]
+    fn test_write_float_values() {
+        let mut buf = Vec::new();
+        let values = [
+            AnyValue::F16(1.5),
+            AnyValue::F32(1.25),
+            AnyValue::F64(-3.5),
+            AnyValue::Int(AnyInt::U8(7)),
+        ];
+        let written = write_values(&mut buf, &values, Endianness::BigEndian).unwrap();
+        assert_eq!(written, 15);
+        let mut expected = vec![0x3E, 0x00];
+        expected.extend_from_slice(&1.25f32.to_be_bytes());
+        expected.extend_from_slice(&(-3.5f64).to_be_bytes());
+        expected.push(7);
+        assert_eq!(buf, expected);
+    }
+
     #[test]
     fn test_write_lpbuf() {
         let mut buf = [0u8; 8];
@@ -203,4 +195,13 @@ mod tests {
         write_cstr(stream, string).unwrap();
         assert_eq!(buf, [0x74, 0x65, 0x73, 0x74, 0x00, 0x00, 0x00, 0x00]);
     }
+
+    #[test]
+    fn test_write_tagged_values() {
+        let mut buf = Vec::new();
+        let values = [AnyInt::U32(0x12345678u32), AnyInt::I32(0x12345678i32)];
+        let written = write_tagged_values(&mut buf, &values, Endianness::BigEndian).unwrap();
+        assert_eq!(written, 10);
+        assert_eq!(buf, [2, 0x12, 0x34, 0x56, 0x78, 8, 0x12, 0x34, 0x56, 0x78]);
+    }
 }
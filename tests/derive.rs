@@ -0,0 +1,103 @@
+#![cfg(feature = "derive")]
+
+use std::io::Cursor;
+
+use neoncore::streams::advanced_readers::{NeonRead, StructReader};
+use neoncore::streams::{AnyInt, Endianness};
+
+#[derive(Debug, PartialEq, NeonRead)]
+struct Ident {
+    mag: u32,
+    class: u8,
+    #[neon(pad_after = 2)]
+    data: u8,
+}
+
+#[derive(Debug, PartialEq, NeonRead)]
+struct Header {
+    ident: Ident,
+    #[neon(endian = "big")]
+    kind: u16,
+    #[neon(read_as = "u32")]
+    entry: u64,
+    #[neon(cond = "ident.class == 2")]
+    wide: Option<u64>,
+    #[neon(pad_before = 1, cond = "ident.class == 1")]
+    narrow: Option<u32>,
+    flag: bool,
+}
+
+const DATA: [u8; 27] = [
+    0x7F, 0x45, 0x4C, 0x46, 0x02, 0x01, 0xAA, 0xBB, // ident
+    0x00, 0x03, // kind
+    0x78, 0x56, 0x34, 0x12, // entry
+    0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // wide
+    0x01, // flag
+    0x00, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn test_derive_read() {
+    let header = Header::read_from(Cursor::new(DATA), Endianness::LittleEndian).unwrap();
+    assert_eq!(
+        header,
+        Header {
+            ident: Ident {
+                mag: 0x464C457F,
+                class: 2,
+                data: 1,
+            },
+            kind: 3,
+            entry: 0x12345678,
+            wide: Some(0x0102030405060708),
+            narrow: None,
+            flag: true,
+        }
+    );
+}
+
+#[test]
+fn test_derive_matches_struct_reader() {
+    let derived = Ident::read_from(Cursor::new(DATA), Endianness::LittleEndian).unwrap();
+    let reader = StructReader::new_le()
+        .add_u32_field("mag")
+        .add_u8_field("class")
+        .add_u8_field("data")
+        .read(Cursor::new(DATA))
+        .unwrap();
+
+    assert_eq!(reader["mag"], AnyInt::from(derived.mag));
+    assert_eq!(reader["class"], AnyInt::from(derived.class));
+    assert_eq!(reader["data"], AnyInt::from(derived.data));
+}
+
+#[test]
+fn test_derive_eof() {
+    assert!(Header::read_from(Cursor::new(&DATA[..10]), Endianness::LittleEndian).is_err());
+}
+
+#[derive(Debug, PartialEq, NeonRead)]
+struct Optional {
+    present: bool,
+    #[neon(pad_before = 2, pad_after = 1, cond = "present")]
+    value: Option<u16>,
+    end: u8,
+}
+
+#[test]
+fn test_derive_cond_padding() {
+    let v = Optional::read_from(Cursor::new([0x00, 0x07]), Endianness::LittleEndian).unwrap();
+    assert_eq!(
+        v,
+        Optional {
+            present: false,
+            value: None,
+            end: 7,
+        }
+    );
+
+    let data = [0x01, 0xFF, 0xFF, 0x34, 0x12, 0xFF, 0x07];
+    let v = Optional::read_from(Cursor::new(data), Endianness::LittleEndian).unwrap();
+    assert_eq!(v.value, Some(0x1234));
+    assert_eq!(v.end, 7);
+}